@@ -0,0 +1,111 @@
+//! Append-only audit log of attaches and detaches (`--audit-log`).
+//!
+//! Each operation, successful or not, is one JSON object per line:
+//!
+//! ```text
+//! {"time":"2024-05-01T09:30:00Z","action":"attach","vm":"work","vid":"1050","pid":"0407","serial":"12345678","syspath":"/sys/devices/.../1-4","result":"ok"}
+//! ```
+//!
+//! `result` is `ok`, `dry-run`, `vm-stopped` for devices a VM let go of by
+//! shutting down, or `failed` with the reason in `error`.  Lines are written
+//! whatever the log level, and each one is flushed as it is written.
+
+use crate::events::json_str;
+use crate::UsbFilter;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How an operation ended.
+pub enum Outcome<'a> {
+    Done,
+    DryRun,
+    /// The VM shut down, so the device left it without a detach.
+    VmStopped,
+    Failed(&'a str),
+}
+
+pub struct AuditLog {
+    file: LineWriter<File>,
+}
+
+impl AuditLog {
+    /// Append to `path`, creating it if needed.
+    pub fn open(path: &Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: LineWriter::new(file),
+        })
+    }
+
+    /// Record `action` on `device` at `syspath` for `vm`.  A failed write
+    /// is reported but doesn't stop the daemon.
+    pub fn write(
+        &mut self,
+        action: &str,
+        vm: &str,
+        device: &UsbFilter,
+        syspath: &Path,
+        outcome: Outcome,
+    ) {
+        let field = |value: Option<&String>| value.map_or("null".to_owned(), |v| json_str(v));
+        let (result, error) = match outcome {
+            Outcome::Done => ("ok", None),
+            Outcome::DryRun => ("dry-run", None),
+            Outcome::VmStopped => ("vm-stopped", None),
+            Outcome::Failed(e) => ("failed", Some(e)),
+        };
+        let mut line = format!(
+            "{{\"time\":{},\"action\":{},\"vm\":{},\"vid\":{},\"pid\":{},\"serial\":{},\"syspath\":{},\"result\":{}",
+            json_str(&utc_timestamp()),
+            json_str(action),
+            json_str(vm),
+            field(device.vid.as_ref()),
+            field(device.pid.as_ref()),
+            field(device.serial.as_ref()),
+            json_str(&syspath.to_string_lossy()),
+            json_str(result)
+        );
+        if let Some(error) = error {
+            line.push_str(&format!(",\"error\":{}", json_str(error)));
+        }
+        line.push_str("}\n");
+        if let Err(e) = self
+            .file
+            .write_all(line.as_bytes())
+            .and_then(|_| self.file.flush())
+        {
+            warn!("failed to write audit log: {}", e);
+        }
+    }
+}
+
+/// The current time as RFC 3339 in UTC, to the second.
+pub fn utc_timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Days since the epoch to a civil date, after Howard Hinnant's
+    // `civil_from_days`, with eras of 400 years starting on 0000-03-01.
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
@@ -0,0 +1,128 @@
+//! Credentials for remote hypervisors, such as `qemu+tcp://` with SASL.
+//!
+//! libvirt asks for credentials through a callback, which the `virt`
+//! bindings only take as a plain `fn`, so the configured ones are kept in a
+//! process-wide slot.  Anything that isn't configured is prompted for on the
+//! terminal, if there is one.  Credentials are never logged.
+
+use log::{debug, warn};
+use std::io::{self, BufRead, Write};
+use std::sync::OnceLock;
+use virt::connect::{Connect, ConnectAuth, ConnectCredential};
+use virt::error::Error;
+use virt::sys;
+
+/// A username and password from the config file or environment.
+#[derive(Default)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+static CREDENTIALS: OnceLock<Credentials> = OnceLock::new();
+
+/// Use `credentials` for every connection opened from now on.  Only the
+/// first call has any effect.
+pub fn configure(credentials: Credentials) {
+    let _ = CREDENTIALS.set(credentials);
+}
+
+/// Open `uri`, answering libvirt's requests for credentials unless it is a
+/// local socket URI.
+pub fn open(uri: &str) -> Result<Connect, Error> {
+    if is_local(uri) {
+        return Connect::open(uri);
+    }
+    debug!("Opening {} with authentication", uri);
+    let mut auth = ConnectAuth::new(
+        vec![
+            sys::VIR_CRED_USERNAME,
+            sys::VIR_CRED_AUTHNAME,
+            sys::VIR_CRED_PASSPHRASE,
+            sys::VIR_CRED_ECHOPROMPT,
+            sys::VIR_CRED_NOECHOPROMPT,
+        ],
+        answer,
+    );
+    Connect::open_auth(uri, &mut auth, 0)
+}
+
+/// Whether `uri` is `driver:///path` or `driver+unix:///path`, which talk
+/// to the local daemon over its socket and never ask for credentials.
+/// Anything unusual, such as an alias from `libvirt.conf`, counts as
+/// remote; authenticating a local connection costs nothing.
+fn is_local(uri: &str) -> bool {
+    let (scheme, rest) = match uri.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let transport = scheme.split_once('+').map(|(_, t)| t);
+    rest.starts_with('/') && transport.is_none_or(|t| t == "unix")
+}
+
+/// Fill in each credential libvirt asks for: from the configured ones,
+/// else from the terminal, else with libvirt's default.  The signature is
+/// fixed by `ConnectAuthCallback`.
+#[allow(clippy::ptr_arg)]
+fn answer(creds: &mut Vec<ConnectCredential>) {
+    let configured = CREDENTIALS.get();
+    for cred in creds.iter_mut() {
+        let typed = cred.typed as sys::virConnectCredentialType;
+        let value = match typed {
+            sys::VIR_CRED_USERNAME | sys::VIR_CRED_AUTHNAME => {
+                configured.and_then(|c| c.username.clone())
+            }
+            sys::VIR_CRED_PASSPHRASE => configured.and_then(|c| c.password.clone()),
+            _ => None,
+        };
+        let echo = typed != sys::VIR_CRED_PASSPHRASE && typed != sys::VIR_CRED_NOECHOPROMPT;
+        cred.result = value
+            .or_else(|| prompt(&cred.prompt, echo))
+            .or_else(|| Some(cred.def_result.clone()).filter(|d| !d.is_empty()));
+        if cred.result.is_none() {
+            warn!(
+                "libvirt asked for '{}', which isn't configured and there is no terminal to ask on",
+                cred.prompt
+            );
+        }
+    }
+}
+
+/// Ask on the terminal, hiding the answer unless `echo`.  `None` if stdin
+/// isn't a terminal.
+fn prompt(prompt: &str, echo: bool) -> Option<String> {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+        return None;
+    }
+    eprint!("{}: ", prompt.trim_end_matches([':', ' ']));
+    io::stderr().flush().ok()?;
+    let saved = match echo {
+        true => None,
+        _ => hide_input(),
+    };
+    let mut line = String::new();
+    let read = io::stdin().lock().read_line(&mut line);
+    if let Some(saved) = saved {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+        // The newline typed wasn't echoed either.
+        eprintln!();
+    }
+    read.ok()?;
+    Some(line.trim_end_matches(['\r', '\n']).to_owned())
+}
+
+/// Turn off terminal echo on stdin, returning the settings to restore.
+fn hide_input() -> Option<libc::termios> {
+    unsafe {
+        let mut saved: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+            return None;
+        }
+        let mut hidden = saved;
+        hidden.c_lflag &= !libc::ECHO;
+        if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &hidden) != 0 {
+            return None;
+        }
+        Some(saved)
+    }
+}
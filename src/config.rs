@@ -0,0 +1,411 @@
+//! Static passthrough rules loaded at startup (`--config`).
+//!
+//! The file is TOML, but only the subset rules need: an array of `[[rule]]`
+//! tables of string keys, with `#` comments, and an optional `[auth]` table
+//! with the `username` and `password` for a remote hypervisor.
+//!
+//! ```toml
+//! [[rule]]
+//! vm = "work"
+//! vid = "1050"
+//! pid = "0407,0410"
+//! serial = "12345678"  # optional; "" for only devices without one
+//! guest_bus = "1"       # optional guest controller index
+//! guest_port = "2"      # optional guest port on it
+//! max_attach_retries = "1"  # optional: then give up until a Reattach
+//! template = "<hostdev ...>...</hostdev>"  # optional, as for SetTemplate
+//! access = "read-write"  # optional; "read-only" is an error
+//!
+//! [[rule]]
+//! vm = "keys"
+//! product = "nitrokey"    # the product string contains it, any case
+//! manufacturer = "..."    # likewise; vid and pid are optional with either
+//! match = "exact"         # optional: whole strings, case-sensitive
+//!
+//! [[rule]]
+//! vm = "lab"
+//! hub = "1-4"             # anything behind this hub; with vid and pid,
+//!                         # only those devices behind it
+//!
+//! [[rule]]
+//! vm = "capture"
+//! vid = "1234"
+//! pid = "5678"
+//! speed = "super"         # optional: low, full, high, super or super-plus
+//!
+//! [[rule]]
+//! vm = "gaming"
+//! type = "pci"            # vid and pid are the PCI vendor and device
+//! address = "0000:01:00.0"  # instead of, or as well as, vid and pid
+//! ```
+//!
+//! VIDs and PIDs take the same forms as over D-Bus.  Anything else,
+//! including an ID that isn't valid, is an error naming the line.
+
+use crate::{
+    canon_id_list, canon_id_pattern, canon_pci_address, canon_speed, check_balanced_tags, hub_port,
+    UsbFilter,
+};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/// Everything a config file sets.
+#[derive(Default)]
+pub struct Config {
+    /// Each rule's VM and filter.
+    pub rules: Vec<(String, UsbFilter)>,
+    /// Credentials from `[auth]`.  Never logged.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+/// The table keys are being read into.
+enum Table {
+    None,
+    Rule(Box<Rule>),
+    Auth,
+}
+
+/// One `[[rule]]` while it is being read.
+#[derive(Default)]
+struct Rule {
+    line: usize,
+    vm: Option<String>,
+    vid: Option<String>,
+    pid: Option<String>,
+    serial: Option<String>,
+    guest_bus: Option<String>,
+    guest_port: Option<String>,
+    max_attach_retries: Option<String>,
+    template: Option<String>,
+    kind: Option<String>,
+    address: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    mode: Option<String>,
+    hub: Option<String>,
+    speed: Option<String>,
+    access: Option<String>,
+}
+
+impl Rule {
+    fn finish(self) -> io::Result<(String, UsbFilter)> {
+        let at = self.line;
+        let missing = |key: &str| invalid(at, &format!("rule has no {}", key));
+        let vm = self
+            .vm
+            .filter(|vm| !vm.is_empty())
+            .ok_or_else(|| missing("vm"))?;
+        let pci = match self.kind.as_deref() {
+            None | Some("usb") => false,
+            Some("pci") => true,
+            Some(kind) => return Err(invalid(at, &format!("invalid type '{}'", kind))),
+        };
+        if !pci && self.address.is_some() {
+            return Err(invalid(at, "address is only for type = \"pci\""));
+        }
+        let pci_address = match self.address {
+            Some(address) => Some(
+                canon_pci_address(&address)
+                    .ok_or_else(|| invalid(at, &format!("invalid address '{}'", address)))?,
+            ),
+            None => None,
+        };
+        // libvirt only has read-only SCSI hostdevs; USB and PCI devices are
+        // handed to the guest whole.
+        match self.access.as_deref() {
+            None | Some("read-write") => {}
+            Some("read-only") => {
+                return Err(invalid(
+                    at,
+                    "access \"read-only\" isn't supported for USB or PCI hostdevs",
+                ))
+            }
+            Some(access) => return Err(invalid(at, &format!("invalid access '{}'", access))),
+        }
+        let manufacturer_match = self.manufacturer.filter(|m| !m.is_empty());
+        let product_match = self.product.filter(|p| !p.is_empty());
+        let exact_match = match self.mode.as_deref() {
+            None | Some("contains") => false,
+            Some("exact") => true,
+            Some(mode) => return Err(invalid(at, &format!("invalid match '{}'", mode))),
+        };
+        let hub = match self.hub {
+            Some(_) if pci => return Err(invalid(at, "hub is only for USB rules")),
+            Some(hub) => {
+                Some(hub_port(&hub).ok_or_else(|| invalid(at, &format!("invalid hub '{}'", hub)))?)
+            }
+            None => None,
+        };
+        let speed = match self.speed {
+            Some(_) if pci => return Err(invalid(at, "speed is only for USB rules")),
+            Some(speed) => Some(
+                canon_speed(&speed)
+                    .ok_or_else(|| invalid(at, &format!("invalid speed '{}'", speed)))?,
+            ),
+            None => None,
+        };
+        // A PCI address, a manufacturer or product, or a hub is enough on
+        // its own.
+        let alone = pci_address.is_some()
+            || manufacturer_match.is_some()
+            || product_match.is_some()
+            || hub.is_some();
+        let (vid, pid) = match (alone, self.vid, self.pid) {
+            (true, None, None) => (None, None),
+            (_, vid, pid) => (
+                Some(vid.ok_or_else(|| missing("vid"))?),
+                Some(pid.ok_or_else(|| missing("pid"))?),
+            ),
+        };
+        let xml_template = match self.template.filter(|t| !t.is_empty()) {
+            Some(template) => {
+                check_balanced_tags(&template)
+                    .map_err(|e| invalid(at, &format!("invalid template: {}", e)))?;
+                Some(template)
+            }
+            None => None,
+        };
+        // Numbers are strings like everything else; the port counts from 1.
+        let number = |key: &str, value: Option<String>, min: u32| match value {
+            None => Ok(None),
+            Some(v)
+                if v.bytes().all(|b| b.is_ascii_digit())
+                    && v.parse::<u32>().is_ok_and(|n| n >= min) =>
+            {
+                Ok(v.parse().ok())
+            }
+            Some(v) => Err(invalid(at, &format!("invalid {} '{}'", key, v))),
+        };
+        let filter = UsbFilter {
+            vid: match vid {
+                Some(vid) => Some(
+                    canon_id_pattern(&vid)
+                        .ok_or_else(|| invalid(at, &format!("invalid vid '{}'", vid)))?,
+                ),
+                None => None,
+            },
+            pid: match pid {
+                Some(pid) => Some(
+                    canon_id_list(&pid)
+                        .ok_or_else(|| invalid(at, &format!("invalid pid '{}'", pid)))?,
+                ),
+                None => None,
+            },
+            pci,
+            pci_address,
+            manufacturer_match,
+            product_match,
+            exact_match,
+            hub,
+            speed,
+            // An empty serial matches only devices without one.
+            serial: self.serial,
+            guest_bus: number("guest_bus", self.guest_bus, 0)?,
+            guest_port: number("guest_port", self.guest_port, 1)?,
+            max_attach_retries: number("max_attach_retries", self.max_attach_retries, 0)?,
+            xml_template,
+            ..Default::default()
+        };
+        Ok((vm, filter))
+    }
+}
+
+fn invalid(line: usize, what: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, what))
+}
+
+/// Read the file at `path`.
+pub fn load(path: &Path) -> io::Result<Config> {
+    let mut config = Config::default();
+    let mut table = Table::None;
+    let mut seen_auth = false;
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let n = n + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(header) = line.strip_prefix("[[").and_then(|t| t.split_once("]]")) {
+            if header.0.trim() != "rule" || !is_comment(header.1) {
+                return Err(invalid(n, "only [[rule]] and [auth] tables are supported"));
+            }
+            if let Table::Rule(done) = std::mem::replace(&mut table, Table::None) {
+                config.rules.push(done.finish()?);
+            }
+            table = Table::Rule(Box::new(Rule {
+                line: n,
+                ..Default::default()
+            }));
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[').and_then(|t| t.split_once(']')) {
+            if header.0.trim() != "auth" || !is_comment(header.1) {
+                return Err(invalid(n, "only [[rule]] and [auth] tables are supported"));
+            }
+            if std::mem::replace(&mut seen_auth, true) {
+                return Err(invalid(n, "duplicate [auth] table"));
+            }
+            if let Table::Rule(done) = std::mem::replace(&mut table, Table::Auth) {
+                config.rules.push(done.finish()?);
+            }
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(n, "expected key = \"value\""))?;
+        let value =
+            parse_string(value.trim()).ok_or_else(|| invalid(n, "expected a quoted string"))?;
+        let slot = match (&mut table, key.trim()) {
+            (Table::None, _) => return Err(invalid(n, "expected [[rule]] or [auth]")),
+            (Table::Rule(rule), "vm") => &mut rule.vm,
+            (Table::Rule(rule), "vid") => &mut rule.vid,
+            (Table::Rule(rule), "pid") => &mut rule.pid,
+            (Table::Rule(rule), "serial") => &mut rule.serial,
+            (Table::Rule(rule), "guest_bus") => &mut rule.guest_bus,
+            (Table::Rule(rule), "guest_port") => &mut rule.guest_port,
+            (Table::Rule(rule), "max_attach_retries") => &mut rule.max_attach_retries,
+            (Table::Rule(rule), "template") => &mut rule.template,
+            (Table::Rule(rule), "type") => &mut rule.kind,
+            (Table::Rule(rule), "address") => &mut rule.address,
+            (Table::Rule(rule), "manufacturer") => &mut rule.manufacturer,
+            (Table::Rule(rule), "product") => &mut rule.product,
+            (Table::Rule(rule), "match") => &mut rule.mode,
+            (Table::Rule(rule), "hub") => &mut rule.hub,
+            (Table::Rule(rule), "speed") => &mut rule.speed,
+            (Table::Rule(rule), "access") => &mut rule.access,
+            (Table::Auth, "username") => &mut config.username,
+            (Table::Auth, "password") => &mut config.password,
+            (_, key) => return Err(invalid(n, &format!("unknown key '{}'", key))),
+        };
+        if slot.replace(value).is_some() {
+            return Err(invalid(n, &format!("duplicate key '{}'", key.trim())));
+        }
+    }
+    if let Table::Rule(done) = table {
+        config.rules.push(done.finish()?);
+    }
+    Ok(config)
+}
+
+/// Whether what follows a value is nothing or a comment.
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// A TOML basic `"..."` or literal `'...'` string, optionally followed by
+/// a comment.
+fn parse_string(value: &str) -> Option<String> {
+    if let Some(literal) = value.strip_prefix('\'') {
+        let (s, rest) = literal.split_once('\'')?;
+        return Some(s.to_owned()).filter(|_| is_comment(rest));
+    }
+    let mut chars = value.strip_prefix('"')?.chars();
+    let mut out = String::new();
+    loop {
+        match chars.next()? {
+            '"' => break,
+            '\\' => match chars.next()? {
+                '"' => out.push('"'),
+                '\\' => out.push('\\'),
+                'n' => out.push('\n'),
+                't' => out.push('\t'),
+                _ => return None,
+            },
+            c => out.push(c),
+        }
+    }
+    Some(out).filter(|_| is_comment(chars.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A rule for `vm = "work"` with a VID/PID and the given guest address.
+    fn rule(guest_bus: Option<&str>, guest_port: Option<&str>) -> Rule {
+        Rule {
+            line: 3,
+            vm: Some("work".to_owned()),
+            vid: Some("1050".to_owned()),
+            pid: Some("0407".to_owned()),
+            guest_bus: guest_bus.map(str::to_owned),
+            guest_port: guest_port.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn guest_address_numbers() {
+        let (_, filter) = rule(Some("1"), Some("2")).finish().unwrap();
+        assert_eq!((filter.guest_bus, filter.guest_port), (Some(1), Some(2)));
+        let (_, filter) = rule(Some("0"), None).finish().unwrap();
+        assert_eq!((filter.guest_bus, filter.guest_port), (Some(0), None));
+    }
+
+    #[test]
+    fn max_attach_retries_is_a_number() {
+        let mut retries = rule(None, None);
+        retries.max_attach_retries = Some("0".to_owned());
+        assert_eq!(retries.finish().unwrap().1.max_attach_retries, Some(0));
+        let mut retries = rule(None, None);
+        retries.max_attach_retries = Some("few".to_owned());
+        match retries.finish() {
+            Err(e) => assert_eq!(e.to_string(), "line 3: invalid max_attach_retries 'few'"),
+            Ok(_) => panic!("max_attach_retries 'few' was accepted"),
+        }
+    }
+
+    #[test]
+    fn read_only_access_is_refused() {
+        let mut read_write = rule(None, None);
+        read_write.access = Some("read-write".to_owned());
+        assert!(read_write.finish().is_ok());
+        let mut read_only = rule(None, None);
+        read_only.access = Some("read-only".to_owned());
+        match read_only.finish() {
+            Err(e) => assert_eq!(
+                e.to_string(),
+                "line 3: access \"read-only\" isn't supported for USB or PCI hostdevs"
+            ),
+            Ok(_) => panic!("read-only access was accepted"),
+        }
+    }
+
+    #[test]
+    fn template_must_balance() {
+        let mut templated = rule(None, None);
+        templated.template = Some("<hostdev><source></source></hostdev>".to_owned());
+        assert!(templated.finish().unwrap().1.xml_template.is_some());
+        let mut broken = rule(None, None);
+        broken.template = Some("<hostdev><source></hostdev>".to_owned());
+        match broken.finish() {
+            Err(e) => assert!(e.to_string().starts_with("line 3: invalid template: ")),
+            Ok(_) => panic!("an unbalanced template was accepted"),
+        }
+    }
+
+    #[test]
+    fn empty_serial_is_kept() {
+        let mut empty = rule(None, None);
+        empty.serial = Some(String::new());
+        assert_eq!(empty.finish().unwrap().1.serial.as_deref(), Some(""));
+        assert_eq!(rule(None, None).finish().unwrap().1.serial, None);
+    }
+
+    #[test]
+    fn guest_port_rejects_non_numbers_and_zero() {
+        for port in ["0", "two", "-1", "+2", " 2", "0x2", "", "99999999999"] {
+            let e = match rule(Some("0"), Some(port)).finish() {
+                Err(e) => e,
+                Ok(_) => panic!("guest_port {:?} was accepted", port),
+            };
+            assert_eq!(
+                e.to_string(),
+                format!("line 3: invalid guest_port '{}'", port)
+            );
+        }
+        assert!(rule(Some("one"), None).finish().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Length-prefixed binary command protocol on a Unix socket, for
+//! environments without D-Bus (`--control-socket`).
+//!
+//! Every message is a big-endian `u32` byte count followed by that many
+//! bytes.  A request is a sequence of strings, each itself a big-endian
+//! `u32` byte count followed by UTF-8: the D-Bus method name, then its
+//! arguments in D-Bus order, all as strings (`SetMaxPower`'s milliamps in
+//! decimal).  The response is a status byte, `0` if the call returned a
+//! reply or `1` if it failed, followed by the UTF-8 reply or error text.
+//!
+//! A connection may carry any number of requests, answered in order.
+//! Clients are served one at a time, like D-Bus calls.
+
+use crate::events::remove_stale_socket;
+use crate::{call, DbusDevice};
+use log::warn;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest request accepted, in bytes.
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+/// How long a connected client may stay silent before it is dropped, so an
+/// idle client can't lock out the others.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listen on `path`, replacing a stale socket left by a previous run.
+/// Anything at `path` that isn't a socket is left alone, and the bind
+/// fails.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    remove_stale_socket(path);
+    UnixListener::bind(path)
+}
+
+/// Serve clients on `listener`, from `bind`, forever.
+pub fn serve(listener: UnixListener, dev: DbusDevice) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("failed to accept control client: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream.set_read_timeout(Some(CLIENT_TIMEOUT)) {
+            warn!("dropping control client: {}", e);
+            continue;
+        }
+        if let Err(e) = serve_client(&mut stream, &dev) {
+            warn!("dropping control client: {}", e);
+        }
+    }
+}
+
+/// Answer requests from one client until it disconnects.
+fn serve_client(stream: &mut UnixStream, dev: &DbusDevice) -> io::Result<()> {
+    loop {
+        let request = match read_message(stream) {
+            Ok(r) => r,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (status, reply) = match parse_request(&request) {
+            Some((method, args)) => match call(dev, &method, &args) {
+                Ok(reply) => (0, reply),
+                Err(e) => (1, e.description().to_owned()),
+            },
+            None => (1, "malformed request".to_owned()),
+        };
+        let mut response = vec![status];
+        response.extend_from_slice(reply.as_bytes());
+        stream.write_all(&(response.len() as u32).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+}
+
+fn read_message(stream: &mut UnixStream) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_MESSAGE {
+        return Err(io::Error::new(ErrorKind::InvalidData, "request too large"));
+    }
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Split a request into the method name and its arguments.
+fn parse_request(mut request: &[u8]) -> Option<(String, Vec<String>)> {
+    let mut strings = Vec::new();
+    while !request.is_empty() {
+        let len = u32::from_be_bytes(request.get(..4)?.try_into().ok()?) as usize;
+        let bytes = request.get(4..4 + len)?;
+        strings.push(String::from_utf8(bytes.to_vec()).ok()?);
+        request = &request[4 + len..];
+    }
+    if strings.is_empty() {
+        return None;
+    }
+    let method = strings.remove(0);
+    Some((method, strings))
+}
@@ -0,0 +1,47 @@
+//! Domain handles cached by VM, so that a burst of matching devices doesn't
+//! look the same VM up in libvirt once per device.
+//!
+//! A handle goes stale when its VM is undefined, even if another VM is then
+//! defined under the same name.  Entries are therefore dropped on every
+//! lifecycle event for the VM, and by callers whenever an operation on a
+//! handle fails, so that the next use looks the VM up afresh.
+
+use crate::hypervisor::{Error, Guest, Hypervisor};
+use log::debug;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+#[derive(Default)]
+pub struct DomainCache {
+    /// By the name or UUID they were looked up by.
+    domains: BTreeMap<String, Rc<dyn Guest>>,
+    /// Round trips to libvirt so far, for the debug log.
+    lookups: u64,
+}
+
+impl DomainCache {
+    /// The domain for `vm`, a name or UUID, looked up only if it isn't
+    /// cached.  Failed lookups aren't cached.
+    pub fn get(&mut self, conn: &dyn Hypervisor, vm: &str) -> Result<Rc<dyn Guest>, Error> {
+        if let Some(domain) = self.domains.get(vm) {
+            return Ok(domain.clone());
+        }
+        self.lookups += 1;
+        debug!("Looking up vm {} (libvirt lookup {})", vm, self.lookups);
+        let domain = conn.lookup(vm)?;
+        self.domains.insert(vm.to_owned(), domain.clone());
+        Ok(domain)
+    }
+
+    /// Drop every handle, as after a reconnect.
+    pub fn clear(&mut self) {
+        self.domains.clear();
+    }
+
+    /// Drop the handle for `vm`, if it is cached.
+    pub fn forget(&mut self, vm: &str) {
+        if self.domains.remove(vm).is_some() {
+            debug!("Forgot cached vm {}", vm);
+        }
+    }
+}
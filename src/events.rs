@@ -0,0 +1,180 @@
+//! Newline-delimited JSON stream of attach/detach events on a Unix socket.
+//!
+//! Any number of local clients (e.g. `socat - UNIX-CONNECT:<path>`) can
+//! connect and read one JSON object per line:
+//!
+//! ```text
+//! {"event":"attached","vm":"work","vid":"1050","pid":"0407","syspath":"/sys/devices/.../1-4"}
+//! ```
+//!
+//! The event loop never blocks on a client.  Output for a slow reader is
+//! buffered per client, and a client that falls more than `MAX_PENDING`
+//! bytes behind is disconnected.
+
+use log::warn;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+/// Bytes a client may fall behind before it is dropped.
+const MAX_PENDING: usize = 64 * 1024;
+
+struct Client {
+    stream: UnixStream,
+    pending: Vec<u8>,
+}
+
+pub struct EventSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+/// Remove a socket at `path` that nothing listens on any more, as left by
+/// a previous run.  Anything that isn't a socket is left alone.
+pub fn remove_stale_socket(path: &Path) {
+    let is_socket = std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket());
+    if is_socket && UnixStream::connect(path).is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+impl EventSocket {
+    /// Listen on `path`, replacing a stale socket left by a previous run.
+    /// Anything at `path` that isn't a socket is left alone, and the bind
+    /// fails.
+    pub fn bind(path: &Path) -> io::Result<EventSocket> {
+        remove_stale_socket(path);
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(EventSocket {
+            listener,
+            path: path.to_owned(),
+            clients: Vec::new(),
+        })
+    }
+
+    /// The listening socket, for registering with the poll loop.
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Accept every client waiting to connect.
+    pub fn accept(&mut self) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("dropping event client: {}", e);
+                        continue;
+                    }
+                    self.clients.push(Client {
+                        stream,
+                        pending: Vec::new(),
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("failed to accept event client: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Queue an event for every client and write as much as each will take.
+    pub fn send(&mut self, event: &str, vm: &str, vid: &str, pid: &str, syspath: &Path) {
+        let line = format!(
+            "{{\"event\":{},\"vm\":{},\"vid\":{},\"pid\":{},\"syspath\":{}}}\n",
+            json_str(event),
+            json_str(vm),
+            json_str(vid),
+            json_str(pid),
+            json_str(&syspath.to_string_lossy()),
+        );
+        for client in self.clients.iter_mut() {
+            client.pending.extend_from_slice(line.as_bytes());
+        }
+        self.flush();
+    }
+
+    /// Whether any client has output it hasn't read yet.
+    pub fn has_pending(&self) -> bool {
+        self.clients.iter().any(|client| !client.pending.is_empty())
+    }
+
+    /// Write buffered output, dropping clients that hung up or fell too far
+    /// behind.
+    pub fn flush(&mut self) {
+        self.clients.retain_mut(|client| {
+            while !client.pending.is_empty() {
+                match client.stream.write(&client.pending) {
+                    Ok(0) => return false,
+                    Ok(n) => {
+                        client.pending.drain(..n);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(_) => return false,
+                }
+            }
+            if client.pending.len() > MAX_PENDING {
+                warn!("dropping event client that fell behind");
+                return false;
+            }
+            true
+        });
+    }
+}
+
+impl Drop for EventSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Quote and escape `s` as a JSON string, which is also a valid TOML
+/// basic string.
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    #[test]
+    fn bind_replaces_a_stale_socket() {
+        let path = temp_path("stale.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        let socket = EventSocket::bind(&path).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+        drop(socket);
+    }
+
+    #[test]
+    fn bind_leaves_other_files_alone() {
+        let path = temp_path("not-a-socket");
+        std::fs::write(&path, "keep me").unwrap();
+        assert!(EventSocket::bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+//! The hypervisor operations the daemon needs, behind traits so that the
+//! matching and attach logic doesn't depend on libvirt directly.
+//!
+//! `Libvirt` is the only backend, and `Connections` puts several of them
+//! behind one for a repeated `--connect`.  Everything past startup goes
+//! through `Hypervisor` and `Guest`, so another backend, such as one that
+//! records calls for tests, only has to implement these.
+
+use crate::auth;
+use crate::is_uuid;
+use crate::lifecycle::{self, Lifecycle};
+use crate::wake::Sender;
+use log::warn;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+use virt::connect::Connect;
+use virt::domain::Domain;
+use virt::nodedev::NodeDevice;
+use virt::sys;
+
+/// A failed hypervisor operation, with the backend's message.
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<virt::error::Error> for Error {
+    fn from(e: virt::error::Error) -> Error {
+        Error(e.to_string())
+    }
+}
+
+/// A connection to the hypervisor.
+pub trait Hypervisor {
+    /// The VM named `vm`, or with that UUID.
+    fn lookup(&self, vm: &str) -> Result<Rc<dyn Guest>, Error>;
+    /// Every VM that is running or paused.
+    fn running(&self) -> Result<Vec<Rc<dyn Guest>>, Error>;
+    /// The XML description of the node device `name`, if there is one.
+    fn node_device_xml(&self, name: &str) -> Option<String>;
+    /// Whether the hypervisor still answers on the connection.
+    fn is_alive(&self) -> bool;
+    /// Send every start, stop and definition of a VM to `sender`.
+    fn watch_lifecycle(&self, sender: Sender<Lifecycle>) -> Result<(), Error>;
+    /// Open the connection afresh after it was lost, watching VMs again if
+    /// they were being watched.  Guests looked up before are stale.
+    fn reconnect(&self) -> Result<(), Error>;
+    fn close(self: Box<Self>) -> Result<(), Error>;
+}
+
+/// A VM on the hypervisor.
+pub trait Guest {
+    fn name(&self) -> Result<String, Error>;
+    fn uuid(&self) -> Result<String, Error>;
+    /// Whether it is running or paused, rather than shut off.
+    fn is_active(&self) -> Result<bool, Error>;
+    fn is_paused(&self) -> Result<bool, Error>;
+    fn resume(&self) -> Result<(), Error>;
+    /// Its live XML description.
+    fn xml_desc(&self) -> Result<String, Error>;
+    fn attach_device(&self, xml: &str) -> Result<(), Error>;
+    fn detach_device(&self, xml: &str) -> Result<(), Error>;
+}
+
+/// A libvirt connection.
+pub struct Libvirt {
+    uri: String,
+    /// Replaced by `reconnect`.
+    conn: RefCell<Connect>,
+    /// Whether libvirt's event loop runs, which domain events need.
+    events: bool,
+    /// Where `watch_lifecycle` sends events, to watch again on reconnect.
+    watcher: RefCell<Option<Sender<Lifecycle>>>,
+}
+
+impl Libvirt {
+    /// Connect to `uri`, answering libvirt's requests for credentials.
+    /// With `events`, libvirt's event loop is started first, so that
+    /// `watch_lifecycle` works; a failure to start it is only logged.
+    pub fn open(uri: &str, events: bool) -> Result<Libvirt, Error> {
+        let events = events
+            && lifecycle::start_event_loop()
+                .map_err(|e| {
+                    warn!(
+                        "no libvirt event loop, VMs won't get devices back on restart: {}",
+                        e
+                    )
+                })
+                .is_ok();
+        Ok(Libvirt {
+            uri: uri.to_owned(),
+            conn: RefCell::new(auth::open(uri)?),
+            events,
+            watcher: RefCell::new(None),
+        })
+    }
+
+    /// Connect to another `uri`, relying on the event loop `open` started
+    /// for this one, if any.
+    pub fn open_another(&self, uri: &str) -> Result<Libvirt, Error> {
+        Ok(Libvirt {
+            uri: uri.to_owned(),
+            conn: RefCell::new(auth::open(uri)?),
+            events: self.events,
+            watcher: RefCell::new(None),
+        })
+    }
+}
+
+impl Hypervisor for Libvirt {
+    fn lookup(&self, vm: &str) -> Result<Rc<dyn Guest>, Error> {
+        let domain = match is_uuid(vm) {
+            true => Domain::lookup_by_uuid_string(&self.conn.borrow(), vm)?,
+            _ => Domain::lookup_by_name(&self.conn.borrow(), vm)?,
+        };
+        Ok(Rc::new(domain))
+    }
+
+    fn running(&self) -> Result<Vec<Rc<dyn Guest>>, Error> {
+        let domains = self
+            .conn
+            .borrow()
+            .list_all_domains(sys::VIR_CONNECT_LIST_DOMAINS_ACTIVE)?;
+        Ok(domains
+            .into_iter()
+            .map(|d| Rc::new(d) as Rc<dyn Guest>)
+            .collect())
+    }
+
+    fn node_device_xml(&self, name: &str) -> Option<String> {
+        NodeDevice::lookup_by_name(&self.conn.borrow(), name)
+            .ok()?
+            .get_xml_desc(0)
+            .ok()
+    }
+
+    fn is_alive(&self) -> bool {
+        self.conn.borrow().is_alive().unwrap_or(false)
+    }
+
+    /// Does nothing if the event loop isn't running.
+    fn watch_lifecycle(&self, sender: Sender<Lifecycle>) -> Result<(), Error> {
+        if !self.events {
+            return Ok(());
+        }
+        lifecycle::watch(&self.conn.borrow(), sender.clone())?;
+        self.watcher.replace(Some(sender));
+        Ok(())
+    }
+
+    /// A failure to watch VMs on the new connection is only logged.
+    fn reconnect(&self) -> Result<(), Error> {
+        let conn = auth::open(&self.uri)?;
+        if let Some(sender) = self.watcher.borrow().clone() {
+            if let Err(e) = lifecycle::watch(&conn, sender) {
+                warn!("can't watch VMs starting and stopping: {}", e);
+            }
+        }
+        // The old connection is dead, so closing it can only fail.
+        let _ = self.conn.replace(conn).close();
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        self.conn.into_inner().close()?;
+        Ok(())
+    }
+}
+
+/// Split a VM argument scoped to one connection, `vm@uri`, into the VM and
+/// the URI.  The URI is whatever follows the first `@` that is followed by
+/// a scheme and `://`, so that both VM names and URIs may hold an `@`.
+pub fn split_scope(vm: &str) -> (&str, Option<&str>) {
+    let scoped = vm.match_indices('@').find(|(at, _)| {
+        let rest = &vm[at + 1..];
+        rest.find("://")
+            .is_some_and(|end| !rest[..end].contains(['@', '/']))
+    });
+    match scoped {
+        Some((at, _)) => (&vm[..at], Some(&vm[at + 1..])),
+        None => (vm, None),
+    }
+}
+
+/// Several connections behind one, for a repeated `--connect`.  A VM given
+/// as `vm@uri` is looked up on that connection alone, one given by UUID on
+/// each in turn, and one given by name only on the first, the primary, so
+/// that VMs of the same name elsewhere are never picked by accident.
+pub struct Connections {
+    /// Each connection with its URI, the primary first.
+    all: Vec<(String, Box<dyn Hypervisor>)>,
+}
+
+impl Connections {
+    pub fn new(all: Vec<(String, Box<dyn Hypervisor>)>) -> Connections {
+        Connections { all }
+    }
+
+    fn primary(&self) -> &dyn Hypervisor {
+        &*self.all[0].1
+    }
+}
+
+impl Hypervisor for Connections {
+    fn lookup(&self, vm: &str) -> Result<Rc<dyn Guest>, Error> {
+        match split_scope(vm) {
+            (name, Some(uri)) => match self.all.iter().find(|(u, _)| u == uri) {
+                Some((_, conn)) => conn.lookup(name),
+                None => Err(Error(format!("no --connect for {}", uri))),
+            },
+            (uuid, None) if is_uuid(uuid) => {
+                let mut error = None;
+                for (_, conn) in &self.all {
+                    match conn.lookup(uuid) {
+                        Ok(guest) => return Ok(guest),
+                        Err(e) => error = error.or(Some(e)),
+                    }
+                }
+                Err(error.unwrap_or_else(|| Error(format!("no domain {}", uuid))))
+            }
+            (name, None) => self.primary().lookup(name),
+        }
+    }
+
+    fn running(&self) -> Result<Vec<Rc<dyn Guest>>, Error> {
+        let mut running = Vec::new();
+        for (_, conn) in &self.all {
+            running.extend(conn.running()?);
+        }
+        Ok(running)
+    }
+
+    /// Devices are on this host, which the primary connection is to.
+    fn node_device_xml(&self, name: &str) -> Option<String> {
+        self.primary().node_device_xml(name)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.all.iter().all(|(_, conn)| conn.is_alive())
+    }
+
+    fn watch_lifecycle(&self, sender: Sender<Lifecycle>) -> Result<(), Error> {
+        for (_, conn) in &self.all {
+            conn.watch_lifecycle(sender.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Reopens only the connections that were lost.
+    fn reconnect(&self) -> Result<(), Error> {
+        for (uri, conn) in &self.all {
+            if !conn.is_alive() {
+                conn.reconnect()
+                    .map_err(|e| Error(format!("{}: {}", uri, e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) -> Result<(), Error> {
+        let mut result = Ok(());
+        for (_, conn) in self.all {
+            result = result.and(conn.close());
+        }
+        result
+    }
+}
+
+impl Guest for Domain {
+    fn name(&self) -> Result<String, Error> {
+        Ok(self.get_name()?)
+    }
+
+    fn uuid(&self) -> Result<String, Error> {
+        Ok(self.get_uuid_string()?)
+    }
+
+    fn is_active(&self) -> Result<bool, Error> {
+        Ok(Domain::is_active(self)?)
+    }
+
+    fn is_paused(&self) -> Result<bool, Error> {
+        Ok(self.get_state()?.0 == sys::VIR_DOMAIN_PAUSED)
+    }
+
+    fn resume(&self) -> Result<(), Error> {
+        Domain::resume(self)?;
+        Ok(())
+    }
+
+    fn xml_desc(&self) -> Result<String, Error> {
+        Ok(self.get_xml_desc(0)?)
+    }
+
+    fn attach_device(&self, xml: &str) -> Result<(), Error> {
+        Domain::attach_device(self, xml)?;
+        Ok(())
+    }
+
+    fn detach_device(&self, xml: &str) -> Result<(), Error> {
+        Domain::detach_device(self, xml)?;
+        Ok(())
+    }
+}
+
+/// A backend that keeps its VMs in memory and records every attach and
+/// detach, for tests.
+#[cfg(test)]
+pub mod mock {
+    use super::{Error, Guest, Hypervisor};
+    use crate::lifecycle::Lifecycle;
+    use crate::wake::Sender;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// An attach or detach, with the VM's name and the XML sent.
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Call {
+        Attach(String, String),
+        Detach(String, String),
+    }
+
+    #[derive(Default)]
+    pub struct MockHypervisor {
+        vms: Vec<Rc<MockGuest>>,
+        calls: Rc<RefCell<Vec<Call>>>,
+        /// The connection was lost, until a `reconnect`.
+        pub dead: Cell<bool>,
+        /// Fail every `reconnect`.
+        pub fail_reconnect: Cell<bool>,
+    }
+
+    impl MockHypervisor {
+        /// Define a running VM.
+        pub fn add_vm(&mut self, name: &str, uuid: &str) -> Rc<MockGuest> {
+            let vm = Rc::new(MockGuest {
+                name: name.to_owned(),
+                uuid: uuid.to_owned(),
+                active: Cell::new(true),
+                fail_attach: Cell::new(false),
+                hostdevs: RefCell::new(Vec::new()),
+                calls: self.calls.clone(),
+            });
+            self.vms.push(vm.clone());
+            vm
+        }
+
+        /// Every attach and detach so far, in order.
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl Hypervisor for MockHypervisor {
+        fn lookup(&self, vm: &str) -> Result<Rc<dyn Guest>, Error> {
+            self.vms
+                .iter()
+                .find(|g| g.name == vm || g.uuid == vm)
+                .map(|g| g.clone() as Rc<dyn Guest>)
+                .ok_or_else(|| Error(format!("no domain {}", vm)))
+        }
+
+        fn running(&self) -> Result<Vec<Rc<dyn Guest>>, Error> {
+            let running = self.vms.iter().filter(|g| g.active.get());
+            Ok(running.map(|g| g.clone() as Rc<dyn Guest>).collect())
+        }
+
+        fn node_device_xml(&self, _name: &str) -> Option<String> {
+            None
+        }
+
+        fn is_alive(&self) -> bool {
+            !self.dead.get()
+        }
+
+        fn watch_lifecycle(&self, _sender: Sender<Lifecycle>) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn reconnect(&self) -> Result<(), Error> {
+            if self.fail_reconnect.get() {
+                return Err(Error("connection refused".into()));
+            }
+            self.dead.set(false);
+            Ok(())
+        }
+
+        fn close(self: Box<Self>) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    pub struct MockGuest {
+        name: String,
+        uuid: String,
+        pub active: Cell<bool>,
+        /// Refuse every attach.
+        pub fail_attach: Cell<bool>,
+        /// XML of the hostdevs attached, which its live XML lists.
+        pub hostdevs: RefCell<Vec<String>>,
+        calls: Rc<RefCell<Vec<Call>>>,
+    }
+
+    impl Guest for MockGuest {
+        fn name(&self) -> Result<String, Error> {
+            Ok(self.name.clone())
+        }
+
+        fn uuid(&self) -> Result<String, Error> {
+            Ok(self.uuid.clone())
+        }
+
+        fn is_active(&self) -> Result<bool, Error> {
+            Ok(self.active.get())
+        }
+
+        fn is_paused(&self) -> Result<bool, Error> {
+            Ok(false)
+        }
+
+        fn resume(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        /// A guest with a USB controller and the attached hostdevs.
+        fn xml_desc(&self) -> Result<String, Error> {
+            Ok(format!(
+                "<domain type='kvm'><name>{}</name><devices><controller type='usb' model='qemu-xhci'/>{}</devices></domain>",
+                self.name,
+                self.hostdevs.borrow().concat()
+            ))
+        }
+
+        fn attach_device(&self, xml: &str) -> Result<(), Error> {
+            let call = Call::Attach(self.name.clone(), xml.to_owned());
+            self.calls.borrow_mut().push(call);
+            if self.fail_attach.get() {
+                return Err(Error("attach refused".into()));
+            }
+            self.hostdevs.borrow_mut().push(xml.to_owned());
+            Ok(())
+        }
+
+        fn detach_device(&self, xml: &str) -> Result<(), Error> {
+            let call = Call::Detach(self.name.clone(), xml.to_owned());
+            self.calls.borrow_mut().push(call);
+            self.hostdevs.borrow_mut().retain(|h| h != xml);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockHypervisor;
+    use super::*;
+
+    #[test]
+    fn scope_is_split_at_the_uri() {
+        assert_eq!(split_scope("work"), ("work", None));
+        assert_eq!(
+            split_scope("work@qemu:///session"),
+            ("work", Some("qemu:///session"))
+        );
+        assert_eq!(
+            split_scope("a@b@qemu+ssh://me@host/system"),
+            ("a@b", Some("qemu+ssh://me@host/system"))
+        );
+        assert_eq!(split_scope("me@home"), ("me@home", None));
+    }
+
+    #[test]
+    fn names_stay_on_their_connection() {
+        let mut system = MockHypervisor::default();
+        system.add_vm("work", "11111111-2222-3333-4444-555555555555");
+        let mut session = MockHypervisor::default();
+        session.add_vm("work", "66666666-7777-8888-9999-000000000000");
+        session.add_vm("games", "aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee");
+        let conns = Connections::new(vec![
+            ("qemu:///system".to_owned(), Box::new(system)),
+            ("qemu:///session".to_owned(), Box::new(session)),
+        ]);
+        let uuid = |vm: &str| conns.lookup(vm).and_then(|g| g.uuid()).ok();
+
+        assert_eq!(
+            uuid("work").as_deref(),
+            Some("11111111-2222-3333-4444-555555555555")
+        );
+        assert_eq!(
+            uuid("work@qemu:///session").as_deref(),
+            Some("66666666-7777-8888-9999-000000000000")
+        );
+        // Unscoped names are only looked for on the primary.
+        assert_eq!(uuid("games"), None);
+        assert!(uuid("games@qemu:///session").is_some());
+        assert_eq!(uuid("work@qemu:///elsewhere"), None);
+        // UUIDs are unique, so any connection will do.
+        assert_eq!(
+            uuid("aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee").as_deref(),
+            Some("aaaaaaaa-bbbb-cccc-dddd-eeeeeeeeeeee")
+        );
+        assert_eq!(conns.running().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,121 @@
+//! libvirt domain lifecycle events, so that devices follow a VM through
+//! shutdowns without being replugged.
+//!
+//! The `virt` bindings don't wrap domain events, so this goes through
+//! `virt::sys` directly.  libvirt only delivers events from its own event
+//! loop, which has to be registered before the hypervisor connection is
+//! opened and then run on a thread of its own.  The callback forwards each
+//! start, stop and (re)definition to the `poll` loop over a channel.
+
+use crate::wake::Sender;
+use log::warn;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::thread;
+use std::time::Duration;
+use virt::connect::Connect;
+use virt::error::Error;
+use virt::sys;
+
+/// A VM starting, stopping or being (re)defined.
+pub struct Lifecycle {
+    pub name: String,
+    pub uuid: String,
+    pub change: Change,
+}
+
+pub enum Change {
+    /// Started or resumed.
+    Started,
+    Stopped,
+    /// Defined, redefined or undefined, so any handle to it may be stale.
+    Defined,
+}
+
+/// Register libvirt's default event loop and run it on a new thread.  Must
+/// be called before the connection is opened.
+pub fn start_event_loop() -> Result<(), Error> {
+    if unsafe { sys::virEventRegisterDefaultImpl() } == -1 {
+        return Err(Error::last_error());
+    }
+    thread::spawn(|| loop {
+        if unsafe { sys::virEventRunDefaultImpl() } == -1 {
+            warn!("libvirt event loop failed: {}", Error::last_error());
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+    Ok(())
+}
+
+/// Send every start, stop and definition of a VM on `conn` to `sender`,
+/// from the event loop thread.
+pub fn watch(conn: &Connect, sender: Sender<Lifecycle>) -> Result<(), Error> {
+    let opaque = Box::into_raw(Box::new(sender)) as *mut c_void;
+    // RegisterAny takes every event's callback as the generic type; libvirt
+    // calls it with the arguments of the event registered for.
+    let callback = unsafe {
+        std::mem::transmute::<
+            extern "C" fn(sys::virConnectPtr, sys::virDomainPtr, c_int, c_int, *mut c_void),
+            unsafe extern "C" fn(sys::virConnectPtr, sys::virDomainPtr, *mut c_void),
+        >(on_lifecycle)
+    };
+    let ret = unsafe {
+        sys::virConnectDomainEventRegisterAny(
+            conn.as_ptr(),
+            std::ptr::null_mut(),
+            sys::VIR_DOMAIN_EVENT_ID_LIFECYCLE as c_int,
+            Some(callback),
+            opaque,
+            Some(free_sender),
+        )
+    };
+    if ret == -1 {
+        // libvirt only frees the opaque pointer of a registered callback.
+        drop(unsafe { Box::from_raw(opaque as *mut Sender<Lifecycle>) });
+        return Err(Error::last_error());
+    }
+    Ok(())
+}
+
+extern "C" fn on_lifecycle(
+    _conn: sys::virConnectPtr,
+    dom: sys::virDomainPtr,
+    event: c_int,
+    _detail: c_int,
+    opaque: *mut c_void,
+) {
+    let change = match event {
+        e if e == sys::VIR_DOMAIN_EVENT_STARTED as c_int
+            || e == sys::VIR_DOMAIN_EVENT_RESUMED as c_int =>
+        {
+            Change::Started
+        }
+        e if e == sys::VIR_DOMAIN_EVENT_STOPPED as c_int => Change::Stopped,
+        e if e == sys::VIR_DOMAIN_EVENT_DEFINED as c_int
+            || e == sys::VIR_DOMAIN_EVENT_UNDEFINED as c_int =>
+        {
+            Change::Defined
+        }
+        _ => return,
+    };
+    let sender = unsafe { &*(opaque as *const Sender<Lifecycle>) };
+    let name = unsafe {
+        let name = sys::virDomainGetName(dom);
+        if name.is_null() {
+            return;
+        }
+        CStr::from_ptr(name).to_string_lossy().into_owned()
+    };
+    let mut uuid = [0 as c_char; sys::VIR_UUID_STRING_BUFLEN as usize];
+    let uuid = unsafe {
+        if sys::virDomainGetUUIDString(dom, uuid.as_mut_ptr()) == -1 {
+            return;
+        }
+        CStr::from_ptr(uuid.as_ptr()).to_string_lossy().into_owned()
+    };
+    let _ = sender.send(Lifecycle { name, uuid, change });
+}
+
+extern "C" fn free_sender(opaque: *mut c_void) {
+    drop(unsafe { Box::from_raw(opaque as *mut Sender<Lifecycle>) });
+}
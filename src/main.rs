@@ -1,291 +1,7240 @@
+mod audit;
+mod auth;
+mod config;
+mod control;
+mod domains;
+mod events;
+mod hypervisor;
+mod lifecycle;
+mod metrics;
+mod monitor;
+mod notify;
+mod record;
+mod signals;
+mod store;
+mod wake;
+
+use audit::{utc_timestamp, AuditLog, Outcome};
+use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
 use dbus::blocking::Connection as DbusConnection;
-use dbus_crossroads::{Context, Crossroads};
+use dbus::channel::{MatchingReceiver, Sender as _};
+use dbus::message::MatchRule;
+use dbus::Message;
+use dbus_crossroads::{Context, Crossroads, MethodErr};
+use domains::DomainCache;
+use events::{json_str, EventSocket};
+use hypervisor::{Connections, Guest, Hypervisor, Libvirt};
+use lifecycle::{Change, Lifecycle};
+use log::{debug, error, info, warn, LevelFilter};
+use metrics::Metrics;
+use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
-use std::collections::{BTreeMap, HashSet};
+use monitor::Monitor;
+use notify::Notifier;
+use record::{Record, Recorder};
+use signals::Signals;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
 use std::error::Error;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, PoisonError, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use udev::MonitorBuilder;
-use virt::connect::Connect;
-use virt::domain::Domain;
 
+/// A request from D-Bus or the control socket to the `poll` loop.  Those
+/// with a `reply` are answered over it.
 #[derive(Debug)]
-pub enum DbusCommand {
-    Add,
-    Remove,
+pub enum Command {
+    Add {
+        vm: String,
+        filter: UsbFilter,
+    },
+    Remove {
+        vm: String,
+        filter: UsbFilter,
+    },
+    /// Add several filters for one VM at once; replies with how many of
+    /// them weren't registered already.
+    AddMany {
+        vm: String,
+        filters: Vec<UsbFilter>,
+        reply: Sender<String>,
+    },
+    /// An empty template restores the built-in one.
+    SetTemplate(String),
+    /// Zero disables the power check.
+    SetMaxPower(u32),
+    /// `startupPolicy` for new hostdevs' `<source>`; `None` leaves it out.
+    SetStartupPolicy(Option<String>),
+    /// Detach whatever is attached at a syspath; replies with the VMs.
+    DetachPath {
+        syspath: PathBuf,
+        reply: Sender<String>,
+    },
+    /// Detach from a VM the devices a filter matches, dropping the VM's
+    /// filters that match them if `remove_filters`; replies with how each
+    /// detach went.
+    Detach {
+        vm: String,
+        filter: UsbFilter,
+        remove_filters: bool,
+        reply: Sender<String>,
+    },
+    /// Detach every device from a VM and drop its filters; replies with how
+    /// many devices were detached.
+    DetachAll {
+        vm: String,
+        reply: Sender<String>,
+    },
+    /// Attach again the devices a VM, or every VM without one, is believed
+    /// to hold but no longer has; replies with how each went.
+    Reattach {
+        vm: Option<String>,
+        reply: Sender<String>,
+    },
+    /// Reply with the effective configuration.
+    GetConfig(Sender<String>),
+    /// Load the `--config` file again and apply what changed; replies with
+    /// an `ApplyResult` as JSON.
+    ReloadFilters(Sender<String>),
+    /// Reply with the latest failure of a VM, or of any VM if empty.
+    LastError {
+        vm: String,
+        reply: Sender<String>,
+    },
+    /// Reply with Prometheus metrics.
+    Metrics(Sender<String>),
+    /// Enable or disable a registered filter without removing it.
+    SetEnabled {
+        vm: String,
+        filter: UsbFilter,
+        enabled: bool,
+    },
+    /// Set a registered filter's boot order; zero clears it.
+    SetBootOrder {
+        vm: String,
+        filter: UsbFilter,
+        order: u32,
+    },
+    /// Set the guest controller and port a registered filter's devices are
+    /// attached at; both `None` lets libvirt choose again.
+    SetGuestAddress {
+        vm: String,
+        filter: UsbFilter,
+        bus: Option<u32>,
+        port: Option<u32>,
+    },
+    /// Set a registered filter's own XML template; `None` goes back to the
+    /// global one.
+    SetFilterTemplate {
+        vm: String,
+        filter: UsbFilter,
+        template: Option<String>,
+    },
+    /// Set how many times a registered filter's failed attaches are
+    /// retried before the device is marked failed; `None` goes back to
+    /// `--attach-retries` and leaving it pending.
+    SetAttachRetries {
+        vm: String,
+        filter: UsbFilter,
+        retries: Option<u32>,
+    },
+    /// Move matching devices from one VM to another; replies with their
+    /// syspaths.
+    Move {
+        from: String,
+        to: String,
+        filter: UsbFilter,
+        reply: Sender<String>,
+    },
+    /// Stop attaching new devices, leaving existing ones attached.
+    Drain,
     Shutdown,
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+impl Command {
+    /// Add or remove `filter` for `vm`.
+    fn add_or_remove(add: bool, vm: &str, filter: UsbFilter) -> Command {
+        let vm = vm.to_owned();
+        match add {
+            true => Command::Add { vm, filter },
+            _ => Command::Remove { vm, filter },
+        }
+    }
+
+    /// The VM and filter of a command that names a registered filter.
+    fn named_filter(&mut self) -> Option<(&mut String, &mut UsbFilter)> {
+        match self {
+            Command::Add { vm, filter }
+            | Command::Remove { vm, filter }
+            | Command::SetEnabled { vm, filter, .. }
+            | Command::SetBootOrder { vm, filter, .. }
+            | Command::SetGuestAddress { vm, filter, .. }
+            | Command::SetAttachRetries { vm, filter, .. }
+            | Command::SetFilterTemplate { vm, filter, .. } => Some((vm, filter)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct UsbFilter {
     vid: Option<String>,
     pid: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+    /// For a driver filter, the interface driver to match, e.g. `cdc_acm`.
+    /// For an observed device, every driver bound to its interfaces,
+    /// comma-separated.
+    driver: Option<String>,
+    /// For a port filter, the kernel port path to match, e.g. `1-4.2`.  For
+    /// an observed device, the port path it is plugged into: the last
+    /// component of its syspath.
+    port: Option<String>,
+    /// For a hub filter, the port path of a hub, e.g. `1-4`: the filter
+    /// matches only devices plugged in behind it, at any depth.
+    hub: Option<String>,
+    /// USB device class (`bDeviceClass`) as two lowercase hex digits.  Only
+    /// the device-level class: `None` for a device that reports `00` and
+    /// leaves the class to its interfaces.
+    class: Option<String>,
+    /// USB device subclass (`bDeviceSubClass`), as `class`.  Only set
+    /// alongside `class`.
+    subclass: Option<String>,
+    /// USB speed the device enumerated at, as `canon_speed`, e.g. `super`.
+    speed: Option<String>,
+    /// Matches PCI devices, by `vid`/`pid` (vendor and device ID) or
+    /// `pci_address`, rather than USB ones.  Set on observed PCI devices.
+    pci: bool,
+    /// For a PCI filter or device, the PCI address, as `canon_pci_address`.
+    pci_address: Option<String>,
+    /// Text the device's `manufacturer` and `product` strings must contain,
+    /// on top of any VID/PID.  Only used by filters; an observed device's
+    /// strings are in `manufacturer` and `product`.
+    manufacturer_match: Option<String>,
+    product_match: Option<String>,
+    /// Compare `manufacturer_match` and `product_match` with the whole
+    /// string, case-sensitively, rather than as a case-insensitive
+    /// substring.
+    exact_match: bool,
+    /// The VM this filter is registered under is a name template, resolved
+    /// per device by `resolve_vm_template`.
+    templated_vm: bool,
+    /// Disabled filters are kept but skipped during matching.  Not part of
+    /// the filter's identity.
+    enabled: bool,
+    /// Run `--confirm-hook` after each attach and retry until the guest
+    /// confirms it saw the device.  Not part of the filter's identity.
+    confirm: bool,
+    /// `<boot order='N'/>` for the hostdevs this filter attaches.  Not part
+    /// of the filter's identity.
+    boot_order: Option<u32>,
+    /// Guest USB controller index and port for the hostdevs this filter
+    /// attaches, rather than letting libvirt pick.  Not part of the
+    /// filter's identity.
+    guest_bus: Option<u32>,
+    guest_port: Option<u32>,
+    /// How many times a failed attach is retried, overriding
+    /// `--attach-retries`, before the device is marked failed rather than
+    /// left pending.  Not part of the filter's identity.
+    max_attach_retries: Option<u32>,
+    /// XML template for the hostdevs this filter attaches, overriding
+    /// `SetTemplate`'s.  Not part of the filter's identity.
+    xml_template: Option<String>,
 }
 
-struct DbusDevice {
-    sender: Sender<(DbusCommand, String, UsbFilter)>,
+/// A filter's guest-side options for the hostdevs it attaches.
+#[derive(Clone, Default)]
+struct Placement {
+    boot_order: Option<u32>,
+    /// Guest USB controller index and, optionally, port.
+    guest_address: Option<(u32, Option<u32>)>,
+    /// The filter's own XML template, if it has one.
+    xml_template: Option<String>,
 }
 
-// $ dbus-send --type=method_call --print-reply --dest=com.stormcrow.device /device com.stormcrow.device.Add string:<VM> string:<VID> string:<PID>
-fn dbus_server(sender: Sender<(DbusCommand, String, UsbFilter)>) -> Result<(), Box<dyn Error>> {
-    let c = DbusConnection::new_session()?;
-    c.request_name("com.stormcrow.device", false, true, false)?;
-    let mut cr = Crossroads::new();
-    let iface_token = cr.register("com.stormcrow.device", |b| {
-        b.method(
-            "Add",
-            ("vm", "vid", "pid"),
-            ("reply",),
-            move |_ctx: &mut Context,
-                  dev: &mut DbusDevice,
-                  (vm, vid, pid): (String, String, String)| {
-                println!("Incoming Add call for {}:{}!", vid, pid);
-                let filter = UsbFilter {
-                    vid: match vid.len() == 4 {
-                        true => Some(vid),
-                        _ => None,
-                    },
-                    pid: match pid.len() == 4 {
-                        true => Some(pid),
-                        _ => None,
-                    },
-                };
-                dev.sender
-                    .send((DbusCommand::Add, vm, filter))
-                    .expect("failed to transmit from dbus channel");
-                let reply = "OK";
-                Ok((reply,))
-            },
-        );
-        b.method(
-            "Remove",
-            ("vm", "vid", "pid"),
-            ("reply",),
-            move |_ctx: &mut Context,
-                  dev: &mut DbusDevice,
-                  (vm, vid, pid): (String, String, String)| {
-                println!("Incoming Remove call for {}:{}!", vid, pid);
-                let filter = UsbFilter {
-                    vid: match vid.len() == 4 {
-                        true => Some(vid),
-                        _ => None,
-                    },
-                    pid: match pid.len() == 4 {
-                        true => Some(pid),
-                        _ => None,
-                    },
-                };
-                dev.sender
-                    .send((DbusCommand::Remove, vm, filter))
-                    .expect("failed to transmit from dbus channel");
-                let reply = "OK";
-                Ok((reply,))
-            },
-        );
-        b.method(
-            "Quit",
-            (),
-            ("reply",),
-            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
-                dev.sender
-                    .send((
-                        DbusCommand::Shutdown,
-                        "".into(),
-                        UsbFilter {
-                            vid: None,
-                            pid: None,
-                        },
-                    ))
-                    .expect("failed to transmit from dbus channel");
-                Ok(("BYE",))
+impl Default for UsbFilter {
+    fn default() -> Self {
+        UsbFilter {
+            vid: None,
+            pid: None,
+            manufacturer: None,
+            product: None,
+            serial: None,
+            driver: None,
+            port: None,
+            hub: None,
+            class: None,
+            subclass: None,
+            speed: None,
+            pci: false,
+            pci_address: None,
+            manufacturer_match: None,
+            product_match: None,
+            exact_match: false,
+            templated_vm: false,
+            enabled: true,
+            confirm: false,
+            boot_order: None,
+            guest_bus: None,
+            guest_port: None,
+            max_attach_retries: None,
+            xml_template: None,
+        }
+    }
+}
+
+impl PartialEq for UsbFilter {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for UsbFilter {}
+
+impl Hash for UsbFilter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state);
+    }
+}
+
+impl UsbFilter {
+    /// The fields that decide whether two filters are the same filter,
+    /// grouped since tuples only compare and hash up to twelve fields.
+    #[allow(clippy::type_complexity)]
+    fn identity(
+        &self,
+    ) -> (
+        [&Option<String>; 11],
+        (bool, &Option<String>),
+        (&Option<String>, &Option<String>, bool),
+        bool,
+    ) {
+        (
+            [
+                &self.vid,
+                &self.pid,
+                &self.manufacturer,
+                &self.product,
+                &self.serial,
+                &self.driver,
+                &self.port,
+                &self.hub,
+                &self.class,
+                &self.subclass,
+                &self.speed,
+            ],
+            (self.pci, &self.pci_address),
+            (
+                &self.manufacturer_match,
+                &self.product_match,
+                self.exact_match,
+            ),
+            self.templated_vm,
+        )
+    }
+
+    /// A fingerprint filter identifies a device only by its `manufacturer`,
+    /// `product` and `serial` descriptor strings, with no VID/PID.
+    fn is_fingerprint(&self) -> bool {
+        self.vid.is_none()
+            && self.pid.is_none()
+            && self.manufacturer.is_some()
+            && self.product.is_some()
+            && self.serial.is_some()
+    }
+
+    /// Whether the filter has a `manufacturer_match` or `product_match`.
+    fn has_string_match(&self) -> bool {
+        self.manufacturer_match.is_some() || self.product_match.is_some()
+    }
+
+    /// Whether `device` is plugged in behind `hub`, if the filter has one.
+    /// Hubs behind it don't count, as a hub isn't passed through itself.
+    fn hub_matches(&self, device: &UsbFilter) -> bool {
+        let hub = match self.hub.as_deref() {
+            Some(hub) => hub,
+            None => return true,
+        };
+        let behind = device
+            .port
+            .as_deref()
+            .and_then(|port| port.strip_prefix(hub))
+            .is_some_and(|rest| rest.starts_with('.'));
+        behind && device.class.as_deref() != Some(USB_CLASS_HUB)
+    }
+
+    /// Whether `device`'s descriptor strings satisfy `manufacturer_match`
+    /// and `product_match`.  A device without the string doesn't.
+    fn strings_match(&self, device: &UsbFilter) -> bool {
+        [
+            (&self.manufacturer_match, &device.manufacturer),
+            (&self.product_match, &device.product),
+        ]
+        .iter()
+        .all(|(want, got)| match (want.as_deref(), got.as_deref()) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(want), Some(got)) if self.exact_match => want == got,
+            (Some(want), Some(got)) => got.to_lowercase().contains(&want.to_lowercase()),
+        })
+    }
+
+    /// Whether this registered filter matches an observed `device`.
+    ///
+    /// Fingerprint filters compare the three descriptor strings exactly
+    /// (case-sensitive, after trimming surrounding whitespace from sysfs).
+    /// Driver filters match a device with that driver bound to any of its
+    /// interfaces, and port filters whatever is plugged into that port.
+    /// Any other filter matches if the device's VID matches the filter's,
+    /// its PID matches any of the filter's comma-separated PIDs, and its
+    /// serial matches the filter's: any serial if the filter has none, only
+    /// a missing or empty one if the filter's is empty, and an equal one
+    /// otherwise.  A filter ID may be a wildcard or prefix (see
+    /// `id_matches`).  A class, subclass or speed, if the filter has one,
+    /// must match as well, and so must a manufacturer or product match (see
+    /// `strings_match`), and a hub (see `hub_matches`); with one of the last
+    /// two, a filter without a VID or PID matches any.  PCI filters only
+    /// ever match PCI devices, by address or vendor and device ID, and other
+    /// filters only USB devices.
+    fn matches(&self, device: &UsbFilter) -> bool {
+        if self.pci != device.pci {
+            return false;
+        }
+        if let Some(address) = self.pci_address.as_deref() {
+            return device.pci_address.as_deref() == Some(address);
+        }
+        let class_matches = [
+            (&self.class, &device.class),
+            (&self.subclass, &device.subclass),
+            (&self.speed, &device.speed),
+        ]
+        .iter()
+        .all(|(want, got)| want.is_none() || want == got);
+        if !class_matches || !self.strings_match(device) || !self.hub_matches(device) {
+            return false;
+        }
+        if let Some(port) = self.port.as_deref() {
+            return device.port.as_deref() == Some(port);
+        }
+        if let Some(driver) = self.driver.as_deref() {
+            return device
+                .driver
+                .as_deref()
+                .is_some_and(|bound| bound.split(',').any(|d| d == driver));
+        }
+        if self.is_fingerprint() {
+            return self.manufacturer == device.manufacturer
+                && self.product == device.product
+                && self.serial == device.serial;
+        }
+        let any_id = self.has_string_match() || self.hub.is_some();
+        let vid_matches = match (self.vid.as_deref(), device.vid.as_deref()) {
+            (Some(pattern), Some(vid)) => id_matches(pattern, vid),
+            (None, _) => any_id,
+            _ => false,
+        };
+        let pid_matches = match (self.pid.as_deref(), device.pid.as_deref()) {
+            (Some(pids), Some(pid)) => pids.split(',').any(|p| id_matches(p, pid)),
+            (None, _) => any_id,
+            _ => false,
+        };
+        // A device reporting an empty serial has none.
+        let serial = device.serial.as_deref().filter(|s| !s.is_empty());
+        let serial_matches = match self.serial.as_deref() {
+            None => true,
+            Some("") => serial.is_none(),
+            want => want == serial,
+        };
+        vid_matches && pid_matches && serial_matches
+    }
+
+    /// Where this filter's hostdevs go in the guest, and the template they
+    /// are built from.  A guest port without a bus is on controller 0, and
+    /// a bus without a port leaves the port to libvirt.
+    fn placement(&self) -> Placement {
+        Placement {
+            boot_order: self.boot_order,
+            xml_template: self.xml_template.clone(),
+            guest_address: match (self.guest_bus, self.guest_port) {
+                (None, None) => None,
+                (bus, port) => Some((bus.unwrap_or(0), port)),
             },
-        );
-    });
+        }
+    }
+
+    /// How specifically this filter identifies a device, for choosing
+    /// between several filters of one VM that match it.  A VID/PID with a
+    /// serial beats a fingerprint, which beats a port or PCI address, which
+    /// beats a single VID/PID, which beats a list of PIDs, which beats a VID
+    /// or PID with a wildcard or left out, which beats a driver.
+    fn specificity(&self) -> u32 {
+        let wildcard = [&self.vid, &self.pid]
+            .iter()
+            .any(|id| id.as_deref().is_some_and(|id| id.contains('*')));
+        match (self.is_fingerprint(), self.pid.as_deref()) {
+            _ if self.driver.is_some() => 0,
+            _ if self.port.is_some() || self.pci_address.is_some() => 4,
+            (false, _) if self.serial.is_some() => 6,
+            (true, _) => 5,
+            _ if wildcard || self.vid.is_none() || self.pid.is_none() => 1,
+            (false, Some(pid)) if !pid.contains(',') => 3,
+            _ => 2,
+        }
+    }
+
+    /// The filter as fields of a `--record` event.
+    fn to_fields(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        let strings = [
+            ("vid", &self.vid),
+            ("pid", &self.pid),
+            ("manufacturer", &self.manufacturer),
+            ("product", &self.product),
+            ("serial", &self.serial),
+            ("driver", &self.driver),
+            ("port", &self.port),
+            ("hub", &self.hub),
+            ("class", &self.class),
+            ("subclass", &self.subclass),
+            ("speed", &self.speed),
+            ("pci_address", &self.pci_address),
+            ("manufacturer_match", &self.manufacturer_match),
+            ("product_match", &self.product_match),
+        ];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                fields.insert(key.to_owned(), value.clone());
+            }
+        }
+        if self.pci {
+            fields.insert("pci".to_owned(), "1".to_owned());
+        }
+        if self.exact_match {
+            fields.insert("exact_match".to_owned(), "1".to_owned());
+        }
+        if self.templated_vm {
+            fields.insert("templated".to_owned(), "1".to_owned());
+        }
+        if self.confirm {
+            fields.insert("confirm".to_owned(), "1".to_owned());
+        }
+        if let Some(order) = self.boot_order {
+            fields.insert("boot".to_owned(), order.to_string());
+        }
+        if let Some(bus) = self.guest_bus {
+            fields.insert("guest_bus".to_owned(), bus.to_string());
+        }
+        if let Some(port) = self.guest_port {
+            fields.insert("guest_port".to_owned(), port.to_string());
+        }
+        if let Some(retries) = self.max_attach_retries {
+            fields.insert("max_attach_retries".to_owned(), retries.to_string());
+        }
+        if let Some(template) = &self.xml_template {
+            fields.insert("template".to_owned(), template.clone());
+        }
+        fields
+    }
+
+    /// `to_fields`, plus `disabled` for a disabled filter, for `List` and
+    /// `--state-file`.
+    fn to_state_fields(&self) -> BTreeMap<String, String> {
+        let mut fields = self.to_fields();
+        if !self.enabled {
+            fields.insert("disabled".to_owned(), "1".to_owned());
+        }
+        fields
+    }
 
-    cr.insert("/device", &[iface_token], DbusDevice { sender });
+    /// The filter with its VID and PID in canonical form, as `call` takes
+    /// them, for filters read back from a file that may predate that or
+    /// have been edited by hand.  An ID that isn't valid is kept as it is.
+    fn canonicalized(self) -> UsbFilter {
+        UsbFilter {
+            vid: self.vid.map(|vid| canon_id_pattern(&vid).unwrap_or(vid)),
+            pid: self.pid.map(|pid| canon_id_list(&pid).unwrap_or(pid)),
+            ..self
+        }
+    }
 
-    // Serve clients forever.
-    cr.serve(&c)?;
-    Ok(())
+    /// The filter recorded by `to_fields` or `to_state_fields`.
+    fn from_fields(fields: &BTreeMap<String, String>) -> UsbFilter {
+        UsbFilter {
+            vid: fields.get("vid").cloned(),
+            pid: fields.get("pid").cloned(),
+            manufacturer: fields.get("manufacturer").cloned(),
+            product: fields.get("product").cloned(),
+            serial: fields.get("serial").cloned(),
+            driver: fields.get("driver").cloned(),
+            port: fields.get("port").cloned(),
+            hub: fields.get("hub").cloned(),
+            class: fields.get("class").cloned(),
+            subclass: fields.get("subclass").cloned(),
+            speed: fields.get("speed").cloned(),
+            pci: fields.contains_key("pci"),
+            pci_address: fields.get("pci_address").cloned(),
+            manufacturer_match: fields.get("manufacturer_match").cloned(),
+            product_match: fields.get("product_match").cloned(),
+            exact_match: fields.contains_key("exact_match"),
+            templated_vm: fields.contains_key("templated"),
+            confirm: fields.contains_key("confirm"),
+            boot_order: fields.get("boot").and_then(|b| b.parse().ok()),
+            guest_bus: fields.get("guest_bus").and_then(|b| b.parse().ok()),
+            guest_port: fields.get("guest_port").and_then(|p| p.parse().ok()),
+            max_attach_retries: fields
+                .get("max_attach_retries")
+                .and_then(|r| r.parse().ok()),
+            xml_template: fields.get("template").cloned(),
+            enabled: !fields.contains_key("disabled"),
+        }
+    }
 }
 
-fn usb_xml(vid: &str, pid: &str, bus: &str, dev: &str) -> String {
-    format!(
-        r"
-<hostdev mode='subsystem' type='usb'>
-  <source>
-    <vendor id='0x{}'/>
-    <product id='0x{}'/>
-    <address bus='{}' device='{}'/>
-  </source>
-</hostdev>
-",
-        vid, pid, bus, dev
-    )
+#[derive(Clone)]
+struct DbusDevice {
+    sender: wake::Sender<Command>,
+    /// Set once shutdown starts; new commands are rejected from then on.
+    shutting_down: Arc<AtomicBool>,
+    /// Take VIDs and PIDs exactly as given, for `--legacy-match`.
+    legacy_match: bool,
+    /// What query methods read, without waiting for the `poll` loop.
+    snapshot: Arc<RwLock<Snapshot>>,
 }
 
-pub fn poll(
-    mut socket: udev::MonitorSocket,
-    receiver: Receiver<(DbusCommand, String, UsbFilter)>,
-) -> io::Result<()> {
-    let mut poll = Poll::new()?;
-    let mut events = Events::with_capacity(1024);
+impl DbusDevice {
+    /// Forward a command to the `poll` loop, unless it is shutting down.
+    fn send(&self, cmd: Command) -> Result<(), MethodErr> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err(MethodErr::failed("shutting down"));
+        }
+        self.sender
+            .send(cmd)
+            .map_err(|_| MethodErr::failed("event loop has exited"))
+    }
+}
 
-    let mut filters = BTreeMap::<String, HashSet<UsbFilter>>::new();
-    let mut sysdevs = BTreeMap::<PathBuf, UsbFilter>::new();
-    let mut xmls = BTreeMap::<String, Vec<(PathBuf, String)>>::new();
+/// Canonical form of a USB vendor or product ID: lowercase hex without a
+/// `0x` prefix, zero-padded to four digits (`0x46D` becomes `046d`).
+///
+/// Returns `None` if `id` isn't a 1-4 digit hex number.
+fn canon_id(id: &str) -> Option<String> {
+    let id = id.trim();
+    let id = id
+        .strip_prefix("0x")
+        .or_else(|| id.strip_prefix("0X"))
+        .unwrap_or(id);
+    if id.is_empty() || id.len() > 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{:0>4}", id.to_ascii_lowercase()))
+}
 
-    let uri = "qemu:///system";
-    println!("Attempting to connect to hypervisor: '{}'...", uri);
-    let mut conn = match Connect::open(uri) {
-        Ok(c) => c,
-        Err(e) => panic!("No connection to hypervisor: {}", e),
+/// Canonical form of a VID or PID given for a filter: as `canon_id`, or a
+/// wildcard.  `*` matches any ID, and up to three hex digits followed by
+/// `*` match IDs starting with them (`0x04*` becomes `04*`).
+///
+/// Returns `None` if `id` is neither.
+fn canon_id_pattern(id: &str) -> Option<String> {
+    let id = id.trim();
+    let prefix = match id.strip_suffix('*') {
+        Some(prefix) => prefix,
+        None => return canon_id(id),
     };
+    let prefix = prefix
+        .strip_prefix("0x")
+        .or_else(|| prefix.strip_prefix("0X"))
+        .unwrap_or(prefix);
+    if prefix.len() > 3 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{}*", prefix.to_ascii_lowercase()))
+}
 
-    poll.registry().register(
-        &mut socket,
-        Token(0),
-        Interest::READABLE | Interest::WRITABLE,
-    )?;
+/// Whether a device's canonical ID matches a filter's, as made by
+/// `canon_id_pattern`.
+fn id_matches(pattern: &str, id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => id.starts_with(prefix),
+        None => pattern == id,
+    }
+}
 
-    println!("Polling udev monitor...");
-    'event: loop {
-        poll.poll(&mut events, Some(Duration::from_millis(200)))?;
-        while let Ok(msg) = receiver.try_recv() {
-            match msg.0 {
-                DbusCommand::Shutdown => {
-                    break 'event;
-                }
-                DbusCommand::Add => {
-                    let vm = msg.1;
-                    let filter = msg.2;
-                    if !filters.contains_key(&vm) {
-                        filters.insert(vm.clone(), HashSet::new());
-                    }
-                    if let Some(usb_filters) = filters.get_mut(&vm) {
-                        if !usb_filters.contains(&filter) {
-                            println!("udev add: {:?}:{:?}", filter.vid, filter.pid);
-                            usb_filters.insert(filter);
-                        }
-                    }
+/// A VID or PID as the original exact matching took it: used verbatim if it
+/// is four characters long, otherwise dropped so the filter never matches.
+/// Wildcards didn't exist, so an ID containing `*` is dropped too.
+fn legacy_id(id: &str) -> Option<String> {
+    Some(id.to_owned()).filter(|id| id.len() == 4 && !id.contains('*'))
+}
+
+/// Canonical form of a USB class or subclass code: two lowercase hex
+/// digits, with an optional `0x` prefix stripped (`0xE` becomes `0e`).
+///
+/// Returns `None` if `code` isn't a 1-2 digit hex number.
+fn canon_class(code: &str) -> Option<String> {
+    let code = code.trim();
+    let code = code
+        .strip_prefix("0x")
+        .or_else(|| code.strip_prefix("0X"))
+        .unwrap_or(code);
+    if code.is_empty() || code.len() > 2 || !code.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{:0>2}", code.to_ascii_lowercase()))
+}
+
+/// USB speeds by name, with the Mbps that sysfs `speed` reports for them.
+const USB_SPEEDS: &[(&str, &[&str])] = &[
+    ("low", &["1.5"]),
+    ("full", &["12"]),
+    ("high", &["480"]),
+    ("super", &["5000"]),
+    ("super-plus", &["10000", "20000"]),
+];
+
+/// Canonical form of a USB speed: its name in `USB_SPEEDS`, given the name
+/// or the Mbps as sysfs reports it.  `None` if it is neither.
+fn canon_speed(speed: &str) -> Option<String> {
+    let speed = speed.trim().to_ascii_lowercase();
+    USB_SPEEDS
+        .iter()
+        .find(|(name, mbps)| *name == speed || mbps.contains(&speed.as_str()))
+        .map(|(name, _)| (*name).to_owned())
+}
+
+/// Canonical form of a comma-separated list of IDs: each entry through
+/// `canon_id_pattern`, sorted and deduplicated so the same set always
+/// compares equal (`407, 0x0410` becomes `0407,0410`).
+///
+/// Returns `None` if any entry isn't a valid ID or wildcard.
+fn canon_id_list(ids: &str) -> Option<String> {
+    let mut ids = ids
+        .split(',')
+        .map(canon_id_pattern)
+        .collect::<Option<Vec<_>>>()?;
+    ids.sort();
+    ids.dedup();
+    Some(ids.join(","))
+}
+
+/// Whether `port` is a kernel USB port path: the bus number, `-`, then the
+/// port on the root hub and on each hub below it, separated by dots
+/// (`1-4.2` is port 2 of the hub on port 4 of bus 1).
+fn is_port_path(port: &str) -> bool {
+    let numbers = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    match port.split_once('-') {
+        Some((bus, ports)) => numbers(bus) && ports.split('.').all(numbers),
+        None => false,
+    }
+}
+
+/// USB device class of hubs.
+const USB_CLASS_HUB: &str = "09";
+
+/// The port path of a hub given by its port path, e.g. `1-4`, or by its
+/// syspath, which ends in the port path.  `None` if it is neither.
+fn hub_port(hub: &str) -> Option<String> {
+    let port = hub.trim().trim_end_matches('/').rsplit('/').next()?;
+    is_port_path(port).then(|| port.to_owned())
+}
+
+/// A PCI address, `[domain:]bus:slot.function` in hex as `lspci -D` shows
+/// it, as `0000:01:00.0`.  The domain defaults to `0000`.  `None` unless
+/// every part is in range.
+fn canon_pci_address(address: &str) -> Option<String> {
+    let (rest, function) = address.trim().rsplit_once('.')?;
+    let mut parts: Vec<&str> = rest.split(':').collect();
+    if parts.len() == 2 {
+        parts.insert(0, "0");
+    }
+    let hex = |part: &str, max: u32| {
+        Some(part)
+            .filter(|p| !p.is_empty() && p.len() <= 4)
+            .and_then(|p| u32::from_str_radix(p, 16).ok())
+            .filter(|n| *n <= max)
+    };
+    match parts[..] {
+        [domain, bus, slot] => Some(format!(
+            "{:04x}:{:02x}:{:02x}.{:x}",
+            hex(domain, 0xffff)?,
+            hex(bus, 0xff)?,
+            hex(slot, 0x1f)?,
+            hex(function, 7)?
+        )),
+        _ => None,
+    }
+}
+
+/// Build a fingerprint filter from D-Bus arguments, or `None` if any of the
+/// three strings is empty.
+fn fingerprint_filter(manufacturer: String, product: String, serial: String) -> Option<UsbFilter> {
+    if manufacturer.is_empty() || product.is_empty() || serial.is_empty() {
+        return None;
+    }
+    Some(UsbFilter {
+        manufacturer: Some(manufacturer),
+        product: Some(product),
+        serial: Some(serial),
+        ..Default::default()
+    })
+}
+
+/// The levels `SetLogLevel` takes, as `log` names them.
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+/// Handle one method call from either front end, D-Bus or
+/// `--control-socket`, so that both behave identically.
+///
+/// Arguments are strings as D-Bus sends them, except that `SetMaxPower`'s
+/// milliamps are parsed here.  Returns the reply, or an error for a failed
+/// call.
+fn call(dev: &DbusDevice, method: &str, args: &[String]) -> Result<String, MethodErr> {
+    const NO_FINGERPRINT: &str = "ERROR: manufacturer, product and serial are all required";
+    // An empty serial matches any serial.  An ID that isn't valid is an
+    // error reply rather than a filter that matches any ID; `*` is the way
+    // to ask for that.
+    let vid_pid = |vid: &str, pid: &str, serial: &str| -> Result<UsbFilter, String> {
+        let serial = Some(serial.to_owned()).filter(|s| !s.is_empty());
+        if dev.legacy_match {
+            return Ok(UsbFilter {
+                vid: legacy_id(vid),
+                pid: legacy_id(pid),
+                serial,
+                ..Default::default()
+            });
+        }
+        let vid = canon_id_pattern(vid).ok_or_else(|| {
+            format!(
+                "ERROR: invalid VID '{}', expected 4 hex digits or a wildcard",
+                vid
+            )
+        })?;
+        let pid = canon_id_list(pid).ok_or_else(|| {
+            format!(
+                "ERROR: invalid PID '{}', expected 4 hex digits, a wildcard or a comma-separated list of them",
+                pid
+            )
+        })?;
+        Ok(UsbFilter {
+            vid: Some(vid),
+            pid: Some(pid),
+            serial,
+            ..Default::default()
+        })
+    };
+    match (method, args) {
+        ("Add", [vm, vid, pid, serial]) | ("AddConfirmed", [vm, vid, pid, serial]) => {
+            debug!("Incoming {} call for {}:{}!", method, vid, pid);
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => UsbFilter {
+                    confirm: method == "AddConfirmed",
+                    ..filter
+                },
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::Add {
+                vm: vm.clone(),
+                filter,
+            })?;
+        }
+        // The filters come flattened, as a VID, PID and serial each.
+        ("AddMany", [vm, filters @ ..]) if filters.len() % 3 == 0 => {
+            debug!("Incoming AddMany call for {} filter(s)!", filters.len() / 3);
+            // One bad filter rejects the whole call, so nothing is half added.
+            let mut parsed = Vec::new();
+            for (i, f) in filters.chunks(3).enumerate() {
+                match vid_pid(&f[0], &f[1], &f[2]) {
+                    Ok(filter) => parsed.push(filter),
+                    Err(e) => return Ok(format!("{} (filter {})", e, i + 1)),
                 }
-                DbusCommand::Remove => {
-                    let vm = msg.1;
-                    let filter = msg.2;
-                    if let Some(usb_filters) = filters.get_mut(&vm) {
-                        if usb_filters.contains(&filter) {
-                            println!("udev rem: {:?}:{:?}", filter.vid, filter.pid);
-                            usb_filters.remove(&filter);
-                        }
+            }
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::AddMany {
+                vm: vm.clone(),
+                filters: parsed,
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("Remove", [vm, vid, pid, serial]) => {
+            debug!("Incoming Remove call for {}:{}!", vid, pid);
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::Remove {
+                vm: vm.clone(),
+                filter,
+            })?;
+        }
+        ("AddByFingerprint", [vm, manufacturer, product, serial])
+        | ("RemoveByFingerprint", [vm, manufacturer, product, serial]) => {
+            debug!(
+                "Incoming {} call for {}/{}/{}!",
+                method, manufacturer, product, serial
+            );
+            let filter =
+                match fingerprint_filter(manufacturer.clone(), product.clone(), serial.clone()) {
+                    Some(f) => f,
+                    None => return Ok(NO_FINGERPRINT.into()),
+                };
+            dev.send(Command::add_or_remove(
+                method == "AddByFingerprint",
+                vm,
+                filter,
+            ))?;
+        }
+        ("AddByDriver", [vm, driver]) | ("RemoveByDriver", [vm, driver]) => {
+            debug!("Incoming {} call for {}!", method, driver);
+            if driver.is_empty() || driver.contains([',', '/']) {
+                return Ok("ERROR: driver must be a single kernel driver name".into());
+            }
+            let filter = UsbFilter {
+                driver: Some(driver.clone()),
+                ..Default::default()
+            };
+            dev.send(Command::add_or_remove(method == "AddByDriver", vm, filter))?;
+        }
+        ("AddByPort", [vm, port]) | ("RemoveByPort", [vm, port]) => {
+            debug!("Incoming {} call for {}!", method, port);
+            if !is_port_path(port) {
+                return Ok("ERROR: port must be a kernel port path like 1-4.2".into());
+            }
+            let filter = UsbFilter {
+                port: Some(port.clone()),
+                ..Default::default()
+            };
+            dev.send(Command::add_or_remove(method == "AddByPort", vm, filter))?;
+        }
+        ("AddByHub", [vm, hub]) | ("RemoveByHub", [vm, hub]) => {
+            debug!("Incoming {} call for {}!", method, hub);
+            let hub = match hub_port(hub) {
+                Some(hub) => hub,
+                None => {
+                    return Ok(
+                        "ERROR: hub must be a kernel port path like 1-4, or its syspath".into(),
+                    )
+                }
+            };
+            let filter = UsbFilter {
+                hub: Some(hub),
+                ..Default::default()
+            };
+            dev.send(Command::add_or_remove(method == "AddByHub", vm, filter))?;
+        }
+        ("AddMatching", [vm, vid, pid, manufacturer, product, mode])
+        | ("RemoveMatching", [vm, vid, pid, manufacturer, product, mode]) => {
+            debug!(
+                "Incoming {} call for {}:{} manufacturer '{}' product '{}'!",
+                method, vid, pid, manufacturer, product
+            );
+            let exact_match = match mode.as_str() {
+                "" | "contains" => false,
+                "exact" => true,
+                _ => {
+                    return Ok(format!(
+                        "ERROR: invalid match mode '{}', expected contains or exact",
+                        mode
+                    ))
+                }
+            };
+            if manufacturer.is_empty() && product.is_empty() {
+                return Ok("ERROR: a manufacturer or product is required".into());
+            }
+            // Both IDs empty matches any device with those strings.
+            let filter = match (vid.is_empty(), pid.is_empty()) {
+                (true, true) => UsbFilter::default(),
+                _ => match vid_pid(vid, pid, "") {
+                    Ok(filter) => filter,
+                    Err(e) => return Ok(e),
+                },
+            };
+            let filter = UsbFilter {
+                manufacturer_match: Some(manufacturer.clone()).filter(|m| !m.is_empty()),
+                product_match: Some(product.clone()).filter(|p| !p.is_empty()),
+                exact_match,
+                ..filter
+            };
+            dev.send(Command::add_or_remove(method == "AddMatching", vm, filter))?;
+        }
+        ("AddPci", [vm, vendor, device]) | ("RemovePci", [vm, vendor, device]) => {
+            debug!("Incoming {} call for {}:{}!", method, vendor, device);
+            let filter = match vid_pid(vendor, device, "") {
+                Ok(filter) => UsbFilter {
+                    pci: true,
+                    ..filter
+                },
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::add_or_remove(method == "AddPci", vm, filter))?;
+        }
+        ("AddPciByAddress", [vm, address]) | ("RemovePciByAddress", [vm, address]) => {
+            debug!("Incoming {} call for {}!", method, address);
+            let address = match canon_pci_address(address) {
+                Some(address) => address,
+                None => {
+                    return Ok(format!(
+                        "ERROR: invalid PCI address '{}', expected one like 0000:01:00.0",
+                        address
+                    ))
+                }
+            };
+            let filter = UsbFilter {
+                pci: true,
+                pci_address: Some(address),
+                ..Default::default()
+            };
+            dev.send(Command::add_or_remove(
+                method == "AddPciByAddress",
+                vm,
+                filter,
+            ))?;
+        }
+        ("AddByClass", [vm, vid, pid, class, subclass])
+        | ("RemoveByClass", [vm, vid, pid, class, subclass]) => {
+            debug!(
+                "Incoming {} call for {}:{} class {}/{}!",
+                method, vid, pid, class, subclass
+            );
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            // Class 00 means "see the interfaces", which is never matched.
+            let class = match canon_class(class).filter(|c| c != "00") {
+                Some(class) => class,
+                None => {
+                    return Ok(format!(
+                        "ERROR: invalid class '{}', expected 2 hex digits other than 00",
+                        class
+                    ))
+                }
+            };
+            // An empty subclass matches any subclass.
+            let subclass = match subclass.is_empty() {
+                true => None,
+                _ => match canon_class(subclass) {
+                    Some(subclass) => Some(subclass),
+                    None => {
+                        return Ok(format!(
+                            "ERROR: invalid subclass '{}', expected 2 hex digits",
+                            subclass
+                        ))
                     }
+                },
+            };
+            let filter = UsbFilter {
+                class: Some(class),
+                subclass,
+                ..filter
+            };
+            dev.send(Command::add_or_remove(method == "AddByClass", vm, filter))?;
+        }
+        ("AddWithSpeed", [vm, vid, pid, speed]) | ("RemoveWithSpeed", [vm, vid, pid, speed]) => {
+            debug!(
+                "Incoming {} call for {}:{} at {} speed!",
+                method, vid, pid, speed
+            );
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            let speed = match canon_speed(speed) {
+                Some(speed) => speed,
+                None => {
+                    return Ok(format!(
+                        "ERROR: invalid speed '{}', expected low, full, high, super or super-plus",
+                        speed
+                    ))
                 }
+            };
+            let filter = UsbFilter {
+                speed: Some(speed),
+                ..filter
+            };
+            dev.send(Command::add_or_remove(method == "AddWithSpeed", vm, filter))?;
+        }
+        ("AddWithoutSerial", [vm, vid, pid]) | ("RemoveWithoutSerial", [vm, vid, pid]) => {
+            debug!("Incoming {} call for {}:{}!", method, vid, pid);
+            // An empty serial on the filter itself, as opposed to none,
+            // matches only devices without one.
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => UsbFilter {
+                    serial: Some(String::new()),
+                    ..filter
+                },
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::add_or_remove(
+                method == "AddWithoutSerial",
+                vm,
+                filter,
+            ))?;
+        }
+        ("AddTemplated", [vm_template, vid, pid]) => {
+            debug!(
+                "Incoming AddTemplated call for {} -> {}:{}!",
+                vm_template, vid, pid
+            );
+            if !vm_template.contains('{') {
+                return Ok("ERROR: VM template has no placeholders, use Add".into());
             }
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => UsbFilter {
+                    templated_vm: true,
+                    ..filter
+                },
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::Add {
+                vm: vm_template.clone(),
+                filter,
+            })?;
         }
-
-        for event in &events {
-            if event.token() == Token(0) && event.is_writable() {
-                socket.iter().for_each(|x| {
-                    let syspath = x.device().syspath().to_owned();
-                    let mut vidpath = syspath.clone();
-                    let mut pidpath = syspath.clone();
-                    let mut buspath = syspath.clone();
-                    let mut devpath = syspath.clone();
-                    vidpath.push("idVendor");
-                    pidpath.push("idProduct");
-                    buspath.push("busnum");
-                    devpath.push("devnum");
-                    match x.event_type() {
-                        udev::EventType::Add => {
-                            let mut usb_vid = String::new();
-                            let mut usb_pid = String::new();
-                            let mut usb_bus = String::new();
-                            let mut usb_dev = String::new();
-                            let mut f = std::fs::File::open(vidpath).expect("couldn't open USB vendor");
-                            f.read_to_string(&mut usb_vid).expect("failed to read USB vendor");
-                            let mut f = std::fs::File::open(pidpath).expect("couldn't open USB product");
-                            f.read_to_string(&mut usb_pid).expect("failed to read USB vendor");
-                            let mut f = std::fs::File::open(buspath).expect("couldn't open USB bus");
-                            f.read_to_string(&mut usb_bus).expect("failed to read USB vendor");
-                            let mut f = std::fs::File::open(devpath).expect("couldn't open USB device");
-                            f.read_to_string(&mut usb_dev).expect("failed to read USB vendor");
-                            let usb_vid = usb_vid.trim();
-                            let usb_pid = usb_pid.trim();
-                            let usb_bus = usb_bus.trim();
-                            let usb_dev = usb_dev.trim();
-                            let usb_filter = UsbFilter {vid: Some(usb_vid.into()), pid: Some(usb_pid.into())};
-                            for (vm, vm_filter) in filters.iter() {
-                                if vm_filter.contains(&usb_filter) {
-                                    println!("Adding syspath: {} for vm {} [VID:{} PID:{}]", syspath.display(), vm, usb_vid, usb_pid);
-                                    sysdevs.insert(syspath.clone(), usb_filter.clone());
-                                    if let Ok(domain) = Domain::lookup_by_name(&conn, vm) {
-                                        let xml = usb_xml(usb_vid, usb_pid, usb_bus, usb_dev);
-                                        domain.attach_device(&xml).expect("failed to attach USB XML!");
-                                        if !xmls.contains_key(vm) {
-                                            xmls.insert(vm.to_owned(), Vec::new());
-                                        }
-                                        if let Some(vm_xmls) = xmls.get_mut(vm) {
-                                            vm_xmls.push((syspath.clone(), xml));
-                                        }
-                                    }
-                                }
-                            }
-                        },
-                        udev::EventType::Remove => {
-                            match sysdevs.contains_key(&syspath) {
-                                true => {
-                                    println!("Removing syspath: {}", syspath.display());
-                                    sysdevs.remove(&syspath);
-                                    for (vm, vm_xmls) in xmls.iter_mut() {
-                                        for (vm_syspath, xml_str) in vm_xmls.iter() {
-                                            if vm_syspath == &syspath {
-                                                if let Ok(domain) = Domain::lookup_by_name(&conn, vm) {
-                                                    if let Err(e) = domain.detach_device(xml_str) {
-                                                        println!("WARNING: failed to hot-unplug from domain {}: {}", vm, e);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        vm_xmls.retain(|i| i.0 != syspath);
-                                    }
-                                },
-                                false => {
-                                },
-                            }
-                        },
-                        _ => {},
+        ("RemoveTemplated", [vm_template, vid, pid]) => {
+            debug!(
+                "Incoming RemoveTemplated call for {} -> {}:{}!",
+                vm_template, vid, pid
+            );
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => UsbFilter {
+                    templated_vm: true,
+                    ..filter
+                },
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::Remove {
+                vm: vm_template.clone(),
+                filter,
+            })?;
+        }
+        ("EnableFilter", [vm, vid, pid, serial]) | ("DisableFilter", [vm, vid, pid, serial]) => {
+            debug!("Incoming {} call for {}:{}!", method, vid, pid);
+            let enabled = method == "EnableFilter";
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::SetEnabled {
+                vm: vm.clone(),
+                filter,
+                enabled,
+            })?;
+        }
+        ("SetBootOrder", [vm, vid, pid, serial, order]) => {
+            let order: u32 = order.parse().map_err(|_| MethodErr::invalid_arg(order))?;
+            debug!(
+                "Incoming SetBootOrder call for {}:{} -> {}!",
+                vid, pid, order
+            );
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::SetBootOrder {
+                vm: vm.clone(),
+                filter,
+                order,
+            })?;
+        }
+        ("SetGuestAddress", [vm, vid, pid, serial, bus, port]) => {
+            debug!(
+                "Incoming SetGuestAddress call for {}:{} -> bus '{}' port '{}'!",
+                vid, pid, bus, port
+            );
+            // Empty leaves that part of the address to libvirt.
+            let number = |n: &str| -> Result<Option<u32>, ()> {
+                match n {
+                    "" => Ok(None),
+                    n if n.bytes().all(|b| b.is_ascii_digit()) => {
+                        n.parse().map(Some).map_err(|_| ())
                     }
-                });
+                    _ => Err(()),
+                }
+            };
+            let bus = match number(bus) {
+                Ok(bus) => bus,
+                Err(()) => {
+                    return Ok(format!(
+                        "ERROR: invalid guest bus '{}', expected a controller index",
+                        bus
+                    ))
+                }
+            };
+            let port = match number(port) {
+                Ok(Some(0)) | Err(()) => {
+                    return Ok(format!(
+                        "ERROR: invalid guest port '{}', expected a port from 1",
+                        port
+                    ))
+                }
+                Ok(port) => port,
+            };
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::SetGuestAddress {
+                vm: vm.clone(),
+                filter,
+                bus,
+                port,
+            })?;
+        }
+        ("SetAttachRetries", [vm, vid, pid, serial, retries]) => {
+            debug!(
+                "Incoming SetAttachRetries call for {}:{} -> '{}'!",
+                vid, pid, retries
+            );
+            // Empty goes back to --attach-retries.
+            let retries = match retries.as_str() {
+                "" => None,
+                n if n.bytes().all(|b| b.is_ascii_digit()) => match n.parse() {
+                    Ok(n) => Some(n),
+                    Err(_) => return Ok(format!("ERROR: invalid retries '{}'", retries)),
+                },
+                _ => return Ok(format!("ERROR: invalid retries '{}'", retries)),
+            };
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::SetAttachRetries {
+                vm: vm.clone(),
+                filter,
+                retries,
+            })?;
+        }
+        ("SetAccessMode", [vm, vid, pid, serial, mode]) => {
+            debug!(
+                "Incoming SetAccessMode call for {}:{} -> '{}'!",
+                vid, pid, mode
+            );
+            if let Err(e) = vid_pid(vid, pid, serial) {
+                return Ok(e);
+            }
+            // libvirt only has read-only SCSI hostdevs; USB and PCI devices
+            // are handed to the guest whole, so read-write is all there is.
+            match mode.as_str() {
+                "" | "read-write" => {}
+                "read-only" => {
+                    warn!(
+                        "read-only access asked for {}:{} on vm {}, but USB and PCI hostdevs can't be read-only",
+                        vid, pid, vm
+                    );
+                    return Ok(
+                        "ERROR: read-only access isn't supported for USB or PCI hostdevs".into(),
+                    );
+                }
+                _ => {
+                    return Ok(format!(
+                        "ERROR: invalid mode '{}', expected 'read-write' or 'read-only'",
+                        mode
+                    ))
+                }
             }
         }
+        ("Move", [vid, pid, from_vm, to_vm]) => {
+            debug!(
+                "Incoming Move call for {}:{} from {} to {}!",
+                vid, pid, from_vm, to_vm
+            );
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::Move {
+                from: from_vm.clone(),
+                to: to_vm.clone(),
+                filter,
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("SetTemplate", [template]) => {
+            debug!("Incoming SetTemplate call!");
+            if let Err(e) = check_balanced_tags(template) {
+                return Ok(format!("ERROR: invalid template: {}", e));
+            }
+            dev.send(Command::SetTemplate(template.clone()))?;
+        }
+        ("SetFilterTemplate", [vm, vid, pid, serial, template]) => {
+            debug!("Incoming SetFilterTemplate call for {}:{}!", vid, pid);
+            if let Err(e) = check_balanced_tags(template) {
+                return Ok(format!("ERROR: invalid template: {}", e));
+            }
+            let filter = match vid_pid(vid, pid, serial) {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            dev.send(Command::SetFilterTemplate {
+                vm: vm.clone(),
+                filter,
+                template: Some(template.clone()).filter(|t| !t.is_empty()),
+            })?;
+        }
+        ("SetMaxPower", [milliamps]) => {
+            let milliamps: u32 = milliamps
+                .parse()
+                .map_err(|_| MethodErr::invalid_arg(milliamps))?;
+            debug!("Incoming SetMaxPower call for {}mA!", milliamps);
+            dev.send(Command::SetMaxPower(milliamps))?;
+        }
+        ("SetStartupPolicy", [policy]) => {
+            debug!("Incoming SetStartupPolicy call for '{}'!", policy);
+            // An empty policy leaves the attribute out again.
+            let policy = match policy.as_str() {
+                "" => None,
+                p if STARTUP_POLICIES.contains(&p) => Some(p.to_owned()),
+                _ => {
+                    return Ok(format!(
+                        "ERROR: startup policy must be one of {}",
+                        STARTUP_POLICIES.join(", ")
+                    ))
+                }
+            };
+            dev.send(Command::SetStartupPolicy(policy))?;
+        }
+        ("DetachPath", [syspath]) => {
+            debug!("Incoming DetachPath call for {}!", syspath);
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::DetachPath {
+                syspath: PathBuf::from(syspath),
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("Detach", [vm, vid, pid, filters]) => {
+            debug!("Incoming Detach call for {}:{} from {}!", vid, pid, vm);
+            let filter = match vid_pid(vid, pid, "") {
+                Ok(filter) => filter,
+                Err(e) => return Ok(e),
+            };
+            let remove_filters = match filters.as_str() {
+                "" | "keep" => false,
+                "remove" => true,
+                _ => return Ok("ERROR: filters must be 'keep' or 'remove'".into()),
+            };
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::Detach {
+                vm: vm.clone(),
+                filter,
+                remove_filters,
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("DetachAll", [vm]) => {
+            debug!("Incoming DetachAll call for {}!", vm);
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::DetachAll {
+                vm: vm.clone(),
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("Reattach", [_]) | ("ReattachAll", []) => {
+            debug!("Incoming {} call!", method);
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::Reattach {
+                vm: args.first().cloned(),
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("GetConfig", []) => {
+            debug!("Incoming GetConfig call!");
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::GetConfig(reply_tx))?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("ReloadFilters", []) => {
+            debug!("Incoming ReloadFilters call!");
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::ReloadFilters(reply_tx))?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("LastError", [vm]) => {
+            debug!("Incoming LastError call for '{}'!", vm);
+            let (reply_tx, reply_rx) = channel::<String>();
+            dev.send(Command::LastError {
+                vm: vm.clone(),
+                reply: reply_tx,
+            })?;
+            return Ok(reply_rx
+                .recv_timeout(Duration::from_secs(10))
+                .unwrap_or_else(|_| "ERROR: no reply from event loop".into()));
+        }
+        ("List", []) | ("Attached", []) | ("Status", []) => {
+            debug!("Incoming {} call!", method);
+            if dev.shutting_down.load(Ordering::SeqCst) {
+                return Err(MethodErr::failed("shutting down"));
+            }
+            let snapshot = dev.snapshot.read().unwrap_or_else(PoisonError::into_inner);
+            return Ok(match method {
+                "List" => filter_list(&snapshot),
+                "Attached" => attached_list(&snapshot),
+                _ => status_report(&snapshot),
+            });
+        }
+        ("GetLogLevel", []) => {
+            debug!("Incoming GetLogLevel call!");
+            return Ok(log::max_level().to_string().to_lowercase());
+        }
+        ("SetLogLevel", [level]) => {
+            // Logging is process-wide, so this needn't wait for the loop.
+            let level = match LevelFilter::from_str(level.trim()) {
+                Ok(level) => level,
+                Err(_) => {
+                    return Ok(format!(
+                        "ERROR: invalid log level '{}', expected one of {}",
+                        level,
+                        LOG_LEVELS.join(", ")
+                    ))
+                }
+            };
+            info!("Log level set to {}", level.as_str().to_lowercase());
+            log::set_max_level(level);
+        }
+        ("Drain", []) => {
+            debug!("Incoming Drain call!");
+            dev.send(Command::Drain)?;
+        }
+        ("Quit", []) => {
+            dev.send(Command::Shutdown)?;
+            // Each front end dispatches calls one at a time, so every call
+            // after this one is rejected rather than racing the teardown.
+            dev.shutting_down.store(true, Ordering::SeqCst);
+            return Ok("BYE".into());
+        }
+        _ => {
+            return Err(MethodErr::failed(&format!(
+                "unknown method {} or wrong number of arguments",
+                method
+            )))
+        }
     }
-
-    println!("Shutting down by request.");
-    if let Err(e) = conn.close() {
-        panic!("Failed to disconnect from hypervisor: {}", e);
-    }
-    Ok(())
+    Ok("OK".into())
 }
 
-fn main() {
-    println!("Starting qemu-stormcrow.");
+/// A `DeviceAttached`, `DeviceDetached` or `UnmatchedDevice` signal, sent
+/// from the `poll` loop to the D-Bus thread, which owns the connection.
+pub struct DeviceSignal {
+    name: &'static str,
+    args: [String; 4],
+}
 
-    println!("Starting dbus monitor...");
-    let (sender, receiver) = channel::<(DbusCommand, String, UsbFilter)>();
-    thread::spawn(move || {
-        dbus_server(sender).expect("failed to launch dbus server");
-    });
+impl DeviceSignal {
+    fn new(name: &'static str, vm: &str, vid: &str, pid: &str, syspath: &Path) -> DeviceSignal {
+        DeviceSignal {
+            name,
+            args: [
+                vm.to_owned(),
+                vid.to_owned(),
+                pid.to_owned(),
+                syspath.to_string_lossy().into_owned(),
+            ],
+        }
+    }
 
-    println!("Making udev monitor...");
-    let socket = MonitorBuilder::new()
-        .expect("failed to create new udev monitor")
-        .match_subsystem_devtype("usb", "usb_device")
-        .expect("failed to create usb matcher")
-        .listen()
-        .expect("failed to register udev monitor");
+    fn unmatched(vid: &str, pid: &str, serial: &str, syspath: &Path) -> DeviceSignal {
+        DeviceSignal {
+            name: "UnmatchedDevice",
+            args: [
+                vid.to_owned(),
+                pid.to_owned(),
+                serial.to_owned(),
+                syspath.to_string_lossy().into_owned(),
+            ],
+        }
+    }
+}
 
-    poll(socket, receiver).expect("failed to poll udev monitor");
-    println!("Done!");
+// $ dbus-send --type=method_call --print-reply --dest=com.stormcrow.device /device com.stormcrow.device.Add string:<VM> string:<VID> string:<PID> string:<SERIAL>
+//
+// Returns an error straight away if the well-known name can't be owned, e.g.
+// because another instance has it and `replace` isn't set.  `ready` is sent
+// on once it is owned.
+fn dbus_server(
+    dev: DbusDevice,
+    signals: Receiver<DeviceSignal>,
+    ready: Sender<()>,
+    system_bus: bool,
+    name: String,
+    replace: bool,
+) -> Result<(), Box<dyn Error>> {
+    let c = if system_bus {
+        DbusConnection::new_system()?
+    } else {
+        DbusConnection::new_session()?
+    };
+    // Allow a later `--replace` instance to take over, but never queue for
+    // the name: an instance that doesn't own it would serve nothing.
+    match c.request_name(name.as_str(), true, replace, true)? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => {}
+        _ => {
+            return Err(format!(
+                "{} is already owned by another instance; stop it or launch with --replace",
+                name
+            )
+            .into())
+        }
+    }
+    let _ = ready.send(());
+    let mut cr = Crossroads::new();
+    let iface_token = cr.register("com.stormcrow.device", |b| {
+        for signal in ["DeviceAttached", "DeviceDetached"] {
+            b.signal::<(String, String, String, String), _>(
+                signal,
+                ("vm", "vid", "pid", "syspath"),
+            );
+        }
+        b.signal::<(String, String, String, String), _>(
+            "UnmatchedDevice",
+            ("vid", "pid", "serial", "syspath"),
+        );
+        for method in [
+            "Add",
+            "AddConfirmed",
+            "Remove",
+            "EnableFilter",
+            "DisableFilter",
+        ] {
+            b.method(
+                method,
+                ("vm", "vid", "pid", "serial"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vid, pid, serial): (String, String, String, String)| {
+                    Ok((call(dev, method, &[vm, vid, pid, serial])?,))
+                },
+            );
+        }
+        b.method(
+            "AddMany",
+            ("vm", "filters"),
+            ("reply",),
+            |_ctx: &mut Context,
+             dev: &mut DbusDevice,
+             (vm, filters): (String, Vec<(String, String, String)>)| {
+                let mut args = vec![vm];
+                for (vid, pid, serial) in filters {
+                    args.extend([vid, pid, serial]);
+                }
+                Ok((call(dev, "AddMany", &args)?,))
+            },
+        );
+        for method in ["AddByFingerprint", "RemoveByFingerprint"] {
+            b.method(
+                method,
+                ("vm", "manufacturer", "product", "serial"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, manufacturer, product, serial): (String, String, String, String)| {
+                    Ok((call(dev, method, &[vm, manufacturer, product, serial])?,))
+                },
+            );
+        }
+        for method in ["AddByDriver", "RemoveByDriver"] {
+            b.method(
+                method,
+                ("vm", "driver"),
+                ("reply",),
+                move |_ctx: &mut Context, dev: &mut DbusDevice, (vm, driver): (String, String)| {
+                    Ok((call(dev, method, &[vm, driver])?,))
+                },
+            );
+        }
+        for method in ["AddByPort", "RemoveByPort"] {
+            b.method(
+                method,
+                ("vm", "port"),
+                ("reply",),
+                move |_ctx: &mut Context, dev: &mut DbusDevice, (vm, port): (String, String)| {
+                    Ok((call(dev, method, &[vm, port])?,))
+                },
+            );
+        }
+        for method in ["AddByHub", "RemoveByHub"] {
+            b.method(
+                method,
+                ("vm", "hub"),
+                ("reply",),
+                move |_ctx: &mut Context, dev: &mut DbusDevice, (vm, hub): (String, String)| {
+                    Ok((call(dev, method, &[vm, hub])?,))
+                },
+            );
+        }
+        for method in ["AddMatching", "RemoveMatching"] {
+            b.method(
+                method,
+                ("vm", "vid", "pid", "manufacturer", "product", "mode"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vid, pid, manufacturer, product, mode): (
+                    String,
+                    String,
+                    String,
+                    String,
+                    String,
+                    String,
+                )| {
+                    Ok((call(
+                        dev,
+                        method,
+                        &[vm, vid, pid, manufacturer, product, mode],
+                    )?,))
+                },
+            );
+        }
+        for method in ["AddPci", "RemovePci"] {
+            b.method(
+                method,
+                ("vm", "vendor", "device"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vendor, device): (String, String, String)| {
+                    Ok((call(dev, method, &[vm, vendor, device])?,))
+                },
+            );
+        }
+        for method in ["AddPciByAddress", "RemovePciByAddress"] {
+            b.method(
+                method,
+                ("vm", "address"),
+                ("reply",),
+                move |_ctx: &mut Context, dev: &mut DbusDevice, (vm, address): (String, String)| {
+                    Ok((call(dev, method, &[vm, address])?,))
+                },
+            );
+        }
+        for method in ["AddByClass", "RemoveByClass"] {
+            b.method(
+                method,
+                ("vm", "vid", "pid", "class", "subclass"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vid, pid, class, subclass): (String, String, String, String, String)| {
+                    Ok((call(dev, method, &[vm, vid, pid, class, subclass])?,))
+                },
+            );
+        }
+        for method in ["AddWithSpeed", "RemoveWithSpeed"] {
+            b.method(
+                method,
+                ("vm", "vid", "pid", "speed"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vid, pid, speed): (String, String, String, String)| {
+                    Ok((call(dev, method, &[vm, vid, pid, speed])?,))
+                },
+            );
+        }
+        for method in ["AddWithoutSerial", "RemoveWithoutSerial"] {
+            b.method(
+                method,
+                ("vm", "vid", "pid"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm, vid, pid): (String, String, String)| {
+                    Ok((call(dev, method, &[vm, vid, pid])?,))
+                },
+            );
+        }
+        for method in ["AddTemplated", "RemoveTemplated"] {
+            b.method(
+                method,
+                ("vm_template", "vid", "pid"),
+                ("reply",),
+                move |_ctx: &mut Context,
+                      dev: &mut DbusDevice,
+                      (vm_template, vid, pid): (String, String, String)| {
+                    Ok((call(dev, method, &[vm_template, vid, pid])?,))
+                },
+            );
+        }
+        b.method(
+            "SetBootOrder",
+            ("vm", "vid", "pid", "serial", "order"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, serial, order): (String, String, String, String, u32)| {
+                Ok((call(dev, "SetBootOrder", &[vm, vid, pid, serial, order.to_string()])?,))
+            },
+        );
+        b.method(
+            "SetGuestAddress",
+            ("vm", "vid", "pid", "serial", "bus", "port"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, serial, bus, port): (
+                String,
+                String,
+                String,
+                String,
+                String,
+                String,
+            )| {
+                Ok((call(
+                    dev,
+                    "SetGuestAddress",
+                    &[vm, vid, pid, serial, bus, port],
+                )?,))
+            },
+        );
+        b.method(
+            "SetFilterTemplate",
+            ("vm", "vid", "pid", "serial", "template"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, serial, template): (String, String, String, String, String)| {
+                Ok((call(
+                    dev,
+                    "SetFilterTemplate",
+                    &[vm, vid, pid, serial, template],
+                )?,))
+            },
+        );
+        b.method(
+            "SetAttachRetries",
+            ("vm", "vid", "pid", "serial", "retries"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, serial, retries): (String, String, String, String, String)| {
+                Ok((call(
+                    dev,
+                    "SetAttachRetries",
+                    &[vm, vid, pid, serial, retries],
+                )?,))
+            },
+        );
+        b.method(
+            "SetAccessMode",
+            ("vm", "vid", "pid", "serial", "mode"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, serial, mode): (String, String, String, String, String)| {
+                Ok((call(dev, "SetAccessMode", &[vm, vid, pid, serial, mode])?,))
+            },
+        );
+        b.method(
+            "Move",
+            ("vid", "pid", "from_vm", "to_vm"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vid, pid, from_vm, to_vm): (String, String, String, String)| {
+                Ok((call(dev, "Move", &[vid, pid, from_vm, to_vm])?,))
+            },
+        );
+        b.method(
+            "SetTemplate",
+            ("template",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (template,): (String,)| {
+                Ok((call(dev, "SetTemplate", &[template])?,))
+            },
+        );
+        b.method(
+            "SetMaxPower",
+            ("milliamps",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (milliamps,): (u32,)| {
+                Ok((call(dev, "SetMaxPower", &[milliamps.to_string()])?,))
+            },
+        );
+        b.method(
+            "SetStartupPolicy",
+            ("policy",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (policy,): (String,)| {
+                Ok((call(dev, "SetStartupPolicy", &[policy])?,))
+            },
+        );
+        b.method(
+            "DetachPath",
+            ("syspath",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (syspath,): (String,)| {
+                Ok((call(dev, "DetachPath", &[syspath])?,))
+            },
+        );
+        b.method(
+            "Detach",
+            ("vm", "vid", "pid", "filters"),
+            ("reply",),
+            move |_ctx: &mut Context,
+                  dev: &mut DbusDevice,
+                  (vm, vid, pid, filters): (String, String, String, String)| {
+                Ok((call(dev, "Detach", &[vm, vid, pid, filters])?,))
+            },
+        );
+        b.method(
+            "DetachAll",
+            ("vm",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (vm,): (String,)| {
+                Ok((call(dev, "DetachAll", &[vm])?,))
+            },
+        );
+        b.method(
+            "Reattach",
+            ("vm",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (vm,): (String,)| {
+                Ok((call(dev, "Reattach", &[vm])?,))
+            },
+        );
+        b.method(
+            "ReattachAll",
+            (),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "ReattachAll", &[])?,))
+            },
+        );
+        b.method(
+            "GetConfig",
+            (),
+            ("config",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "GetConfig", &[])?,))
+            },
+        );
+        b.method(
+            "ReloadFilters",
+            (),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "ReloadFilters", &[])?,))
+            },
+        );
+        b.method(
+            "GetLogLevel",
+            (),
+            ("level",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "GetLogLevel", &[])?,))
+            },
+        );
+        b.method(
+            "SetLogLevel",
+            ("level",),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (level,): (String,)| {
+                Ok((call(dev, "SetLogLevel", &[level])?,))
+            },
+        );
+        b.method(
+            "LastError",
+            ("vm",),
+            ("error",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (vm,): (String,)| {
+                Ok((call(dev, "LastError", &[vm])?,))
+            },
+        );
+        b.method(
+            "List",
+            (),
+            ("filters",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| Ok((call(dev, "List", &[])?,)),
+        );
+        b.method(
+            "Attached",
+            (),
+            ("devices",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "Attached", &[])?,))
+            },
+        );
+        b.method(
+            "Status",
+            (),
+            ("status",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| {
+                Ok((call(dev, "Status", &[])?,))
+            },
+        );
+        b.method(
+            "Drain",
+            (),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| Ok((call(dev, "Drain", &[])?,)),
+        );
+        b.method(
+            "Quit",
+            (),
+            ("reply",),
+            move |_ctx: &mut Context, dev: &mut DbusDevice, (): ()| Ok((call(dev, "Quit", &[])?,)),
+        );
+    });
+
+    let sender = dev.sender.clone();
+    cr.insert("/device", &[iface_token], dev);
+
+    // Taken over by a `--replace` instance: nothing reaches us any more.
+    c.start_receive(
+        MatchRule::new_signal("org.freedesktop.DBus", "NameLost"),
+        Box::new(move |msg, _conn| {
+            if msg.read1::<&str>().ok() == Some(name.as_str()) {
+                error!(
+                    "Lost D-Bus name {} to another instance, shutting down.",
+                    name
+                );
+                let _ = sender.send(Command::Shutdown);
+            }
+            true
+        }),
+    );
+
+    // As `Crossroads::serve`, but emitting signals from `poll` in between.
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let _ = cr.handle_message(msg, conn);
+            true
+        }),
+    );
+    loop {
+        c.process(Duration::from_millis(200))?;
+        while let Ok(signal) = signals.try_recv() {
+            let mut msg = Message::new_signal("/device", "com.stormcrow.device", signal.name)?;
+            let [first, second, third, syspath] = signal.args;
+            msg.append_all((first, second, third, syspath));
+            if c.send(msg).is_err() {
+                warn!("failed to emit {} signal", signal.name);
+            }
+        }
+    }
+}
+
+/// The sysfs attributes of a `usb_device`, or of a PCI device, that
+/// matching and XML generation use, read once per event so they can be
+/// recorded.
+const SYSFS_ATTRS: &[&str] = &[
+    "idVendor",
+    "idProduct",
+    "busnum",
+    "devnum",
+    "manufacturer",
+    "product",
+    "serial",
+    "bMaxPower",
+    "bDeviceClass",
+    "bDeviceSubClass",
+    // Mbps, as `480` or `5000`.
+    "speed",
+    // PCI vendor and device IDs, as `0x10de`.
+    "vendor",
+    "device",
+];
+
+/// Sysfs attribute values by name, plus `drivers` from
+/// `read_interface_drivers`, and `subsystem` (`pci`) for a PCI device.
+/// Only the trailing newline is dropped, so that `SerialNormalization`
+/// decides about other whitespace in serials.
+type SysfsAttrs = BTreeMap<String, String>;
+
+/// Read every attribute in `SYSFS_ATTRS` that the device at `syspath` has.
+fn read_sysfs_attrs(syspath: &Path) -> SysfsAttrs {
+    let mut attrs: SysfsAttrs = SYSFS_ATTRS
+        .iter()
+        .filter_map(|attr| {
+            let value = std::fs::read_to_string(syspath.join(attr)).ok()?;
+            Some((attr.to_string(), value.trim_end_matches('\n').to_owned()))
+        })
+        .collect();
+    if let Some(drivers) = read_interface_drivers(syspath) {
+        attrs.insert("drivers".to_owned(), drivers);
+    }
+    let subsystem = std::fs::read_link(syspath.join("subsystem")).ok();
+    if subsystem.as_deref().and_then(Path::file_name) == Some(OsStr::new("pci")) {
+        attrs.insert("subsystem".to_owned(), "pci".to_owned());
+    }
+    attrs
+}
+
+/// Drivers bound to the interfaces of the `usb_device` at `syspath`, from
+/// each interface's `driver` symlink, sorted and comma-separated.  `None`
+/// if no interface has a driver.
+fn read_interface_drivers(syspath: &Path) -> Option<String> {
+    let sysname = syspath.file_name()?.to_str()?;
+    let interface = format!("{}:", sysname);
+    let mut drivers: Vec<String> = std::fs::read_dir(syspath)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .is_some_and(|n| n.starts_with(&interface))
+        })
+        .filter_map(|entry| std::fs::read_link(entry.path().join("driver")).ok())
+        .filter_map(|link| link.file_name().and_then(|n| n.to_str()).map(str::to_owned))
+        .collect();
+    drivers.sort();
+    drivers.dedup();
+    Some(drivers.join(",")).filter(|d| !d.is_empty())
+}
+
+/// An attribute from `attrs`, trimmed.
+fn sysfs_attr<'a>(attrs: &'a SysfsAttrs, attr: &str) -> Option<&'a str> {
+    attrs.get(attr).map(|v| v.trim())
+}
+
+/// The bus and device numbers that locate a device for libvirt, from
+/// `attrs`.  Every `usb_device` should have them, but some hubs and virtual
+/// devices don't, or go away before they can be read.  Anything but a
+/// decimal number is refused rather than put into XML.
+fn bus_address(attrs: &SysfsAttrs) -> io::Result<(&str, &str)> {
+    let read = |attr| {
+        let value = sysfs_attr(attrs, attr)
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no {} attribute", attr))
+            })?;
+        match value.chars().all(|c| c.is_ascii_digit()) {
+            true => Ok(value),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} '{}' isn't a decimal number", attr, value),
+            )),
+        }
+    };
+    Ok((read("busnum")?, read("devnum")?))
+}
+
+/// The VM name that stands for whichever VM is running when a device
+/// appears.
+const WILDCARD_VM: &str = "*";
+
+/// The VM a device matching a wildcard filter goes to: the one it is
+/// attached to already, if that is running, else the first running VM by
+/// name.  Only ever one, as a device can be passed through to one guest.
+fn wildcard_vm(conn: &dyn Hypervisor, state: &State, syspath: &Path) -> Option<String> {
+    let mut running: Vec<(String, String)> = match conn.running() {
+        Ok(guests) => guests
+            .iter()
+            .filter_map(|g| Some((g.name().ok()?, g.uuid().ok()?)))
+            .collect(),
+        Err(e) => {
+            warn!("can't list running VMs for a wildcard filter: {}", e);
+            return None;
+        }
+    };
+    running.sort();
+    let owner = state.sysdevs.get(syspath).map(|d| d.vm.as_str());
+    running
+        .iter()
+        .find(|(name, uuid)| owner == Some(name) || owner == Some(uuid))
+        .or(running.first())
+        .map(|(_, uuid)| uuid.clone())
+}
+
+/// Resolve a templated VM name, such as `vm-{serial_last4}`, for a device.
+///
+/// Supports `{vid}`, `{pid}`, `{bus}`, `{dev}`, `{serial}` and
+/// `{serial_last4}`.  Returns `None` if the template needs a serial the
+/// device doesn't report, or contains an unknown placeholder.
+fn resolve_vm_template(template: &str, device: &UsbFilter, bus: &str, dev: &str) -> Option<String> {
+    let serial = match device.serial.as_deref() {
+        Some(s) => s,
+        None if template.contains("{serial") => return None,
+        None => "",
+    };
+    let serial_last4: String = serial
+        .chars()
+        .skip(serial.chars().count().saturating_sub(4))
+        .collect();
+    let name = template
+        .replace("{vid}", device.vid.as_deref().unwrap_or(""))
+        .replace("{pid}", device.pid.as_deref().unwrap_or(""))
+        .replace("{bus}", bus)
+        .replace("{dev}", dev)
+        .replace("{serial_last4}", &serial_last4)
+        .replace("{serial}", serial);
+    if name.contains('{') || name.contains('}') {
+        return None;
+    }
+    Some(name)
+}
+
+/// How serial numbers are normalised before comparison, applied to both
+/// registered filters and observed devices.
+#[derive(Clone, Copy, Debug)]
+pub struct SerialNormalization {
+    /// Strip surrounding whitespace.
+    trim: bool,
+    /// Compare case-insensitively, by uppercasing.
+    upper: bool,
+    /// Strip leading zeros (an all-zero serial becomes `0`).
+    strip_zeros: bool,
+}
+
+impl Default for SerialNormalization {
+    /// Trim only, which is what sysfs reads have always done.
+    fn default() -> Self {
+        SerialNormalization {
+            trim: true,
+            upper: false,
+            strip_zeros: false,
+        }
+    }
+}
+
+impl SerialNormalization {
+    /// Parse a comma-separated list of `trim`, `upper` and `strip-zeros`,
+    /// or `none`.
+    fn parse(spec: &str) -> Option<SerialNormalization> {
+        let mut norm = SerialNormalization {
+            trim: false,
+            upper: false,
+            strip_zeros: false,
+        };
+        for step in spec.split(',') {
+            match step.trim() {
+                "none" => {}
+                "trim" => norm.trim = true,
+                "upper" => norm.upper = true,
+                "strip-zeros" => norm.strip_zeros = true,
+                _ => return None,
+            }
+        }
+        Some(norm)
+    }
+
+    fn apply(&self, serial: &str) -> String {
+        let mut serial = match self.trim {
+            true => serial.trim().to_owned(),
+            _ => serial.to_owned(),
+        };
+        if self.upper {
+            serial = serial.to_uppercase();
+        }
+        if self.strip_zeros && serial.starts_with('0') {
+            serial = match serial.trim_start_matches('0') {
+                "" => "0".to_owned(),
+                rest => rest.to_owned(),
+            };
+        }
+        serial
+    }
+}
+
+/// A daily window during which attaches are suppressed, in minutes since
+/// midnight UTC.  An `end` before `start` wraps past midnight.
+#[derive(Clone, Copy, Debug)]
+pub struct SuppressWindow {
+    start: u32,
+    end: u32,
+}
+
+impl SuppressWindow {
+    /// Parse `HH:MM-HH:MM`, e.g. `23:30-05:00`.
+    fn parse(spec: &str) -> Option<SuppressWindow> {
+        let minutes = |hhmm: &str| {
+            let (h, m) = hhmm.trim().split_once(':')?;
+            let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+            Some(h * 60 + m).filter(|_| h < 24 && m < 60)
+        };
+        let (start, end) = spec.split_once('-')?;
+        let window = SuppressWindow {
+            start: minutes(start)?,
+            end: minutes(end)?,
+        };
+        Some(window).filter(|w| w.start != w.end)
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        match self.start < self.end {
+            true => self.start <= minute && minute < self.end,
+            _ => minute >= self.start || minute < self.end,
+        }
+    }
+}
+
+impl fmt::Display for SuppressWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// The current time of day, in minutes since midnight UTC.
+fn utc_minute_of_day() -> u32 {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    (secs / 60 % (24 * 60)) as u32
+}
+
+impl fmt::Display for SerialNormalization {
+    /// The steps in `parse` syntax.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let steps = [
+            (self.trim, "trim"),
+            (self.upper, "upper"),
+            (self.strip_zeros, "strip-zeros"),
+        ];
+        let steps: Vec<&str> = steps
+            .iter()
+            .filter(|(on, _)| *on)
+            .map(|(_, name)| *name)
+            .collect();
+        match steps.is_empty() {
+            true => write!(f, "none"),
+            _ => write!(f, "{}", steps.join(",")),
+        }
+    }
+}
+
+/// Maximum current the device's active configuration may draw, in mA.
+///
+/// Read from the `bMaxPower` sysfs attribute (e.g. `500mA`).  `None` if the
+/// attribute is absent or unparseable, in which case no limit is applied.
+fn max_power_ma(attrs: &SysfsAttrs) -> Option<u32> {
+    sysfs_attr(attrs, "bMaxPower")?
+        .trim_end_matches("mA")
+        .parse()
+        .ok()
+}
+
+/// Built-in hostdev template, used when no custom template is set.
+const USB_XML_TEMPLATE: &str = r"
+<hostdev mode='subsystem' type='usb'>
+  <source>
+    <vendor id='0x{vid}'/>
+    <product id='0x{pid}'/>
+    <address bus='{bus}' device='{dev}'/>
+  </source>
+</hostdev>
+";
+
+/// Hostdev template for devices without a VID/PID, which libvirt can only
+/// find by bus address.  Custom templates are not used for these.
+const USB_ADDRESS_XML_TEMPLATE: &str = r"
+<hostdev mode='subsystem' type='usb'>
+  <source>
+    <address bus='{bus}' device='{dev}'/>
+  </source>
+</hostdev>
+";
+
+/// Hostdev XML for the PCI device at `address`, as `canon_pci_address`.
+/// libvirt manages it: the device is unbound from its host driver for the
+/// attach, and bound back after the detach.
+fn pci_xml(address: &str) -> String {
+    // The address is canonical, so each part is plain hex.
+    let (domain, rest) = address.split_once(':').unwrap_or(("0000", address));
+    let (bus, rest) = rest.split_once(':').unwrap_or(("00", rest));
+    let (slot, function) = rest.split_once('.').unwrap_or((rest, "0"));
+    format!(
+        "
+<hostdev mode='subsystem' type='pci' managed='yes'>
+  <source>
+    <address domain='0x{}' bus='0x{}' slot='0x{}' function='0x{}'/>
+  </source>
+</hostdev>
+",
+        xml_escape(domain),
+        xml_escape(bus),
+        xml_escape(slot),
+        xml_escape(function)
+    )
+}
+
+/// Check that every element opened in `xml` is closed in order.
+///
+/// This is not a full XML parser, just enough to catch a truncated or
+/// mistyped custom template before it is handed to libvirt.
+fn check_balanced_tags(xml: &str) -> Result<(), String> {
+    let mut open = Vec::<&str>::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(e) => start + e,
+            None => return Err("unterminated tag".into()),
+        };
+        let tag = &rest[start + 1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with('?') || tag.starts_with('!') || tag.ends_with('/') {
+            continue;
+        }
+        if let Some(closing) = tag.strip_prefix('/') {
+            let closing = closing.trim();
+            match open.pop() {
+                Some(o) if o == closing => {}
+                Some(o) => return Err(format!("</{}> closes <{}>", closing, o)),
+                None => return Err(format!("</{}> has no opening tag", closing)),
+            }
+        } else {
+            open.push(tag.split_whitespace().next().unwrap_or(""));
+        }
+    }
+    match open.pop() {
+        Some(o) => Err(format!("<{}> is never closed", o)),
+        None => Ok(()),
+    }
+}
+
+/// Fill in a hostdev XML template.
+///
+/// `template` defaults to [`USB_XML_TEMPLATE`].  The placeholders `{vid}`,
+/// `{pid}`, `{bus}`, `{dev}` and `{serial}` are replaced with the device's
+/// values, escaped for XML; `{serial}` is empty for devices without one.
+/// Callers pass the IDs through `canon_id` and check the address is
+/// decimal first (see `bus_address`), so only a serial should need
+/// escaping.
+fn usb_xml(
+    template: Option<&str>,
+    vid: &str,
+    pid: &str,
+    bus: &str,
+    dev: &str,
+    serial: &str,
+) -> String {
+    template
+        .unwrap_or(USB_XML_TEMPLATE)
+        .replace("{vid}", &xml_escape(vid))
+        .replace("{pid}", &xml_escape(pid))
+        .replace("{bus}", &xml_escape(bus))
+        .replace("{dev}", &xml_escape(dev))
+        .replace("{serial}", &xml_escape(serial))
+}
+
+/// `s` with the characters that are special in XML text and attributes
+/// replaced by entities.
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '\'' => out.push_str("&apos;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The values libvirt accepts for a hostdev's `startupPolicy`.
+const STARTUP_POLICIES: &[&str] = &["mandatory", "requisite", "optional"];
+
+/// Add `startupPolicy='POLICY'` to the `<source>` element of hostdev XML,
+/// so that, with `optional`, the VM still boots when the device is absent.
+/// libvirt accepts the attribute on USB hostdev sources since 1.0.0; older
+/// versions reject the XML.
+///
+/// Returns `None` if the XML already sets a policy, e.g. from a custom
+/// template, or has no `<source` element.
+fn with_startup_policy(xml: &str, policy: &str) -> Option<String> {
+    if xml.contains("startupPolicy=") {
+        return None;
+    }
+    let at = xml.find("<source")? + "<source".len();
+    Some(format!(
+        "{} startupPolicy='{}'{}",
+        &xml[..at],
+        policy,
+        &xml[at..]
+    ))
+}
+
+/// Add `<boot order='N'/>` to hostdev XML, just before `</hostdev>`.
+///
+/// Returns `None` if the XML already has a `<boot>` element, e.g. from a
+/// custom template, or has no closing `</hostdev>`.
+fn with_boot_order(xml: &str, order: u32) -> Option<String> {
+    if xml.contains("<boot ") {
+        return None;
+    }
+    let end = xml.rfind("</hostdev>")?;
+    Some(format!(
+        "{}  <boot order='{}'/>\n{}",
+        &xml[..end],
+        order,
+        &xml[end..]
+    ))
+}
+
+/// Add the host-side `<address bus='B' device='D'/>` to the `<source>` of
+/// hostdev XML, for a custom template that leaves it out.  libvirt only
+/// tells identical devices apart by address: without it, the attach of a
+/// second one with the same VID/PID is refused, and a detach may take
+/// whichever of them libvirt finds first.
+///
+/// Returns `None` if the source already has an address, or there is no
+/// `</source>`.
+fn with_host_address(xml: &str, bus: &str, dev: &str) -> Option<String> {
+    let start = xml.find("<source")?;
+    let end = start + xml[start..].find("</source>")?;
+    if xml[start..end].contains("<address") {
+        return None;
+    }
+    Some(format!(
+        "{}<address bus='{}' device='{}'/>{}",
+        &xml[..end],
+        xml_escape(bus),
+        xml_escape(dev),
+        &xml[end..]
+    ))
+}
+
+/// Add a guest-side `<address type='usb' bus='B' port='P'/>` to hostdev
+/// XML, just before `</hostdev>`, so the device lands on that controller
+/// (and port) of the guest.  The port is left out if `None`.
+///
+/// Returns `None` if the XML already has a guest USB address, e.g. from a
+/// custom template, or has no closing `</hostdev>`.
+fn with_guest_address(xml: &str, bus: u32, port: Option<u32>) -> Option<String> {
+    if xml.contains("<address type='usb'") || xml.contains("<address type=\"usb\"") {
+        return None;
+    }
+    let end = xml.rfind("</hostdev>")?;
+    let port = port.map(|p| format!(" port='{}'", p)).unwrap_or_default();
+    Some(format!(
+        "{}  <address type='usb' bus='{}'{}/>\n{}",
+        &xml[..end],
+        bus,
+        port,
+        &xml[end..]
+    ))
+}
+
+/// Text content of the first `<tag>...</tag>` element in `xml`.
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(xml[start..end].trim())
+}
+
+/// Value of attribute `attr` on the first `<tag ...>` element in `xml`.
+fn xml_attr<'a>(xml: &'a str, tag: &str, attr: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{} ", tag))?;
+    element_attr(&xml[start..start + xml[start..].find('>')?], attr)
+}
+
+/// Value of attribute `attr` within a single element's opening tag.
+fn element_attr<'a>(element: &'a str, attr: &str) -> Option<&'a str> {
+    let value = &element[element.find(&format!(" {}=", attr))? + attr.len() + 2..];
+    let quote = value.chars().next()?;
+    let value = &value[1..];
+    Some(&value[..value.find(quote)?])
+}
+
+/// Whether a domain's XML has a USB controller devices can be hot-plugged
+/// into.  Any `<controller type='usb'>` counts unless its model is `none`,
+/// which is how libvirt spells "no USB bus".
+fn has_usb_controller(domain_xml: &str) -> bool {
+    domain_xml
+        .match_indices("<controller ")
+        .filter_map(|(i, _)| Some(&domain_xml[i..i + domain_xml[i..].find('>')?]))
+        .any(|c| element_attr(c, "type") == Some("usb") && element_attr(c, "model") != Some("none"))
+}
+
+/// Whether a domain can take hot-plugged USB hostdevs.
+///
+/// QEMU guests need a USB controller (see `has_usb_controller`).  LXC
+/// containers have no USB bus; libvirt takes the same subsystem hostdev XML
+/// and creates the device node under the container's `/dev/bus/usb`, so
+/// they always qualify.
+fn accepts_usb_hostdev(domain_xml: &str) -> bool {
+    xml_attr(domain_xml, "domain", "type") == Some("lxc") || has_usb_controller(domain_xml)
+}
+
+/// Whether `vm` is a domain UUID rather than a name, either in the usual
+/// `8-4-4-4-12` form or as 32 bare hex digits, both of which libvirt takes.
+fn is_uuid(vm: &str) -> bool {
+    let groups: Vec<usize> = vm.split('-').map(str::len).collect();
+    (groups == [8, 4, 4, 4, 12] || groups == [32])
+        && vm.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+}
+
+/// The key that filters and attachments for `vm`, a name or UUID, are kept
+/// under: the domain's UUID, so that one VM referred to both ways has one
+/// set of filters and names can be reused.  A VM that can't be looked up,
+/// e.g. one that isn't defined yet, is kept under `vm` as given.
+fn vm_key(conn: &dyn Hypervisor, vm: &str) -> String {
+    if vm == WILDCARD_VM {
+        return vm.to_owned();
+    }
+    match conn.lookup(vm).and_then(|d| d.uuid()) {
+        Ok(uuid) => uuid,
+        Err(_) => vm.to_owned(),
+    }
+}
+
+/// Build hostdev XML from libvirt's node device for the USB device named
+/// `sysname` in sysfs (e.g. `3-7`), rather than from our own sysfs reads.
+///
+/// libvirt names USB node devices `usb_` followed by the sysfs name with
+/// punctuation replaced, so `3-7` is `usb_3_7`.  Returns `None` if there is
+/// no such node device or its XML lacks the bus/device/ID fields.
+fn nodedev_xml(
+    conn: &dyn Hypervisor,
+    sysname: &str,
+    template: Option<&str>,
+    serial: &str,
+) -> Option<String> {
+    let name = format!("usb_{}", sysname.replace(['-', '.', ':'], "_"));
+    let desc = conn.node_device_xml(&name)?;
+    let cap = &desc[desc.find("type='usb_device'")?..];
+    let bus = xml_text(cap, "bus")?;
+    let dev = xml_text(cap, "device")?;
+    let vid = canon_id(xml_attr(cap, "vendor", "id")?)?;
+    let pid = canon_id(xml_attr(cap, "product", "id")?)?;
+    Some(usb_xml(template, &vid, &pid, bus, dev, serial))
+}
+
+/// Resolve a udev device to the `usb_device` that owns it.
+///
+/// Walks `parent()` upwards from `device` itself, stopping at the first
+/// `usb_device` in the `usb` subsystem.  The walk never leaves the `usb`
+/// subsystem, so an interface of a composite device can't resolve to the PCI
+/// host controller above its root hub.  Returns `None` if there is no owning
+/// `usb_device`.
+fn owning_usb_device(device: udev::Device) -> Option<udev::Device> {
+    let mut current = Some(device);
+    while let Some(dev) = current {
+        if dev.subsystem() != Some(OsStr::new("usb")) {
+            return None;
+        }
+        if dev.devtype() == Some(OsStr::new("usb_device")) {
+            return Some(dev);
+        }
+        current = dev.parent();
+    }
+    None
+}
+
+/// An attach to try again.
+#[derive(Debug, PartialEq)]
+struct PendingAttach {
+    /// Key of the filter that matched: a VM, a VM template or `*`.
+    filter: String,
+    /// The VM that filter resolved to.
+    vm: String,
+    /// Attaches tried so far.
+    attempts: u32,
+}
+
+/// Filter and attachment bookkeeping owned by the `poll` loop.
+#[derive(Default)]
+struct State {
+    filters: BTreeMap<String, HashSet<UsbFilter>>,
+    /// Attached devices by syspath.
+    sysdevs: BTreeMap<PathBuf, SysDev>,
+    xmls: BTreeMap<String, Vec<(PathBuf, String)>>,
+    xml_template: Option<String>,
+    max_power: Option<u32>,
+    /// `startupPolicy` for the `<source>` of generated hostdevs.
+    startup_policy: Option<String>,
+    /// Build attach XML from libvirt node devices when possible.
+    nodedev: bool,
+    /// Local subscribers to attach/detach events.
+    events: Option<EventSocket>,
+    /// Attaches and detaches for the D-Bus thread to emit as signals.
+    dbus_signals: Option<Sender<DeviceSignal>>,
+    /// Per-VM result of probing for a hot-pluggable USB controller.
+    usb_capable: BTreeMap<String, bool>,
+    serial_norm: SerialNormalization,
+    /// Log attaches and detaches instead of performing them.
+    dry_run: bool,
+    /// Resume a paused VM to attach a device to it.
+    resume_on_attach: bool,
+    /// Log the XML of every attach and detach at info rather than debug.
+    verbose_xml: bool,
+    /// Where udev events and filter changes are recorded, for `--record`.
+    recorder: Option<Recorder>,
+    /// Where every attach and detach is logged, for `--audit-log`.
+    audit: Option<AuditLog>,
+    /// Where filters are saved after every change, for `--state-file`.
+    /// Unset during a replay, which must not clobber the live filters.
+    state_file: Option<PathBuf>,
+    /// Program that confirms a guest saw an attached device.
+    confirm_hook: Option<PathBuf>,
+    confirm_timeout: Duration,
+    /// Attaches waiting on `confirm_hook`.
+    confirming: Vec<PendingConfirm>,
+    /// When a `Drain` started.  No new devices are attached once set.
+    draining: Option<Instant>,
+    /// When `reconcile` next compares attached devices with the VMs' live
+    /// XML, for `--reconcile-interval`.
+    next_reconcile: Option<Instant>,
+    /// When `check_connection` next checks the hypervisor connection, or
+    /// tries to reopen it.
+    next_connection_check: Option<Instant>,
+    /// How long to wait after the next failed reconnect.  Zero while
+    /// connected.
+    reconnect_delay: Duration,
+    /// Times of day when attaches are suppressed.
+    suppress_windows: Vec<SuppressWindow>,
+    /// Compare sysfs VIDs and PIDs as read, for `--legacy-match`.
+    legacy_match: bool,
+    /// Per-VM circuit breakers over failed attaches.
+    breakers: BTreeMap<String, Breaker>,
+    /// Domain handles, so that attaches and detaches needn't each look
+    /// their VM up.
+    domains: DomainCache,
+    /// How many times a failed attach is retried before it is left pending.
+    attach_retries: u32,
+    /// Attaches that failed, or were paused by a breaker, by syspath.
+    /// Retried by `retry_pending`.
+    pending_attaches: BTreeMap<PathBuf, PendingAttach>,
+    /// Devices whose filter's `max_attach_retries` ran out, by syspath.
+    /// Skipped until a `Reattach` or a replug.
+    failed_attaches: BTreeMap<PathBuf, PendingAttach>,
+    /// How long a plugged device must stay before it is matched.  Zero
+    /// matches it straight away.
+    debounce: Duration,
+    /// Plugged devices waiting out `debounce`, by syspath, with the time of
+    /// their latest add event.
+    debouncing: BTreeMap<PathBuf, Instant>,
+    /// How long an attached device may be gone before it is detached.
+    /// Zero detaches it straight away.
+    unplug_grace: Duration,
+    /// Attached devices that were removed and are waiting out
+    /// `unplug_grace`, by syspath, with the time of their removal.
+    unplugging: BTreeMap<PathBuf, Instant>,
+    /// Plugged devices no filter matched, by syspath, with when they were
+    /// last reported and the VID, PID and serial reported.
+    unmatched: BTreeMap<PathBuf, (Instant, [String; 3])>,
+    /// The latest failure of each VM, for `LastError`.
+    errors: LastErrors,
+    /// Attach and detach counters for `--metrics-addr`.
+    metrics: Metrics,
+    /// Options the daemon was started with, and where devices come from
+    /// (`monitor`, `scan` or `replay`), for `GetConfig`.
+    args: Args,
+    source: &'static str,
+}
+
+/// The part of `State` that `List`, `Attached` and `Status` read.  The
+/// `poll` loop copies it out at the end of every cycle, holding the lock
+/// only for the copy, so that front ends answer queries without waiting
+/// for a cycle that may be busy in libvirt.
+#[derive(Default)]
+pub struct Snapshot {
+    filters: BTreeMap<String, HashSet<UsbFilter>>,
+    sysdevs: BTreeMap<PathBuf, SysDev>,
+    /// Attaches waiting for a retry, with the VM and the attempts made.
+    pending: BTreeMap<PathBuf, (String, u32)>,
+    /// Devices marked failed, likewise.
+    failed: BTreeMap<PathBuf, (String, u32)>,
+    /// Each VM's breaker: recent failed attaches, and when its attaches
+    /// resume if it is open.
+    breakers: BTreeMap<String, (u32, Option<Instant>)>,
+    uri: String,
+    connected: bool,
+    dry_run: bool,
+    draining: bool,
+}
+
+/// Copy what queries read from `state` into `snapshot`.  `connected` is
+/// checked before the lock is taken.
+fn publish(conn: &dyn Hypervisor, state: &State, snapshot: &RwLock<Snapshot>) {
+    let connected = conn.is_alive();
+    let mut snapshot = snapshot.write().unwrap_or_else(PoisonError::into_inner);
+    snapshot.filters.clone_from(&state.filters);
+    snapshot.sysdevs.clone_from(&state.sysdevs);
+    let attempts = |attaches: &BTreeMap<PathBuf, PendingAttach>| {
+        attaches
+            .iter()
+            .map(|(syspath, a)| (syspath.clone(), (a.vm.clone(), a.attempts)))
+            .collect()
+    };
+    snapshot.pending = attempts(&state.pending_attaches);
+    snapshot.failed = attempts(&state.failed_attaches);
+    snapshot.breakers = state
+        .breakers
+        .iter()
+        .map(|(vm, b)| (vm.clone(), (b.failures, b.open_until)))
+        .collect();
+    snapshot.uri = state
+        .args
+        .connect
+        .clone()
+        .unwrap_or_else(|| DEFAULT_URI.to_owned());
+    snapshot.connected = connected;
+    snapshot.dry_run = state.dry_run;
+    snapshot.draining = state.draining.is_some();
+}
+
+/// The latest failed attach, detach or lookup of each VM, by VM key, with
+/// when it happened.
+#[derive(Default)]
+struct LastErrors {
+    by_vm: BTreeMap<String, (String, String)>,
+    /// The VM of the latest failure of all.
+    latest: Option<String>,
+}
+
+impl LastErrors {
+    fn record(&mut self, vm: &str, message: String) {
+        self.by_vm.insert(vm.to_owned(), (utc_timestamp(), message));
+        self.latest = Some(vm.to_owned());
+    }
+
+    /// The latest failure of `vm`, or of any VM without it, as a JSON
+    /// object: `{"time":...,"vm":...,"error":...}`, or `null` if there
+    /// was none.
+    fn to_json(&self, vm: Option<&str>) -> String {
+        let vm = match vm.or(self.latest.as_deref()) {
+            Some(vm) => vm,
+            None => return "null".to_owned(),
+        };
+        match self.by_vm.get(vm) {
+            Some((time, message)) => format!(
+                "{{\"time\":{},\"vm\":{},\"error\":{}}}",
+                json_str(time),
+                json_str(vm),
+                json_str(message)
+            ),
+            None => "null".to_owned(),
+        }
+    }
+}
+
+/// An attached device and the one VM it is attached to.
+#[derive(Clone)]
+struct SysDev {
+    device: UsbFilter,
+    vm: String,
+    /// USB bus and device number when it was attached, both empty for a
+    /// PCI device.  The kernel numbers every plug afresh.
+    address: (String, String),
+}
+
+/// The effective configuration, as TOML `key = value` lines.
+///
+/// Options that were changed at runtime, such as the XML template or power
+/// limit, are reported with their current values.  Unset options are left
+/// out.
+fn effective_config(state: &State) -> String {
+    let args = &state.args;
+    let path = |p: &PathBuf| json_str(&p.to_string_lossy());
+    let windows: Vec<String> = state
+        .suppress_windows
+        .iter()
+        .map(|w| json_str(&w.to_string()))
+        .collect();
+    let mut lines = vec![
+        format!(
+            "connect = {}",
+            json_str(args.connect.as_deref().unwrap_or(DEFAULT_URI))
+        ),
+        format!(
+            "also_connect = [{}]",
+            args.also_connect
+                .iter()
+                .map(|uri| json_str(uri))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        format!("source = {}", json_str(state.source)),
+        format!("dbus = {}", !args.no_dbus),
+        format!(
+            "bus = {}",
+            json_str(if args.system_bus { "system" } else { "session" })
+        ),
+        format!(
+            "dbus_name = {}",
+            json_str(args.dbus_name.as_deref().unwrap_or(DEFAULT_DBUS_NAME))
+        ),
+        format!("dry_run = {}", state.dry_run),
+        format!("keep_attached = {}", args.keep_attached),
+        format!("resume_on_attach = {}", state.resume_on_attach),
+        format!("verbose_xml = {}", state.verbose_xml),
+        format!("nodedev = {}", state.nodedev),
+        format!("legacy_match = {}", state.legacy_match),
+        format!(
+            "serial_normalize = {}",
+            json_str(&state.serial_norm.to_string())
+        ),
+        format!("suppress_windows = [{}]", windows.join(", ")),
+        format!("confirm_timeout = {}", state.confirm_timeout.as_secs()),
+        format!("debounce_ms = {}", state.debounce.as_millis()),
+        format!("unplug_grace_ms = {}", state.unplug_grace.as_millis()),
+        format!("attach_retries = {}", state.attach_retries),
+    ];
+    let optional = [
+        (
+            "scan_interval",
+            args.scan_interval.map(|d| d.as_secs().to_string()),
+        ),
+        (
+            "max_runtime",
+            args.max_runtime.map(|d| d.as_secs().to_string()),
+        ),
+        (
+            "drain_timeout",
+            args.drain_timeout.map(|d| d.as_secs().to_string()),
+        ),
+        (
+            "reconcile_interval",
+            args.reconcile_interval.map(|d| d.as_secs().to_string()),
+        ),
+        (
+            "poll_timeout_ms",
+            args.poll_timeout.map(|d| d.as_millis().to_string()),
+        ),
+        ("event_socket", args.event_socket.as_ref().map(path)),
+        (
+            "metrics_addr",
+            args.metrics_addr.map(|a| json_str(&a.to_string())),
+        ),
+        ("control_socket", args.control_socket.as_ref().map(path)),
+        ("confirm_hook", state.confirm_hook.as_ref().map(path)),
+        ("record", args.record.as_ref().map(path)),
+        ("audit_log", args.audit_log.as_ref().map(path)),
+        ("state_file", args.state_file.as_ref().map(path)),
+        ("config", args.config.as_ref().map(path)),
+        ("replay", args.replay.as_ref().map(path)),
+        ("max_power", state.max_power.map(|ma| ma.to_string())),
+        (
+            "startup_policy",
+            state.startup_policy.as_deref().map(json_str),
+        ),
+        ("xml_template", state.xml_template.as_deref().map(json_str)),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value {
+            lines.push(format!("{} = {}", key, value));
+        }
+    }
+    if args.replay.is_some() {
+        lines.push(format!("replay_fast = {}", args.replay_fast));
+    }
+    lines.join("\n")
+}
+
+/// Every registered filter as newline-delimited JSON, one object per
+/// filter with the VM and the filter's `to_state_fields`.  Sorted by VM and
+/// then by fields.  VMs whose filters have all been removed are left out,
+/// so with no filters the reply is empty.
+fn filter_list(snapshot: &Snapshot) -> String {
+    let mut lines = Vec::new();
+    for (vm, usb_filters) in snapshot.filters.iter() {
+        let mut vm_lines: Vec<String> = usb_filters
+            .iter()
+            .map(|filter| filter_json(Some(vm), filter))
+            .collect();
+        vm_lines.sort();
+        lines.extend(vm_lines);
+    }
+    lines.join("\n")
+}
+
+/// A filter's `to_state_fields` as a JSON object, led by its VM if given.
+fn filter_json(vm: Option<&str>, filter: &UsbFilter) -> String {
+    let mut fields: Vec<String> = vm
+        .map(|vm| format!("\"vm\":{}", json_str(vm)))
+        .into_iter()
+        .collect();
+    for (key, value) in &filter.to_state_fields() {
+        fields.push(format!("{}:{}", json_str(key), json_str(value)));
+    }
+    format!("{{{}}}", fields.join(","))
+}
+
+/// Every attached device as newline-delimited JSON, one object per device
+/// with its `vm`, `vid`, `pid` and `syspath`, sorted by syspath.  A device
+/// without a VID or PID has an empty string for it.  With nothing attached
+/// the reply is empty.
+fn attached_list(snapshot: &Snapshot) -> String {
+    let lines: Vec<String> = snapshot
+        .sysdevs
+        .iter()
+        .map(|(syspath, sysdev)| attached_json(syspath, sysdev))
+        .collect();
+    lines.join("\n")
+}
+
+fn attached_json(syspath: &Path, sysdev: &SysDev) -> String {
+    format!(
+        "{{\"vm\":{},\"vid\":{},\"pid\":{},\"syspath\":{}}}",
+        json_str(&sysdev.vm),
+        json_str(sysdev.device.vid.as_deref().unwrap_or("")),
+        json_str(sysdev.device.pid.as_deref().unwrap_or("")),
+        json_str(&syspath.to_string_lossy())
+    )
+}
+
+/// The whole daemon state as a single JSON object, for dashboards:
+///
+/// ```json
+/// {"uri":"qemu:///system","connected":true,"dry_run":false,"draining":false,
+///  "filters":{"<vm>":[{"vid":"1050","pid":"0407"}]},
+///  "attached":[{"vm":"<vm>","vid":"1050","pid":"0407","syspath":"..."}],
+///  "pending":[{"vm":"<vm>","syspath":"...","attempts":3}],
+///  "failed":[{"vm":"<vm>","syspath":"...","attempts":3}],
+///  "breakers":{"<vm>":{"failures":2,"open_for_secs":null}}}
+/// ```
+///
+/// Filters are keyed by VM and sorted as for `List`; devices are as for
+/// `Attached`.  `connected` is whether libvirt still answered on the
+/// connection at the end of the last poll cycle.  `pending` has the
+/// devices waiting for a retry, with the attempts made so far, and `failed`
+/// those whose filter's `max_attach_retries` ran out.  `breakers` has each VM
+/// with recent failed attaches: how many, and the seconds until its
+/// attaches resume if they are paused, else `null`.
+fn status_report(snapshot: &Snapshot) -> String {
+    let filters: Vec<String> = snapshot
+        .filters
+        .iter()
+        .filter(|(_, usb_filters)| !usb_filters.is_empty())
+        .map(|(vm, usb_filters)| {
+            let mut objects: Vec<String> =
+                usb_filters.iter().map(|f| filter_json(None, f)).collect();
+            objects.sort();
+            format!("{}:[{}]", json_str(vm), objects.join(","))
+        })
+        .collect();
+    let attached: Vec<String> = snapshot
+        .sysdevs
+        .iter()
+        .map(|(p, d)| attached_json(p, d))
+        .collect();
+    let attempts = |attaches: &BTreeMap<PathBuf, (String, u32)>| -> Vec<String> {
+        attaches
+            .iter()
+            .map(|(syspath, (vm, attempts))| {
+                format!(
+                    "{{\"vm\":{},\"syspath\":{},\"attempts\":{}}}",
+                    json_str(vm),
+                    json_str(&syspath.to_string_lossy()),
+                    attempts
+                )
+            })
+            .collect()
+    };
+    let pending = attempts(&snapshot.pending);
+    let failed = attempts(&snapshot.failed);
+    let now = Instant::now();
+    let breakers: Vec<String> = snapshot
+        .breakers
+        .iter()
+        .map(|(vm, (failures, open_until))| {
+            let open_for = open_until
+                .and_then(|until| until.checked_duration_since(now))
+                .map_or("null".to_owned(), |left| left.as_secs().to_string());
+            format!(
+                "{}:{{\"failures\":{},\"open_for_secs\":{}}}",
+                json_str(vm),
+                failures,
+                open_for
+            )
+        })
+        .collect();
+    format!(
+        "{{\"uri\":{},\"connected\":{},\"dry_run\":{},\"draining\":{},\"filters\":{{{}}},\"attached\":[{}],\"pending\":[{}],\"failed\":[{}],\"breakers\":{{{}}}}}",
+        json_str(&snapshot.uri),
+        snapshot.connected,
+        snapshot.dry_run,
+        snapshot.draining,
+        filters.join(","),
+        attached.join(","),
+        pending.join(","),
+        failed.join(","),
+        breakers.join(",")
+    )
+}
+
+/// Delay before the first retry of a failed attach, doubled for each
+/// further retry.
+const ATTACH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How many times a failed attach is retried straight away, by default.
+const DEFAULT_ATTACH_RETRIES: u32 = 2;
+
+/// `domain.attach_device(xml)`, or `detach_device` unless `attach`, with
+/// the XML logged as it is sent: at info with `--verbose-xml`, else at
+/// debug.  If libvirt refuses it, the XML is logged whatever the level, so
+/// that the failure can be reproduced with `virsh attach-device`.
+fn hotplug(
+    domain: &dyn Guest,
+    attach: bool,
+    vm: &str,
+    xml: &str,
+    verbose: bool,
+) -> Result<(), hypervisor::Error> {
+    let action = if attach { "attach" } else { "detach" };
+    match verbose {
+        true => info!("Sending {} to vm {}:\n{}", action, vm, xml),
+        _ => debug!("Sending {} to vm {}:\n{}", action, vm, xml),
+    }
+    let result = match attach {
+        true => domain.attach_device(xml),
+        _ => domain.detach_device(xml),
+    };
+    if let Err(e) = result.as_ref() {
+        warn!("vm {} refused {} ({}) of:\n{}", vm, action, e, xml);
+    }
+    result
+}
+
+/// `domain.attach_device(xml)`, retried up to `retries` times with a
+/// doubling delay for a VM that is briefly busy, such as while its guest
+/// boots.  Returns the last error if every attempt failed.
+fn attach_with_retries(
+    domain: &dyn Guest,
+    xml: &str,
+    vm: &str,
+    retries: u32,
+    verbose: bool,
+) -> Result<(), hypervisor::Error> {
+    let mut delay = ATTACH_RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match hotplug(domain, true, vm, xml, verbose) {
+            Ok(_) => {
+                if attempt > 1 {
+                    info!("Attached to vm {} on attempt {}", vm, attempt);
+                }
+                return Ok(());
+            }
+            Err(e) if attempt > retries => return Err(e),
+            Err(e) => {
+                warn!(
+                    "attach to vm {} failed (attempt {} of {}), retrying in {:?}: {}",
+                    vm,
+                    attempt,
+                    retries + 1,
+                    delay,
+                    e
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Try again every attach that failed or was paused, to the VM it was for.
+/// Called on every udev and lifecycle event, since that is when a busy VM
+/// has most likely come back.
+fn retry_pending(conn: &dyn Hypervisor, state: &mut State) {
+    for (syspath, pending) in std::mem::take(&mut state.pending_attaches) {
+        // Unplugged, or attached elsewhere since.
+        if state.sysdevs.contains_key(&syspath) || !syspath.exists() {
+            continue;
+        }
+        info!(
+            "Retrying pending attach of syspath: {} to vm {}",
+            syspath.display(),
+            pending.vm
+        );
+        let attrs = read_sysfs_attrs(&syspath);
+        device_added(conn, state, &syspath, &attrs, Some(&pending.filter));
+        if let Some(again) = state.pending_attaches.get_mut(&syspath) {
+            again.attempts += pending.attempts;
+        }
+    }
+}
+
+/// Try once more, as for a `Reattach`, to attach every device marked failed
+/// for `vm`.  Returns a line per device saying how that went.
+fn retry_failed(conn: &dyn Hypervisor, state: &mut State, vm: &str) -> Vec<String> {
+    let failed: Vec<(PathBuf, String)> = state
+        .failed_attaches
+        .iter()
+        .filter(|(_, failed)| failed.vm == vm)
+        .map(|(syspath, failed)| (syspath.clone(), failed.filter.clone()))
+        .collect();
+    let mut lines = Vec::new();
+    for (syspath, filter) in failed {
+        state.failed_attaches.remove(&syspath);
+        info!(
+            "Retrying failed attach of syspath: {} to vm {}",
+            syspath.display(),
+            vm
+        );
+        let attrs = read_sysfs_attrs(&syspath);
+        device_added(conn, state, &syspath, &attrs, Some(&filter));
+        let status = match state.sysdevs.get(&syspath) {
+            Some(sysdev) if sysdev.vm == vm => "reattached",
+            _ => "failed",
+        };
+        lines.push(format!("{}: {}", syspath.display(), status));
+    }
+    lines
+}
+
+/// Consecutive failed attaches within `BREAKER_WINDOW` that pause a VM's
+/// attaches for `BREAKER_COOLDOWN`.
+const BREAKER_FAILURES: u32 = 5;
+const BREAKER_WINDOW: Duration = Duration::from_secs(60);
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(300);
+
+/// Circuit breaker for one VM, so a persistently broken target isn't
+/// retried on every plug.  Dropped after a successful attach.
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    first_failure: Option<Instant>,
+    /// Attaches are paused until then.
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    /// Count a failed attach to `vm`, opening the breaker after
+    /// `BREAKER_FAILURES` in a row within `BREAKER_WINDOW`.
+    fn failed(&mut self, vm: &str) {
+        let now = Instant::now();
+        if self.first_failure.is_none_or(|t| now - t > BREAKER_WINDOW) {
+            self.failures = 0;
+            self.first_failure = Some(now);
+        }
+        self.failures += 1;
+        if self.failures >= BREAKER_FAILURES {
+            warn!(
+                "{} failed attaches to vm {} in a row, pausing its attaches for {:?}",
+                self.failures, vm, BREAKER_COOLDOWN
+            );
+            self.open_until = Some(now + BREAKER_COOLDOWN);
+            self.failures = 0;
+            self.first_failure = None;
+        }
+    }
+
+    /// Whether attaches to `vm` are paused.  Closes the breaker once the
+    /// cooldown has passed.
+    fn is_open(&mut self, vm: &str) -> bool {
+        match self.open_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                info!("Cooldown over, resuming attaches to vm {}", vm);
+                self.open_until = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// How many times an unconfirmed attach is retried before giving up.
+const CONFIRM_RETRIES: u32 = 3;
+
+/// An attach waiting for `--confirm-hook` to report that the guest saw the
+/// device.
+struct PendingConfirm {
+    vm: String,
+    syspath: PathBuf,
+    xml: String,
+    /// Arguments the hook is run with: VM, VID, PID, bus and device.
+    args: Vec<String>,
+    hook: Child,
+    deadline: Instant,
+    retries: u32,
+}
+
+/// Start the confirm hook for one attach.
+fn spawn_confirm_hook(hook: &Path, args: &[String]) -> io::Result<Child> {
+    std::process::Command::new(hook)
+        .args(args)
+        .stdin(Stdio::null())
+        .spawn()
+}
+
+/// Collect finished confirm hooks.  An attach the hook didn't confirm
+/// before the timeout is detached and attached again, up to
+/// `CONFIRM_RETRIES` times, after which the device is left attached.
+fn check_confirmations(conn: &dyn Hypervisor, state: &mut State) {
+    let hook = match state.confirm_hook.clone() {
+        Some(h) => h,
+        None => return,
+    };
+    let mut waiting = Vec::new();
+    for mut pending in std::mem::take(&mut state.confirming) {
+        let confirmed = match pending.hook.try_wait() {
+            Ok(Some(status)) => status.success(),
+            Ok(None) if Instant::now() < pending.deadline => {
+                waiting.push(pending);
+                continue;
+            }
+            Ok(None) => {
+                let _ = pending.hook.kill();
+                let _ = pending.hook.wait();
+                false
+            }
+            Err(e) => {
+                warn!("failed to wait for confirm hook: {}", e);
+                false
+            }
+        };
+        if confirmed {
+            info!(
+                "Confirmed syspath: {} in vm {}",
+                pending.syspath.display(),
+                pending.vm
+            );
+            continue;
+        }
+        // Nothing to retry if the device went away in the meantime.
+        let attached = state
+            .xmls
+            .get(&pending.vm)
+            .is_some_and(|x| x.iter().any(|(p, _)| *p == pending.syspath));
+        if !attached {
+            continue;
+        }
+        if pending.retries >= CONFIRM_RETRIES {
+            warn!(
+                "vm {} never confirmed syspath: {}, leaving it attached",
+                pending.vm,
+                pending.syspath.display()
+            );
+            continue;
+        }
+        pending.retries += 1;
+        info!(
+            "Syspath: {} not confirmed in vm {}, reattaching (retry {} of {})",
+            pending.syspath.display(),
+            pending.vm,
+            pending.retries,
+            CONFIRM_RETRIES
+        );
+        if let Ok(domain) = state.domains.get(conn, &pending.vm) {
+            let device = state
+                .sysdevs
+                .get(&pending.syspath)
+                .map(|d| d.device.clone())
+                .unwrap_or_default();
+            let detached = hotplug(
+                &*domain,
+                false,
+                &pending.vm,
+                &pending.xml,
+                state.verbose_xml,
+            );
+            if let Err(e) = detached.as_ref() {
+                warn!("failed to hot-unplug from domain {}: {}", pending.vm, e);
+            }
+            let attached = hotplug(&*domain, true, &pending.vm, &pending.xml, state.verbose_xml);
+            if let Some(audit) = state.audit.as_mut() {
+                for (action, result) in [("detach", &detached), ("attach", &attached)] {
+                    let error = result.as_ref().err().map(|e| e.to_string());
+                    let outcome = error.as_deref().map_or(Outcome::Done, Outcome::Failed);
+                    audit.write(action, &pending.vm, &device, &pending.syspath, outcome);
+                }
+            }
+            if let Err(e) = attached {
+                warn!("failed to reattach to domain {}: {}", pending.vm, e);
+                let message = format!("reattach of {} failed: {}", pending.syspath.display(), e);
+                state.errors.record(&pending.vm, message);
+                state.domains.forget(&pending.vm);
+                continue;
+            }
+        }
+        match spawn_confirm_hook(&hook, &pending.args) {
+            Ok(child) => {
+                pending.hook = child;
+                pending.deadline = Instant::now() + state.confirm_timeout;
+                waiting.push(pending);
+            }
+            Err(e) => warn!("failed to run confirm hook {}: {}", hook.display(), e),
+        }
+    }
+    state.confirming = waiting;
+}
+
+/// Apply every queued command to `state`.
+///
+/// Returns `false` once a `Shutdown` command has been received.  Commands
+/// queued behind the `Shutdown` are still applied, so nothing a client was
+/// told succeeded is silently dropped.
+fn drain_commands(
+    receiver: &wake::Receiver<Command>,
+    conn: &dyn Hypervisor,
+    state: &mut State,
+) -> bool {
+    let mut running = true;
+    let mut filters_changed = false;
+    while let Ok(mut cmd) = receiver.try_recv() {
+        if let Some((vm, filter)) = cmd.named_filter() {
+            // Registered serials get the same normalisation as observed ones.
+            filter.serial = filter.serial.take().map(|s| state.serial_norm.apply(&s));
+            if !filter.templated_vm {
+                let key = vm_key(conn, vm);
+                if key != *vm {
+                    debug!("vm {} is {}", vm, key);
+                    *vm = key;
+                }
+            }
+        }
+        match cmd {
+            Command::Shutdown => {
+                running = false;
+            }
+            Command::Add { vm, filter } => {
+                add_filter(state, vm.clone(), filter);
+                filters_changed = true;
+                // A recording has no live devices to pick up.
+                if state.source != "replay" {
+                    attach_present(conn, state, Some(&vm));
+                }
+            }
+            Command::AddMany { vm, filters, reply } => {
+                let vm = vm_key(conn, &vm);
+                let mut added = 0;
+                for mut filter in filters {
+                    filter.serial = filter.serial.take().map(|s| state.serial_norm.apply(&s));
+                    if add_filter(state, vm.clone(), filter) {
+                        added += 1;
+                    }
+                }
+                info!("Added {} new filter(s) for vm {}", added, vm);
+                filters_changed = true;
+                if state.source != "replay" {
+                    attach_present(conn, state, Some(&vm));
+                }
+                let _ = reply.send(added.to_string());
+            }
+            Command::SetTemplate(template) => {
+                state.xml_template = match template.is_empty() {
+                    true => None,
+                    _ => Some(template),
+                };
+                info!(
+                    "XML template: {}",
+                    if state.xml_template.is_some() {
+                        "custom"
+                    } else {
+                        "built-in"
+                    }
+                );
+            }
+            Command::SetMaxPower(milliamps) => {
+                state.max_power = match milliamps {
+                    0 => None,
+                    ma => Some(ma),
+                };
+                info!("Max power: {:?}mA", state.max_power);
+            }
+            Command::SetStartupPolicy(policy) => {
+                info!("Startup policy: {}", policy.as_deref().unwrap_or("none"));
+                state.startup_policy = policy;
+            }
+            Command::GetConfig(reply) => {
+                let _ = reply.send(effective_config(state));
+            }
+            Command::LastError { vm, reply } => {
+                let vm = Some(vm)
+                    .filter(|vm| !vm.is_empty())
+                    .map(|vm| vm_key(conn, &vm));
+                let _ = reply.send(state.errors.to_json(vm.as_deref()));
+            }
+            Command::Metrics(reply) => {
+                // Labelled by name where the VM is defined, else by its key.
+                let mut attached = BTreeMap::<String, usize>::new();
+                for sysdev in state.sysdevs.values() {
+                    let name = state.domains.get(conn, &sysdev.vm).and_then(|d| d.name());
+                    *attached
+                        .entry(name.unwrap_or_else(|_| sysdev.vm.clone()))
+                        .or_default() += 1;
+                }
+                let connected = conn.is_alive();
+                let _ = reply.send(state.metrics.render(connected, &attached));
+            }
+            Command::DetachPath { syspath, reply } => {
+                let vms = device_removed(conn, state, &syspath);
+                let _ = reply.send(match vms.is_empty() {
+                    true => format!("ERROR: nothing attached at {}", syspath.display()),
+                    _ => vms.join(","),
+                });
+            }
+            Command::Detach {
+                vm,
+                filter,
+                remove_filters,
+                reply,
+            } => {
+                let vm = vm_key(conn, &vm);
+                let results = detach_where(conn, state, &vm, |device| filter.matches(device));
+                if results.is_empty() {
+                    let _ = reply.send(format!("ERROR: nothing attached to vm {} matches", vm));
+                    continue;
+                }
+                // The devices are still plugged in, so with their filters in
+                // place a rescan or a restart of the VM would attach them again.
+                if remove_filters {
+                    let detached: Vec<UsbFilter> = results
+                        .iter()
+                        .map(|(_, device, _)| device.clone())
+                        .collect();
+                    for stored in state.filters.get(&vm).cloned().unwrap_or_default() {
+                        if detached.iter().any(|d| stored.matches(d)) {
+                            remove_filter(state, &vm, &stored);
+                            filters_changed = true;
+                        }
+                    }
+                }
+                let lines: Vec<String> = results
+                    .iter()
+                    .map(|(syspath, _, error)| match error {
+                        Some(e) => format!("{}: ERROR: {}", syspath.display(), e),
+                        None => format!("{}: ok", syspath.display()),
+                    })
+                    .collect();
+                let _ = reply.send(lines.join("\n"));
+            }
+            Command::DetachAll { vm, reply } => {
+                let vm = vm_key(conn, &vm);
+                let detached = detach_where(conn, state, &vm, |_| true).len();
+                state.pending_attaches.retain(|_, pending| pending.vm != vm);
+                // Otherwise the next plug would attach them again.
+                for filter in state.filters.get(&vm).cloned().unwrap_or_default() {
+                    remove_filter(state, &vm, &filter);
+                    filters_changed = true;
+                }
+                state.filters.remove(&vm);
+                info!(
+                    "Detached {} device(s) from vm {} and dropped its filters",
+                    detached, vm
+                );
+                let _ = reply.send(detached.to_string());
+            }
+            Command::Reattach { vm, reply } => {
+                // Every VM holding devices, whose attaches are paused, or
+                // with devices marked failed.
+                let vms: Vec<String> = match vm {
+                    Some(vm) => vec![vm_key(conn, &vm)],
+                    None => state
+                        .xmls
+                        .keys()
+                        .chain(state.breakers.keys())
+                        .chain(state.failed_attaches.values().map(|failed| &failed.vm))
+                        .cloned()
+                        .collect::<BTreeSet<String>>()
+                        .into_iter()
+                        .collect(),
+                };
+                let mut lines = Vec::new();
+                for vm in &vms {
+                    // A reattach is a manual retry, so it closes the breaker.
+                    if state.breakers.remove(vm).is_some() {
+                        info!("Reattach requested, resuming attaches to vm {}", vm);
+                    }
+                    lines.extend(reattach(conn, state, vm));
+                    lines.extend(retry_failed(conn, state, vm));
+                }
+                let _ = reply.send(lines.join("\n"));
+            }
+            Command::SetEnabled {
+                vm,
+                filter,
+                enabled,
+            } => {
+                let mut stored = match state.filters.get_mut(&vm).and_then(|f| f.take(&filter)) {
+                    Some(f) => f,
+                    None => {
+                        warn!("No filter {:?}:{:?} for vm {}", filter.vid, filter.pid, vm);
+                        continue;
+                    }
+                };
+                info!(
+                    "udev {}: {:?}:{:?}",
+                    if enabled { "enable" } else { "disable" },
+                    filter.vid,
+                    filter.pid
+                );
+                stored.enabled = enabled;
+                if !enabled {
+                    detach_matching(conn, state, &vm, &stored);
+                }
+                if let Some(usb_filters) = state.filters.get_mut(&vm) {
+                    usb_filters.insert(stored);
+                }
+                filters_changed = true;
+            }
+            Command::SetBootOrder { vm, filter, order } => {
+                let usb_filters = match state.filters.get_mut(&vm) {
+                    Some(f) if f.contains(&filter) => f,
+                    _ => {
+                        warn!("No filter {:?}:{:?} for vm {}", filter.vid, filter.pid, vm);
+                        continue;
+                    }
+                };
+                let boot_order = Some(order).filter(|o| *o > 0);
+                if boot_order.is_some()
+                    && usb_filters
+                        .iter()
+                        .any(|f| f.boot_order == boot_order && *f != filter)
+                {
+                    warn!(
+                        "another filter for vm {} already has boot order {}",
+                        vm, order
+                    );
+                }
+                if let Some(mut stored) = usb_filters.take(&filter) {
+                    info!(
+                        "udev boot order: {:?}:{:?} -> {:?}",
+                        filter.vid, filter.pid, boot_order
+                    );
+                    stored.boot_order = boot_order;
+                    usb_filters.insert(stored);
+                    filters_changed = true;
+                }
+            }
+            Command::SetGuestAddress {
+                vm,
+                filter,
+                bus,
+                port,
+            } => {
+                let usb_filters = match state.filters.get_mut(&vm) {
+                    Some(f) if f.contains(&filter) => f,
+                    _ => {
+                        warn!("No filter {:?}:{:?} for vm {}", filter.vid, filter.pid, vm);
+                        continue;
+                    }
+                };
+                if let Some(mut stored) = usb_filters.take(&filter) {
+                    stored.guest_bus = bus;
+                    stored.guest_port = port;
+                    let address = stored.placement().guest_address;
+                    // Two devices can't share a port, but a bus alone is fine.
+                    if address.is_some_and(|(_, port)| port.is_some())
+                        && usb_filters
+                            .iter()
+                            .any(|f| f.placement().guest_address == address)
+                    {
+                        warn!(
+                            "another filter for vm {} already attaches at guest address {:?}",
+                            vm, address
+                        );
+                    }
+                    info!(
+                        "udev guest address: {:?}:{:?} -> {:?}",
+                        filter.vid, filter.pid, address
+                    );
+                    usb_filters.insert(stored);
+                    filters_changed = true;
+                }
+            }
+            Command::SetFilterTemplate {
+                vm,
+                filter,
+                template,
+            } => {
+                let stored = state.filters.get_mut(&vm).and_then(|f| f.take(&filter));
+                let mut stored = match stored {
+                    Some(f) => f,
+                    None => {
+                        warn!("No filter {:?}:{:?} for vm {}", filter.vid, filter.pid, vm);
+                        continue;
+                    }
+                };
+                info!(
+                    "udev XML template: {:?}:{:?} -> {}",
+                    filter.vid,
+                    filter.pid,
+                    if template.is_some() {
+                        "custom"
+                    } else {
+                        "global"
+                    }
+                );
+                stored.xml_template = template;
+                if let Some(usb_filters) = state.filters.get_mut(&vm) {
+                    usb_filters.insert(stored);
+                }
+                filters_changed = true;
+            }
+            Command::SetAttachRetries {
+                vm,
+                filter,
+                retries,
+            } => {
+                let stored = state.filters.get_mut(&vm).and_then(|f| f.take(&filter));
+                let mut stored = match stored {
+                    Some(f) => f,
+                    None => {
+                        warn!("No filter {:?}:{:?} for vm {}", filter.vid, filter.pid, vm);
+                        continue;
+                    }
+                };
+                info!(
+                    "udev attach retries: {:?}:{:?} -> {:?}",
+                    filter.vid, filter.pid, retries
+                );
+                stored.max_attach_retries = retries;
+                if let Some(usb_filters) = state.filters.get_mut(&vm) {
+                    usb_filters.insert(stored);
+                }
+                filters_changed = true;
+            }
+            Command::Move {
+                from,
+                to,
+                filter,
+                reply,
+            } => {
+                let _ = reply.send(move_devices(conn, state, &from, &to, &filter));
+            }
+            Command::ReloadFilters(reply) => {
+                let reply_text = match reload_filters(conn, state) {
+                    Ok(result) => result.to_json(),
+                    Err(e) => {
+                        warn!("{}", e);
+                        format!("ERROR: {}", e)
+                    }
+                };
+                let _ = reply.send(reply_text);
+            }
+            Command::Drain => {
+                if state.draining.is_none() {
+                    info!("Draining: no new attaches, existing devices stay attached.");
+                    state.draining = Some(Instant::now());
+                }
+            }
+            Command::Remove { vm, filter } => {
+                remove_filter(state, &vm, &filter);
+                filters_changed = true;
+            }
+        }
+    }
+    if filters_changed {
+        save_filters(state);
+    }
+    running
+}
+
+/// Register `filter` for `vm`.  Returns whether it wasn't registered
+/// already.
+fn add_filter(state: &mut State, vm: String, filter: UsbFilter) -> bool {
+    if let Some(recorder) = state.recorder.as_mut() {
+        recorder.write("filter-add", &vm, &filter.to_fields());
+    }
+    let usb_filters = state.filters.entry(vm).or_default();
+    if usb_filters.contains(&filter) {
+        return false;
+    }
+    info!("udev add: {:?}:{:?}", filter.vid, filter.pid);
+    usb_filters.insert(filter);
+    true
+}
+
+/// Write the registered filters to the `--state-file`, if there is one.  A
+/// failed write is reported but doesn't stop the daemon.  A dry run reads
+/// the file on startup but never writes it.
+fn save_filters(state: &State) {
+    let path = match state.state_file.as_deref() {
+        Some(p) if !state.dry_run => p,
+        _ => return,
+    };
+    let filters = state.filters.iter().flat_map(|(vm, usb_filters)| {
+        usb_filters
+            .iter()
+            .map(move |f| (vm.as_str(), f.to_state_fields()))
+    });
+    if let Err(e) = store::save(path, filters) {
+        warn!("failed to save filters to {}: {}", path.display(), e);
+    }
+}
+
+/// Register the filters saved in the `--state-file`, if there is one.  A
+/// missing or unreadable file is reported and leaves the filters empty.
+fn load_filters(conn: &dyn Hypervisor, state: &mut State) {
+    let path = match state.state_file.clone() {
+        Some(p) => p,
+        None => return,
+    };
+    match store::load(&path) {
+        Ok(saved) => {
+            info!("Loaded {} filter(s) from {}", saved.len(), path.display());
+            for (vm, fields) in saved {
+                let filter = match state.legacy_match {
+                    true => UsbFilter::from_fields(&fields),
+                    _ => UsbFilter::from_fields(&fields).canonicalized(),
+                };
+                // Saved by name, or while the VM wasn't defined yet.
+                let vm = match filter.templated_vm {
+                    true => vm,
+                    _ => vm_key(conn, &vm),
+                };
+                add_filter(state, vm, filter);
+            }
+            orphaned_filters(conn, state);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!(
+                "no saved filters at {}, starting without any",
+                path.display()
+            );
+        }
+        Err(e) => warn!("ignoring saved filters in {}: {}", path.display(), e),
+    }
+}
+
+/// Warn about each registered filter whose VM isn't defined, such as one
+/// saved before the VM was deleted, and return how many there are.  They
+/// are kept, since the VM may be defined again.  Wildcard and templated
+/// filters don't name a VM, so are never orphaned.
+fn orphaned_filters(conn: &dyn Hypervisor, state: &State) -> usize {
+    let mut orphaned = 0;
+    for (vm, usb_filters) in &state.filters {
+        if vm == WILDCARD_VM {
+            continue;
+        }
+        let named: Vec<&UsbFilter> = usb_filters.iter().filter(|f| !f.templated_vm).collect();
+        if named.is_empty() || conn.lookup(vm).is_ok() {
+            continue;
+        }
+        orphaned += named.len();
+        for filter in named {
+            warn!(
+                "filter {} is for vm {}, which doesn't exist",
+                filter_json(None, filter),
+                vm
+            );
+        }
+    }
+    if orphaned > 0 {
+        warn!(
+            "{} saved filter(s) are for VMs that don't exist, keeping them in case the VMs come back",
+            orphaned
+        );
+    }
+    orphaned
+}
+
+/// Register the rules from the `--config` file.  They are added on top of
+/// the saved filters, and can be removed over D-Bus like any other until
+/// the next restart.
+fn add_static_filters(conn: &dyn Hypervisor, state: &mut State) {
+    let rules = state.args.rules.clone();
+    if rules.is_empty() {
+        return;
+    }
+    info!("Adding {} rule(s) from the config file", rules.len());
+    for (vm, filter) in rules {
+        let (vm, filter) = static_filter(conn, state, &vm, filter);
+        add_filter(state, vm, filter);
+    }
+}
+
+/// A `--config` rule's VM and filter as they are registered.
+fn static_filter(
+    conn: &dyn Hypervisor,
+    state: &State,
+    vm: &str,
+    mut filter: UsbFilter,
+) -> (String, UsbFilter) {
+    filter.serial = filter.serial.map(|s| state.serial_norm.apply(&s));
+    (vm_key(conn, vm), filter)
+}
+
+/// What applying a changed set of filters did: the filters added and
+/// removed, by VM, and each attach and detach that followed.
+#[derive(Default)]
+struct ApplyResult {
+    added: Vec<(String, UsbFilter)>,
+    removed: Vec<(String, UsbFilter)>,
+    /// Each device's syspath, VM, and `ok`, `pending`, `failed` or
+    /// `ERROR: <reason>`.
+    attached: Vec<(PathBuf, String, String)>,
+    detached: Vec<(PathBuf, String, String)>,
+}
+
+impl ApplyResult {
+    /// As one JSON object:
+    ///
+    /// ```json
+    /// {"added":[{"vm":"<vm>","vid":"1050","pid":"0407"}],"removed":[],
+    ///  "attached":[{"vm":"<vm>","syspath":"...","result":"ok"}],"detached":[]}
+    /// ```
+    fn to_json(&self) -> String {
+        let filters = |filters: &[(String, UsbFilter)]| -> String {
+            let objects: Vec<String> = filters
+                .iter()
+                .map(|(vm, filter)| filter_json(Some(vm), filter))
+                .collect();
+            objects.join(",")
+        };
+        let actions = |actions: &[(PathBuf, String, String)]| -> String {
+            let objects: Vec<String> = actions
+                .iter()
+                .map(|(syspath, vm, result)| {
+                    format!(
+                        "{{\"vm\":{},\"syspath\":{},\"result\":{}}}",
+                        json_str(vm),
+                        json_str(&syspath.to_string_lossy()),
+                        json_str(result)
+                    )
+                })
+                .collect();
+            objects.join(",")
+        };
+        format!(
+            "{{\"added\":[{}],\"removed\":[{}],\"attached\":[{}],\"detached\":[{}]}}",
+            filters(&self.added),
+            filters(&self.removed),
+            actions(&self.attached),
+            actions(&self.detached)
+        )
+    }
+}
+
+/// Read the `--config` file again and apply the rules that changed, for
+/// SIGHUP and `ReloadFilters` alike.  A rule no longer in the file is
+/// unregistered, and the devices it matched that no other filter for its
+/// VM matches are detached.  A new rule is registered and the present
+/// devices it matches are attached.  Filters added over D-Bus are left
+/// alone, and `[auth]` is only read at startup.  Fails, with the old rules
+/// kept, if the file can't be read.
+fn reload_filters(conn: &dyn Hypervisor, state: &mut State) -> Result<ApplyResult, String> {
+    let path = match state.args.config.clone() {
+        Some(p) => p,
+        None => return Err("no --config file to reload".to_owned()),
+    };
+    let rules = match config::load(&path) {
+        Ok(config) => config.rules,
+        Err(e) => return Err(format!("can't load config {}: {}", path.display(), e)),
+    };
+    let old = std::mem::replace(&mut state.args.rules, rules.clone());
+    let mut result = ApplyResult::default();
+    for (vm, filter) in old.iter().filter(|r| !rules.contains(r)) {
+        let (vm, filter) = static_filter(conn, state, vm, filter.clone());
+        remove_filter(state, &vm, &filter);
+        let others: Vec<UsbFilter> = state
+            .filters
+            .get(&vm)
+            .map(|usb_filters| usb_filters.iter().filter(|f| f.enabled).cloned().collect())
+            .unwrap_or_default();
+        let detached = detach_where(conn, state, &vm, |device| {
+            filter.matches(device) && !others.iter().any(|f| f.matches(device))
+        });
+        for (syspath, _, error) in detached {
+            let outcome = error.map_or("ok".to_owned(), |e| format!("ERROR: {}", e));
+            result.detached.push((syspath, vm.clone(), outcome));
+        }
+        result.removed.push((vm, filter));
+    }
+    // Devices already attached, pending or failed, which adding filters
+    // doesn't touch.
+    let known: HashSet<PathBuf> = state
+        .sysdevs
+        .keys()
+        .chain(state.pending_attaches.keys())
+        .chain(state.failed_attaches.keys())
+        .cloned()
+        .collect();
+    let mut vms = BTreeSet::new();
+    for (vm, filter) in rules.into_iter().filter(|r| !old.contains(r)) {
+        let (vm, filter) = static_filter(conn, state, &vm, filter);
+        add_filter(state, vm.clone(), filter.clone());
+        vms.insert(vm.clone());
+        result.added.push((vm, filter));
+    }
+    // A recording has no live devices to pick up.
+    if state.source != "replay" {
+        for vm in &vms {
+            attach_present(conn, state, Some(vm));
+        }
+    }
+    let new = |syspath: &&PathBuf| !known.contains(*syspath);
+    for (syspath, sysdev) in state.sysdevs.iter().filter(|(p, _)| new(p)) {
+        let action = (syspath.clone(), sysdev.vm.clone(), "ok".to_owned());
+        result.attached.push(action);
+    }
+    let unattached = [
+        ("pending", &state.pending_attaches),
+        ("failed", &state.failed_attaches),
+    ];
+    for (outcome, attaches) in unattached {
+        for (syspath, attach) in attaches.iter().filter(|(p, _)| new(p)) {
+            let action = (syspath.clone(), attach.vm.clone(), outcome.to_owned());
+            result.attached.push(action);
+        }
+    }
+    if !result.added.is_empty() || !result.removed.is_empty() {
+        save_filters(state);
+    }
+    info!(
+        "Reloaded {}: {} rule(s) added, {} removed; {} device(s) attached, {} detached",
+        path.display(),
+        result.added.len(),
+        result.removed.len(),
+        result.attached.len(),
+        result.detached.len()
+    );
+    Ok(result)
+}
+
+/// Unregister `filter` for `vm`, if it is registered.
+fn remove_filter(state: &mut State, vm: &str, filter: &UsbFilter) {
+    if let Some(recorder) = state.recorder.as_mut() {
+        recorder.write("filter-remove", vm, &filter.to_fields());
+    }
+    if let Some(usb_filters) = state.filters.get_mut(vm) {
+        if usb_filters.remove(filter) {
+            info!("udev rem: {:?}:{:?}", filter.vid, filter.pid);
+        }
+    }
+}
+
+/// A live udev or scan event for the `usb_device` at `syspath`.
+///
+/// An added device is only matched once it has stayed for the `debounce`
+/// window (see `flush_debounced`), so that a device that enumerates in a
+/// burst of adds and removes isn't attached while it is about to vanish.
+/// A removal cancels a pending add.  It is handled at once, unless the
+/// device is attached and `unplug_grace` is set: then it is detached only
+/// if it hasn't come back at the same syspath by the end of the grace
+/// period (see `flush_unplugged`).
+fn device_event(conn: &dyn Hypervisor, state: &mut State, added: bool, syspath: &Path) {
+    retry_pending(conn, state);
+    if added {
+        // Matched as usual once settled: the attachment is kept if the
+        // device number is the same, else the stale one is replaced.
+        if state.unplugging.remove(syspath).is_some() {
+            info!(
+                "Syspath: {} came back within the unplug grace period",
+                syspath.display()
+            );
+        }
+        match state.debounce.is_zero() {
+            true => device_settled(conn, state, syspath),
+            // Every add restarts the window.
+            _ => {
+                state.debouncing.insert(syspath.to_owned(), Instant::now());
+            }
+        }
+        return;
+    }
+    if state.debouncing.remove(syspath).is_some() {
+        debug!("Syspath: {} went away before settling", syspath.display());
+    }
+    if let Some(recorder) = state.recorder.as_mut() {
+        recorder.write("remove", &syspath.to_string_lossy(), &BTreeMap::new());
+    }
+    if !state.unplug_grace.is_zero() && state.sysdevs.contains_key(syspath) {
+        debug!(
+            "Syspath: {} went away, detaching it in {:?} unless it comes back",
+            syspath.display(),
+            state.unplug_grace
+        );
+        state.unplugging.insert(syspath.to_owned(), Instant::now());
+        return;
+    }
+    device_removed(conn, state, syspath);
+}
+
+/// Detach the devices that stayed away for the whole `unplug_grace`.
+fn flush_unplugged(conn: &dyn Hypervisor, state: &mut State) {
+    let gone: Vec<PathBuf> = state
+        .unplugging
+        .iter()
+        .filter(|(_, since)| since.elapsed() >= state.unplug_grace)
+        .map(|(syspath, _)| syspath.clone())
+        .collect();
+    for syspath in gone {
+        state.unplugging.remove(&syspath);
+        device_removed(conn, state, &syspath);
+    }
+}
+
+/// Match the devices that have waited out the `debounce` window.  Called
+/// every poll cycle.
+fn flush_debounced(conn: &dyn Hypervisor, state: &mut State) {
+    let settled: Vec<PathBuf> = state
+        .debouncing
+        .iter()
+        .filter(|(_, since)| since.elapsed() >= state.debounce)
+        .map(|(syspath, _)| syspath.clone())
+        .collect();
+    for syspath in settled {
+        state.debouncing.remove(&syspath);
+        device_settled(conn, state, &syspath);
+    }
+}
+
+/// Match a plugged device that has settled.  Reads the device's attributes
+/// and records the add if `--record` is on.
+fn device_settled(conn: &dyn Hypervisor, state: &mut State, syspath: &Path) {
+    let attrs = read_sysfs_attrs(syspath);
+    if let Some(recorder) = state.recorder.as_mut() {
+        recorder.write("add", &syspath.to_string_lossy(), &attrs);
+    }
+    if !device_added(conn, state, syspath, &attrs, None) {
+        unmatched_device(state, syspath, &attrs);
+    }
+}
+
+/// How long a device that matched no filter isn't reported again at the
+/// same syspath, so that one plug seen several times is reported once.
+const UNMATCHED_QUIET: Duration = Duration::from_secs(10);
+
+/// Report a plugged USB device that no filter matched, in the log and as
+/// an `UnmatchedDevice` signal, unless it was just reported.
+fn unmatched_device(state: &mut State, syspath: &Path, attrs: &SysfsAttrs) {
+    if sysfs_attr(attrs, "subsystem") == Some("pci") {
+        return;
+    }
+    let id = |attr| {
+        sysfs_attr(attrs, attr).map_or(String::new(), |id| {
+            canon_id(id).unwrap_or_else(|| id.to_owned())
+        })
+    };
+    let device = [
+        id("idVendor"),
+        id("idProduct"),
+        attrs
+            .get("serial")
+            .map(|s| state.serial_norm.apply(s))
+            .unwrap_or_default(),
+    ];
+    state
+        .unmatched
+        .retain(|_, (at, _)| at.elapsed() < UNMATCHED_QUIET);
+    if state
+        .unmatched
+        .get(syspath)
+        .is_some_and(|(_, seen)| *seen == device)
+    {
+        debug!("Syspath: {} still matches no filter", syspath.display());
+        return;
+    }
+    let [vid, pid, serial] = &device;
+    info!(
+        "Syspath: {} ({}:{}, serial {:?}) matches no filter",
+        syspath.display(),
+        vid,
+        pid,
+        serial
+    );
+    if let Some(signals) = state.dbus_signals.as_ref() {
+        let _ = signals.send(DeviceSignal::unmatched(vid, pid, serial, syspath));
+    }
+    state
+        .unmatched
+        .insert(syspath.to_owned(), (Instant::now(), device));
+}
+
+/// Catch up on udev events a monitor may have missed: detach attached
+/// devices that have gone, and attach present ones that match.
+fn resync(conn: &dyn Hypervisor, state: &mut State) {
+    let gone: Vec<PathBuf> = state
+        .sysdevs
+        .keys()
+        .filter(|p| !p.exists())
+        .cloned()
+        .collect();
+    for syspath in gone {
+        info!("Syspath: {} went away unnoticed", syspath.display());
+        device_event(conn, state, false, &syspath);
+    }
+    if !state.filters.is_empty() {
+        attach_present(conn, state, None);
+    }
+}
+
+/// Attach every already-plugged device that `only_vm`'s filters match, or
+/// every VM's without it, so that a device doesn't have to be replugged
+/// after its filter is added.  Devices a VM already holds are left alone,
+/// so this is safe to repeat.
+///
+/// Returns `false` if the present devices couldn't be enumerated.
+fn attach_present(conn: &dyn Hypervisor, state: &mut State, only_vm: Option<&str>) -> bool {
+    let present = match scan_devices(has_pci_filters(state)) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("failed to scan USB devices: {}", e);
+            return false;
+        }
+    };
+    for syspath in present {
+        let attrs = read_sysfs_attrs(&syspath);
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.write("add", &syspath.to_string_lossy(), &attrs);
+        }
+        device_added(conn, state, &syspath, &attrs, only_vm);
+    }
+    true
+}
+
+/// A VM started, stopped or was (re)defined.  Its cached domain handle is
+/// dropped in any case.  When one with filters starts, present devices its
+/// filters match are attached.  A stopped VM has lost its devices, so they
+/// are forgotten without detaching and can go to other VMs.
+fn vm_lifecycle(conn: &dyn Hypervisor, state: &mut State, event: Lifecycle) {
+    state.domains.forget(&event.uuid);
+    state.domains.forget(&event.name);
+    // Keyed by UUID, unless the VM didn't exist when its filter was added.
+    let key = [event.uuid.clone(), event.name.clone()]
+        .into_iter()
+        .find(|k| state.filters.contains_key(k) || state.xmls.contains_key(k));
+    retry_pending(conn, state);
+    if matches!(event.change, Change::Defined) {
+        return;
+    }
+    if let Some(key) = key {
+        vm_started_or_stopped(conn, state, &event, key);
+    }
+    // Devices for whichever VM is running may have somewhere to go now.
+    if state.filters.contains_key(WILDCARD_VM) {
+        attach_present(conn, state, Some(WILDCARD_VM));
+    }
+}
+
+/// Attach the devices of the VM with filters under `key` that started, or
+/// forget those of one that stopped.
+fn vm_started_or_stopped(conn: &dyn Hypervisor, state: &mut State, event: &Lifecycle, key: String) {
+    if matches!(event.change, Change::Started) {
+        info!("vm {} started, attaching its devices", event.name);
+        attach_present(conn, state, Some(&key));
+        return;
+    }
+    info!("vm {} stopped, forgetting its devices", event.name);
+    // Its USB controllers may be different by the next start.
+    state.usb_capable.remove(&key);
+    forget_devices(state, &key);
+}
+
+/// Forget the devices `key` held, which it let go of by stopping.  They
+/// are attached again by `attach_present` when it starts.
+fn forget_devices(state: &mut State, key: &str) {
+    for (syspath, _) in state.xmls.remove(key).unwrap_or_default() {
+        let device = match state.sysdevs.remove(&syspath) {
+            Some(d) => d.device,
+            None => continue,
+        };
+        let vid = device.vid.as_deref().unwrap_or("");
+        let pid = device.pid.as_deref().unwrap_or("");
+        state.metrics.detaches += 1;
+        if let Some(audit) = state.audit.as_mut() {
+            audit.write("detach", key, &device, &syspath, Outcome::VmStopped);
+        }
+        if let Some(events) = state.events.as_mut() {
+            events.send("detached", key, vid, pid, &syspath);
+        }
+        if let Some(signals) = state.dbus_signals.as_ref() {
+            let _ = signals.send(DeviceSignal::new("DeviceDetached", key, vid, pid, &syspath));
+        }
+    }
+}
+
+/// Apply one event from a `--replay` recording.
+fn replay_record(conn: &dyn Hypervisor, state: &mut State, record: Record) {
+    match record.kind.as_str() {
+        "add" => {
+            device_added(
+                conn,
+                state,
+                Path::new(&record.subject),
+                &record.fields,
+                None,
+            );
+        }
+        "remove" => {
+            device_removed(conn, state, Path::new(&record.subject));
+        }
+        "filter-add" | "filter-remove" => {
+            let filter = match state.legacy_match {
+                true => UsbFilter::from_fields(&record.fields),
+                _ => UsbFilter::from_fields(&record.fields).canonicalized(),
+            };
+            match record.kind.as_str() {
+                "filter-add" => {
+                    add_filter(state, record.subject, filter);
+                }
+                _ => remove_filter(state, &record.subject, &filter),
+            }
+        }
+        kind => warn!("skipping unknown recorded event '{}'", kind),
+    }
+}
+
+/// A `usb_device` at `syspath`, with sysfs attributes `attrs`, appeared:
+/// attach it to every VM with a matching filter.
+///
+/// With `only_vm`, only that VM's filters are considered.
+///
+/// Returns `false` if it was matched against the filters and none matched,
+/// rather than being skipped before matching.
+fn device_added(
+    conn: &dyn Hypervisor,
+    state: &mut State,
+    syspath: &Path,
+    attrs: &SysfsAttrs,
+    only_vm: Option<&str>,
+) -> bool {
+    if state.draining.is_some() {
+        info!("Draining, not attaching syspath: {}", syspath.display());
+        return true;
+    }
+    let minute = utc_minute_of_day();
+    if state.suppress_windows.iter().any(|w| w.contains(minute)) {
+        info!(
+            "In a suppression window, not attaching syspath: {}",
+            syspath.display()
+        );
+        return true;
+    }
+    // PCI devices are found by their address, which is their syspath's
+    // last component, rather than a USB bus and device number.
+    let pci = sysfs_attr(attrs, "subsystem") == Some("pci");
+    let (usb_bus, usb_dev) = match pci {
+        true => ("", ""),
+        _ => match bus_address(attrs) {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("skipping syspath: {}: {}", syspath.display(), e);
+                return true;
+            }
+        },
+    };
+    // udev may report one plug more than once, which the checks below
+    // skip as already attached.  The same syspath at a new device number
+    // is a replug whose removal was missed, though: the attachment held
+    // for it is stale, so drop it before matching the new plug.
+    let replugged = state.sysdevs.get(syspath).is_some_and(|d| {
+        !pci && (d.address.0.as_str(), d.address.1.as_str()) != (usb_bus, usb_dev)
+    });
+    if replugged {
+        info!(
+            "Syspath: {} was replugged as {}:{} without a removal, detaching it first",
+            syspath.display(),
+            usb_bus,
+            usb_dev
+        );
+        device_removed(conn, state, syspath);
+    }
+    // Some virtual devices have no VID/PID at all.  They can still be
+    // matched by fingerprint, just never by a VID/PID filter.
+    let read_id = |attr| {
+        sysfs_attr(attrs, attr).map(|id| match state.legacy_match && !pci {
+            true => id.to_owned(),
+            _ => canon_id(id).unwrap_or_else(|| id.to_owned()),
+        })
+    };
+    let usb_vid = read_id(if pci { "vendor" } else { "idVendor" });
+    let usb_pid = read_id(if pci { "device" } else { "idProduct" });
+    if usb_vid.is_none() || usb_pid.is_none() {
+        debug!(
+            "Syspath: {} has no VID/PID, only fingerprint filters can match it",
+            syspath.display()
+        );
+    }
+    // Composite devices report 00 and leave the class to their interfaces.
+    let device_class = sysfs_attr(attrs, "bDeviceClass")
+        .and_then(canon_class)
+        .filter(|c| c != "00");
+    let sysname = syspath.file_name().and_then(|n| n.to_str());
+    let usb_filter = match pci {
+        true => UsbFilter {
+            vid: usb_vid.clone(),
+            pid: usb_pid.clone(),
+            pci: true,
+            pci_address: sysname.and_then(canon_pci_address),
+            ..Default::default()
+        },
+        _ => UsbFilter {
+            vid: usb_vid.clone(),
+            pid: usb_pid.clone(),
+            manufacturer: sysfs_attr(attrs, "manufacturer").map(str::to_owned),
+            product: sysfs_attr(attrs, "product").map(str::to_owned),
+            serial: attrs.get("serial").map(|s| state.serial_norm.apply(s)),
+            driver: attrs.get("drivers").cloned(),
+            port: sysname.filter(|n| is_port_path(n)).map(str::to_owned),
+            class: device_class.clone(),
+            subclass: device_class
+                .and_then(|_| sysfs_attr(attrs, "bDeviceSubClass").and_then(canon_class)),
+            speed: sysfs_attr(attrs, "speed")
+                .map(|s| canon_speed(s).unwrap_or_else(|| s.to_owned())),
+            ..Default::default()
+        },
+    };
+    if let (Some(limit), Some(draw)) = (state.max_power, max_power_ma(attrs)) {
+        if draw > limit {
+            info!(
+                "Skipping syspath: {} draws {}mA, over the {}mA limit",
+                syspath.display(),
+                draw,
+                limit
+            );
+            return true;
+        }
+    }
+    let mut matched = false;
+    // VMs this device has been attached to so far, so that a templated
+    // filter and a plain one resolving to the same VM attach it only once.
+    let mut attached_to = HashSet::new();
+    // Wildcard filters come last, so that they only take devices no filter
+    // for a particular VM wants.
+    let filters = state
+        .filters
+        .iter()
+        .filter(|(vm, _)| *vm != WILDCARD_VM)
+        .chain(state.filters.get_key_value(WILDCARD_VM));
+    for (vm, vm_filter) in filters {
+        if only_vm.is_some_and(|only| only != vm) {
+            continue;
+        }
+        let mut matching: Vec<&UsbFilter> = vm_filter
+            .iter()
+            .filter(|f| f.enabled && f.matches(&usb_filter))
+            .collect();
+        matching.sort_by_key(|f| std::cmp::Reverse(f.specificity()));
+        let filter = match matching.first() {
+            Some(f) => *f,
+            None => continue,
+        };
+        matched = true;
+        if matching.len() > 1 {
+            debug!(
+                "{} filters for vm {} match syspath: {}, attaching once for {:?}:{:?}",
+                matching.len(),
+                vm,
+                syspath.display(),
+                filter.vid,
+                filter.pid
+            );
+        }
+        let confirm = filter.confirm;
+        let placement = filter.placement();
+        let max_retries = filter.max_attach_retries;
+        // Retried through this filter rather than the VM it resolves to,
+        // which a templated or wildcard filter isn't keyed by.
+        let key = vm.clone();
+        let vm = match (vm.as_str(), filter.templated_vm) {
+            (WILDCARD_VM, _) if !attached_to.is_empty() => continue,
+            (WILDCARD_VM, _) => match wildcard_vm(conn, state, syspath) {
+                Some(uuid) => uuid,
+                None => {
+                    info!(
+                        "No VM is running to attach syspath: {} to",
+                        syspath.display()
+                    );
+                    continue;
+                }
+            },
+            (_, false) => vm.clone(),
+            (_, true) => match resolve_vm_template(vm, &usb_filter, usb_bus, usb_dev) {
+                Some(name) => {
+                    info!(
+                        "Resolved VM template {} to {} for syspath: {}",
+                        vm,
+                        name,
+                        syspath.display()
+                    );
+                    // Under the same key as a plain filter for that VM.
+                    match state.domains.get(conn, &name).and_then(|d| d.uuid()) {
+                        Ok(uuid) => uuid,
+                        Err(e) => {
+                            warn!("templated VM {} does not exist", name);
+                            state.errors.record(&name, format!("lookup failed: {}", e));
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    warn!(
+                        "can't resolve VM template {} for syspath: {}",
+                        vm,
+                        syspath.display()
+                    );
+                    continue;
+                }
+            },
+        };
+        // Matching again on bind, or a repeated add, must not reattach to
+        // VMs that have it.
+        if state
+            .xmls
+            .get(&vm)
+            .is_some_and(|x| x.iter().any(|(p, _)| p == syspath))
+        {
+            debug!(
+                "Syspath: {} is already attached to vm {}",
+                syspath.display(),
+                vm
+            );
+            continue;
+        }
+        if !attached_to.insert(vm.clone()) {
+            debug!(
+                "Syspath: {} is already attached to vm {} by another filter",
+                syspath.display(),
+                vm
+            );
+            continue;
+        }
+        // A device can only be passed through to one guest.  A VM that has
+        // stopped has already lost it, though, so it can go elsewhere.
+        if let Some(owner) = state.sysdevs.get(syspath).map(|d| d.vm.clone()) {
+            let owner_active = state.domains.get(conn, &owner).and_then(|d| d.is_active());
+            if owner_active.is_err() {
+                state.domains.forget(&owner);
+            }
+            if owner_active.unwrap_or(false) {
+                warn!(
+                    "syspath: {} matches vm {} too, but is already attached to vm {}",
+                    syspath.display(),
+                    vm,
+                    owner
+                );
+                continue;
+            }
+            info!(
+                "Releasing syspath: {} from vm {}, which isn't running",
+                syspath.display(),
+                owner
+            );
+            state.sysdevs.remove(syspath);
+            if let Some(owner_xmls) = state.xmls.get_mut(&owner) {
+                owner_xmls.retain(|(p, _)| p != syspath);
+            }
+        }
+        let vm = &vm;
+        info!(
+            "Adding syspath: {} for vm {} [VID:{} PID:{}]",
+            syspath.display(),
+            vm,
+            usb_vid.as_deref().unwrap_or("none"),
+            usb_pid.as_deref().unwrap_or("none")
+        );
+        let domain = match state.domains.get(conn, vm) {
+            Ok(domain) => domain,
+            Err(e) => {
+                warn!(
+                    "can't attach syspath: {} to vm {}: {}",
+                    syspath.display(),
+                    vm,
+                    e
+                );
+                state.errors.record(vm, format!("lookup failed: {}", e));
+                continue;
+            }
+        };
+        // A shut-off VM can't take devices.  Its filters stay, and the
+        // device is attached when the VM starts (see `vm_lifecycle`).
+        if !domain.is_active().unwrap_or(true) {
+            info!(
+                "vm {} isn't running, attaching syspath: {} when it starts",
+                vm,
+                syspath.display()
+            );
+            continue;
+        }
+        // Guests take PCI devices without a controller of their own.
+        let capable = pci
+            || *state.usb_capable.entry(vm.clone()).or_insert_with(|| {
+                // If the XML can't be read, let the attach itself report why.
+                let capable = domain.xml_desc().map_or(true, |x| accepts_usb_hostdev(&x));
+                if !capable {
+                    warn!(
+                        "VM {} has no hot-pluggable USB controller, not attaching devices to it",
+                        vm
+                    );
+                }
+                capable
+            });
+        if !capable {
+            continue;
+        }
+        if state
+            .failed_attaches
+            .get(syspath)
+            .is_some_and(|failed| failed.vm == *vm)
+        {
+            info!(
+                "Syspath: {} failed to attach to vm {} too often, skipping it until a Reattach or replug",
+                syspath.display(),
+                vm
+            );
+            continue;
+        }
+        resume_if_paused(&*domain, vm, state.resume_on_attach && !state.dry_run);
+        if state.breakers.get_mut(vm).is_some_and(|b| b.is_open(vm)) {
+            info!(
+                "Attaches to vm {} are paused, not attaching syspath: {}",
+                vm,
+                syspath.display()
+            );
+            let pending = PendingAttach {
+                filter: key,
+                vm: vm.clone(),
+                attempts: 0,
+            };
+            state.pending_attaches.insert(syspath.to_owned(), pending);
+            continue;
+        }
+        let xml = hostdev_xml(
+            conn,
+            state,
+            vm,
+            syspath,
+            &usb_filter,
+            (usb_bus, usb_dev),
+            placement,
+        );
+        if state.dry_run {
+            info!("Dry run, not attaching to vm {}:{}", vm, xml);
+        } else if let Err(e) = attach_with_retries(
+            &*domain,
+            &xml,
+            vm,
+            max_retries.unwrap_or(state.attach_retries),
+            state.verbose_xml,
+        ) {
+            let attempts = max_retries.unwrap_or(state.attach_retries) + 1;
+            match max_retries {
+                Some(_) => {
+                    warn!(
+                        "failed to attach syspath: {} to vm {} after {} attempt(s), marked failed until a Reattach or replug: {}",
+                        syspath.display(),
+                        vm,
+                        attempts,
+                        e
+                    );
+                    state.pending_attaches.remove(syspath);
+                    let failed = PendingAttach {
+                        filter: key,
+                        vm: vm.clone(),
+                        attempts,
+                    };
+                    state.failed_attaches.insert(syspath.to_owned(), failed);
+                }
+                None => {
+                    warn!(
+                        "failed to attach syspath: {} to vm {} after {} attempt(s), pending until the next event: {}",
+                        syspath.display(),
+                        vm,
+                        attempts,
+                        e
+                    );
+                    let pending = PendingAttach {
+                        filter: key,
+                        vm: vm.clone(),
+                        attempts,
+                    };
+                    state.pending_attaches.insert(syspath.to_owned(), pending);
+                }
+            }
+            state.metrics.attach_failures += 1;
+            state
+                .errors
+                .record(vm, format!("attach of {} failed: {}", syspath.display(), e));
+            if let Some(audit) = state.audit.as_mut() {
+                audit.write(
+                    "attach",
+                    vm,
+                    &usb_filter,
+                    syspath,
+                    Outcome::Failed(&e.to_string()),
+                );
+            }
+            state.breakers.entry(vm.clone()).or_default().failed(vm);
+            state.domains.forget(vm);
+            continue;
+        } else {
+            state.breakers.remove(vm);
+        }
+        state.pending_attaches.remove(syspath);
+        state.failed_attaches.remove(syspath);
+        if !state.xmls.contains_key(vm) {
+            state.xmls.insert(vm.to_owned(), Vec::new());
+        }
+        if confirm && !state.dry_run {
+            match state.confirm_hook.as_deref() {
+                Some(hook) => {
+                    let args = vec![
+                        vm.clone(),
+                        usb_vid.clone().unwrap_or_default(),
+                        usb_pid.clone().unwrap_or_default(),
+                        usb_bus.to_owned(),
+                        usb_dev.to_owned(),
+                    ];
+                    match spawn_confirm_hook(hook, &args) {
+                        Ok(child) => state.confirming.push(PendingConfirm {
+                            vm: vm.clone(),
+                            syspath: syspath.to_owned(),
+                            xml: xml.clone(),
+                            args,
+                            hook: child,
+                            deadline: Instant::now() + state.confirm_timeout,
+                            retries: 0,
+                        }),
+                        Err(e) => warn!("failed to run confirm hook {}: {}", hook.display(), e),
+                    }
+                }
+                None => warn!(
+                    "filter for vm {} wants confirmation but there is no --confirm-hook",
+                    vm
+                ),
+            }
+        }
+        if let Some(vm_xmls) = state.xmls.get_mut(vm) {
+            vm_xmls.push((syspath.to_owned(), xml));
+        }
+        state.sysdevs.insert(
+            syspath.to_owned(),
+            SysDev {
+                device: usb_filter.clone(),
+                vm: vm.clone(),
+                address: (usb_bus.to_owned(), usb_dev.to_owned()),
+            },
+        );
+        let vid = usb_vid.as_deref().unwrap_or("");
+        let pid = usb_pid.as_deref().unwrap_or("");
+        state.metrics.attaches += 1;
+        if let Some(audit) = state.audit.as_mut() {
+            let outcome = if state.dry_run {
+                Outcome::DryRun
+            } else {
+                Outcome::Done
+            };
+            audit.write("attach", vm, &usb_filter, syspath, outcome);
+        }
+        if let Some(events) = state.events.as_mut() {
+            events.send("attached", vm, vid, pid, syspath);
+        }
+        if let Some(signals) = state.dbus_signals.as_ref() {
+            let _ = signals.send(DeviceSignal::new("DeviceAttached", vm, vid, pid, syspath));
+        }
+    }
+    matched
+}
+
+/// Resume `domain` if it is paused and `resume` is set.  A VM the user
+/// paused is left alone otherwise; the attach is still attempted, and
+/// logged if libvirt refuses it.
+fn resume_if_paused(domain: &dyn Guest, vm: &str, resume: bool) {
+    if !domain.is_paused().unwrap_or(false) {
+        return;
+    }
+    if !resume {
+        info!(
+            "vm {} is paused, attaching anyway (see --resume-on-attach)",
+            vm
+        );
+        return;
+    }
+    match domain.resume() {
+        Ok(_) => info!("vm {} was paused, resumed it to attach a device", vm),
+        Err(e) => warn!("failed to resume paused vm {}, attaching anyway: {}", vm, e),
+    }
+}
+
+/// The hostdev XML that attaches `device`, observed at `syspath` and bus
+/// address `(bus, dev)`, to `vm`: from libvirt's node device with
+/// `--nodedev`, otherwise from the template, plus the boot order and guest
+/// address of `placement` if set.  The template is `placement`'s if the
+/// filter has one, else the global one.
+fn hostdev_xml(
+    conn: &dyn Hypervisor,
+    state: &State,
+    vm: &str,
+    syspath: &Path,
+    device: &UsbFilter,
+    (bus, dev): (&str, &str),
+    placement: Placement,
+) -> String {
+    if device.pci {
+        let xml = pci_xml(device.pci_address.as_deref().unwrap_or(""));
+        if placement.guest_address.is_some() {
+            warn!(
+                "guest USB addresses don't apply to PCI devices, ignoring it for vm {}",
+                vm
+            );
+        }
+        return match placement.boot_order {
+            Some(order) => with_boot_order(&xml, order).unwrap_or(xml),
+            None => xml,
+        };
+    }
+    let serial = device.serial.as_deref().unwrap_or("");
+    let template = placement
+        .xml_template
+        .as_deref()
+        .or(state.xml_template.as_deref());
+    let nodedev = match state.nodedev {
+        true => syspath
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| nodedev_xml(conn, n, template, serial)),
+        _ => None,
+    };
+    if state.nodedev && nodedev.is_none() {
+        debug!(
+            "No libvirt node device for syspath: {}, using sysfs",
+            syspath.display()
+        );
+    }
+    // Canonical even with `--legacy-match`, so libvirt always gets four
+    // lowercase digits after the `0x`.
+    let ids = (
+        device.vid.as_deref().and_then(canon_id),
+        device.pid.as_deref().and_then(canon_id),
+    );
+    let xml = nodedev.unwrap_or_else(|| match ids {
+        (Some(vid), Some(pid)) => usb_xml(template, &vid, &pid, bus, dev, serial),
+        // Without usable IDs libvirt can only find the device by address,
+        // and a custom template may depend on {vid}/{pid}.
+        _ => usb_xml(Some(USB_ADDRESS_XML_TEMPLATE), "", "", bus, dev, serial),
+    });
+    let xml = match with_host_address(&xml, bus, dev) {
+        Some(addressed) => {
+            debug!(
+                "hostdev XML for vm {} has no host address, adding {}:{}",
+                vm, bus, dev
+            );
+            addressed
+        }
+        None => xml,
+    };
+    let xml = match state.startup_policy.as_deref() {
+        Some(policy) => with_startup_policy(&xml, policy).unwrap_or_else(|| {
+            debug!(
+                "hostdev XML for vm {} already sets a startup policy, keeping it",
+                vm
+            );
+            xml
+        }),
+        None => xml,
+    };
+    let xml = match placement.guest_address {
+        Some((bus, port)) => with_guest_address(&xml, bus, port).unwrap_or_else(|| {
+            warn!(
+                "hostdev XML for vm {} already sets a guest address, keeping it",
+                vm
+            );
+            xml
+        }),
+        None => xml,
+    };
+    match placement.boot_order {
+        Some(order) => with_boot_order(&xml, order).unwrap_or_else(|| {
+            warn!(
+                "hostdev XML for vm {} already sets a boot order, keeping it",
+                vm
+            );
+            xml
+        }),
+        None => xml,
+    }
+}
+
+/// A `usb_device` at `syspath` went away: detach it from whichever VMs it
+/// was attached to, and return those VMs.
+fn device_removed(conn: &dyn Hypervisor, state: &mut State, syspath: &Path) -> Vec<String> {
+    let mut detached = Vec::new();
+    state.pending_attaches.remove(syspath);
+    state.failed_attaches.remove(syspath);
+    let device = match state.sysdevs.remove(syspath) {
+        Some(d) => d.device,
+        None => return detached,
+    };
+    info!("Removing syspath: {}", syspath.display());
+    for (vm, vm_xmls) in state.xmls.iter_mut() {
+        for (vm_syspath, xml_str) in vm_xmls.iter() {
+            if vm_syspath == syspath {
+                detached.push(vm.clone());
+                let mut error = None;
+                if state.dry_run {
+                    info!("Dry run, not detaching from vm {}:{}", vm, xml_str);
+                } else {
+                    let detach = state.domains.get(conn, vm).and_then(|domain| {
+                        hotplug(&*domain, false, vm, xml_str, state.verbose_xml)
+                    });
+                    if let Err(e) = detach {
+                        warn!("failed to hot-unplug from domain {}: {}", vm, e);
+                        state.domains.forget(vm);
+                        state
+                            .errors
+                            .record(vm, format!("detach of {} failed: {}", syspath.display(), e));
+                        error = Some(e.to_string());
+                    }
+                }
+                if let Some(audit) = state.audit.as_mut() {
+                    let outcome = match (state.dry_run, error.as_deref()) {
+                        (true, _) => Outcome::DryRun,
+                        (_, Some(e)) => Outcome::Failed(e),
+                        _ => Outcome::Done,
+                    };
+                    audit.write("detach", vm, &device, syspath, outcome);
+                }
+                let vid = device.vid.as_deref().unwrap_or("");
+                let pid = device.pid.as_deref().unwrap_or("");
+                state.metrics.detaches += 1;
+                if let Some(events) = state.events.as_mut() {
+                    events.send("detached", vm, vid, pid, syspath);
+                }
+                if let Some(signals) = state.dbus_signals.as_ref() {
+                    let _ =
+                        signals.send(DeviceSignal::new("DeviceDetached", vm, vid, pid, syspath));
+                }
+            }
+        }
+        vm_xmls.retain(|i| i.0 != syspath);
+    }
+    detached
+}
+
+/// Move every device attached to `from` that `filter` matches over to `to`,
+/// without waiting for it to be replugged, and return their syspaths.  A
+/// device that `to` refuses is given back to `from`.
+fn move_devices(
+    conn: &dyn Hypervisor,
+    state: &mut State,
+    from: &str,
+    to: &str,
+    filter: &UsbFilter,
+) -> String {
+    let (from, to) = (vm_key(conn, from), vm_key(conn, to));
+    let (from_domain, to_domain) =
+        match (state.domains.get(conn, &from), state.domains.get(conn, &to)) {
+            (Ok(f), Ok(t)) => (f, t),
+            (Err(_), _) => return format!("ERROR: no vm {}", from),
+            (_, Err(_)) => return format!("ERROR: no vm {}", to),
+        };
+    if from == to {
+        return format!("ERROR: already in vm {}", to);
+    }
+    let sysdevs = &state.sysdevs;
+    let moving: Vec<(PathBuf, String)> = state
+        .xmls
+        .get(&from)
+        .map(|x| {
+            x.iter()
+                .filter(|(p, _)| sysdevs.get(p).is_some_and(|d| filter.matches(&d.device)))
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    if moving.is_empty() {
+        return format!("ERROR: no matching device attached to vm {}", from);
+    }
+    let mut moved = Vec::new();
+    for (syspath, old_xml) in moving {
+        let device = match state.sysdevs.get(&syspath) {
+            Some(d) => d.device.clone(),
+            None => continue,
+        };
+        let attrs = read_sysfs_attrs(&syspath);
+        let address = match bus_address(&attrs) {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("can't move syspath: {}: {}", syspath.display(), e);
+                continue;
+            }
+        };
+        // The destination's own filter for the device sets the boot order
+        // and guest address.
+        let placement = state
+            .filters
+            .get(&to)
+            .and_then(|f| {
+                f.iter()
+                    .filter(|f| f.enabled && f.matches(&device))
+                    .max_by_key(|f| f.specificity())
+            })
+            .map(|f| f.placement())
+            .unwrap_or_default();
+        let xml = hostdev_xml(conn, state, &to, &syspath, &device, address, placement);
+        info!(
+            "Moving syspath: {} from vm {} to vm {}",
+            syspath.display(),
+            from,
+            to
+        );
+        if state.dry_run {
+            info!("Dry run, not detaching from vm {}:{}", from, old_xml);
+            info!("Dry run, not attaching to vm {}:{}", to, xml);
+        } else {
+            if let Err(e) = hotplug(&*from_domain, false, &from, &old_xml, state.verbose_xml) {
+                warn!("failed to hot-unplug from domain {}: {}", from, e);
+                state.errors.record(
+                    &from,
+                    format!("detach of {} failed: {}", syspath.display(), e),
+                );
+                if let Some(audit) = state.audit.as_mut() {
+                    audit.write(
+                        "detach",
+                        &from,
+                        &device,
+                        &syspath,
+                        Outcome::Failed(&e.to_string()),
+                    );
+                }
+                state.domains.forget(&from);
+                continue;
+            }
+            if let Some(audit) = state.audit.as_mut() {
+                audit.write("detach", &from, &device, &syspath, Outcome::Done);
+            }
+            if let Err(e) = hotplug(&*to_domain, true, &to, &xml, state.verbose_xml) {
+                warn!(
+                    "failed to attach syspath: {} to vm {}: {}",
+                    syspath.display(),
+                    to,
+                    e
+                );
+                state.metrics.attach_failures += 1;
+                state.errors.record(
+                    &to,
+                    format!("attach of {} failed: {}", syspath.display(), e),
+                );
+                if let Some(audit) = state.audit.as_mut() {
+                    audit.write(
+                        "attach",
+                        &to,
+                        &device,
+                        &syspath,
+                        Outcome::Failed(&e.to_string()),
+                    );
+                }
+                state.domains.forget(&to);
+                if let Err(e) = hotplug(&*from_domain, true, &from, &old_xml, state.verbose_xml) {
+                    warn!(
+                        "failed to give syspath: {} back to vm {}: {}",
+                        syspath.display(),
+                        from,
+                        e
+                    );
+                    device_removed(conn, state, &syspath);
+                }
+                continue;
+            }
+        }
+        if let Some(from_xmls) = state.xmls.get_mut(&from) {
+            from_xmls.retain(|(p, _)| *p != syspath);
+        }
+        state
+            .xmls
+            .entry(to.clone())
+            .or_default()
+            .push((syspath.clone(), xml));
+        if let Some(sysdev) = state.sysdevs.get_mut(&syspath) {
+            sysdev.vm = to.clone();
+        }
+        let vid = device.vid.as_deref().unwrap_or("");
+        let pid = device.pid.as_deref().unwrap_or("");
+        state.metrics.detaches += 1;
+        state.metrics.attaches += 1;
+        if let Some(audit) = state.audit.as_mut() {
+            match state.dry_run {
+                true => {
+                    audit.write("detach", &from, &device, &syspath, Outcome::DryRun);
+                    audit.write("attach", &to, &device, &syspath, Outcome::DryRun);
+                }
+                _ => audit.write("attach", &to, &device, &syspath, Outcome::Done),
+            }
+        }
+        if let Some(events) = state.events.as_mut() {
+            events.send("detached", &from, vid, pid, &syspath);
+            events.send("attached", &to, vid, pid, &syspath);
+        }
+        if let Some(signals) = state.dbus_signals.as_ref() {
+            let _ = signals.send(DeviceSignal::new(
+                "DeviceDetached",
+                &from,
+                vid,
+                pid,
+                &syspath,
+            ));
+            let _ = signals.send(DeviceSignal::new("DeviceAttached", &to, vid, pid, &syspath));
+        }
+        moved.push(syspath.to_string_lossy().into_owned());
+    }
+    match moved.is_empty() {
+        true => format!("ERROR: failed to move any device to vm {}", to),
+        _ => moved.join(","),
+    }
+}
+
+/// Detach from `vm` every attached device that `filter` matches, leaving
+/// the filter registered.  Returns how many devices were detached.
+fn detach_matching(
+    conn: &dyn Hypervisor,
+    state: &mut State,
+    vm: &str,
+    filter: &UsbFilter,
+) -> usize {
+    detach_where(conn, state, vm, |device| filter.matches(device)).len()
+}
+
+/// Detach from `vm` every attached device for which `detach` is true.  If
+/// the VM no longer exists, the devices are only forgotten.  Either way
+/// they are forgotten, as no removal will come for a device still plugged
+/// in.  Returns the syspath and device of each, with the error if libvirt
+/// refused to detach it.
+fn detach_where(
+    conn: &dyn Hypervisor,
+    state: &mut State,
+    vm: &str,
+    detach: impl Fn(&UsbFilter) -> bool,
+) -> Vec<(PathBuf, UsbFilter, Option<String>)> {
+    let mut results = Vec::new();
+    let vm_xmls = match state.xmls.get_mut(vm) {
+        Some(x) => x,
+        None => return results,
+    };
+    let sysdevs = &state.sysdevs;
+    let (matched, kept): (Vec<_>, Vec<_>) = vm_xmls
+        .drain(..)
+        .partition(|(syspath, _)| sysdevs.get(syspath).is_some_and(|d| detach(&d.device)));
+    *vm_xmls = kept;
+    let domain = match state.dry_run || matched.is_empty() {
+        true => None,
+        _ => state
+            .domains
+            .get(conn, vm)
+            .map_err(|e| info!("vm {} is gone, forgetting its devices: {}", vm, e))
+            .ok(),
+    };
+    for (syspath, xml_str) in &matched {
+        info!("Detaching syspath: {} from vm {}", syspath.display(), vm);
+        let mut error = None;
+        if state.dry_run {
+            info!("Dry run, not detaching from vm {}:{}", vm, xml_str);
+        } else if let Some(domain) = domain.as_ref() {
+            if let Err(e) = hotplug(&**domain, false, vm, xml_str, state.verbose_xml) {
+                warn!("failed to hot-unplug from domain {}: {}", vm, e);
+                state.domains.forget(vm);
+                state
+                    .errors
+                    .record(vm, format!("detach of {} failed: {}", syspath.display(), e));
+                error = Some(e.to_string());
+            }
+        }
+        if let Some(device) = state.sysdevs.get(syspath).map(|d| &d.device) {
+            if let Some(audit) = state.audit.as_mut() {
+                let outcome = match (state.dry_run, error.as_deref()) {
+                    (true, _) => Outcome::DryRun,
+                    (_, Some(e)) => Outcome::Failed(e),
+                    _ => Outcome::Done,
+                };
+                audit.write("detach", vm, device, syspath, outcome);
+            }
+            results.push((syspath.clone(), device.clone(), error));
+            let vid = device.vid.as_deref().unwrap_or("");
+            let pid = device.pid.as_deref().unwrap_or("");
+            state.metrics.detaches += 1;
+            if let Some(events) = state.events.as_mut() {
+                events.send("detached", vm, vid, pid, syspath);
+            }
+            if let Some(signals) = state.dbus_signals.as_ref() {
+                let _ = signals.send(DeviceSignal::new("DeviceDetached", vm, vid, pid, syspath));
+            }
+        }
+        // Forget the device once no VM holds it any more.
+        if !state.xmls.values().flatten().any(|(p, _)| p == syspath) {
+            state.sysdevs.remove(syspath);
+        }
+    }
+    results
+}
+
+/// Detach every device stormcrow has attached, from every VM.
+fn detach_all(conn: &dyn Hypervisor, state: &mut State) {
+    let syspaths: Vec<PathBuf> = state.sysdevs.keys().cloned().collect();
+    info!("Detaching {} device(s)...", syspaths.len());
+    for syspath in syspaths {
+        device_removed(conn, state, &syspath);
+    }
+}
+
+/// Attach again every device `vm` is believed to hold that its live XML
+/// doesn't have, as after libvirtd restarted and the guest lost them.  If
+/// the VM isn't running, its devices are forgotten as if it had stopped,
+/// to be attached when it starts.  Returns a line per device saying how
+/// that went.
+fn reattach(conn: &dyn Hypervisor, state: &mut State, vm: &str) -> Vec<String> {
+    let held = state.xmls.get(vm).cloned().unwrap_or_default();
+    if held.is_empty() {
+        return Vec::new();
+    }
+    let live = state
+        .domains
+        .get(conn, vm)
+        .and_then(|domain| match domain.is_active()? {
+            true => Ok(Some((domain.xml_desc()?, domain))),
+            _ => Ok(None),
+        });
+    let (domain_xml, domain) = match live {
+        Ok(Some(live)) => live,
+        Ok(None) => {
+            info!(
+                "vm {} isn't running, its devices will be attached when it starts",
+                vm
+            );
+            forget_devices(state, vm);
+            let line = |(syspath, _): &(PathBuf, String)| {
+                format!("{}: waiting for vm to start", syspath.display())
+            };
+            return held.iter().map(line).collect();
+        }
+        Err(e) => {
+            warn!("can't reattach devices to vm {}: {}", vm, e);
+            state.domains.forget(vm);
+            state.errors.record(vm, format!("reattach failed: {}", e));
+            let line =
+                |(syspath, _): &(PathBuf, String)| format!("{}: ERROR: {}", syspath.display(), e);
+            return held.iter().map(line).collect();
+        }
+    };
+    let mut lines = Vec::new();
+    for (syspath, xml) in held {
+        let sysdev = match state.sysdevs.get(&syspath) {
+            Some(sysdev) => sysdev,
+            None => continue,
+        };
+        let status = if !syspath.exists() {
+            // Its removal is on the way, and will forget it.
+            "unplugged".to_owned()
+        } else if has_hostdev(&domain_xml, sysdev) {
+            "present".to_owned()
+        } else if state.dry_run {
+            info!("Dry run, not reattaching to vm {}:{}", vm, xml);
+            "dry-run".to_owned()
+        } else {
+            info!("Reattaching syspath: {} to vm {}", syspath.display(), vm);
+            let device = sysdev.device.clone();
+            let result = hotplug(&*domain, true, vm, &xml, state.verbose_xml);
+            if let Some(audit) = state.audit.as_mut() {
+                let error = result.as_ref().err().map(ToString::to_string);
+                let outcome = error.as_deref().map_or(Outcome::Done, Outcome::Failed);
+                audit.write("attach", vm, &device, &syspath, outcome);
+            }
+            match result {
+                Ok(()) => {
+                    state.metrics.attaches += 1;
+                    "reattached".to_owned()
+                }
+                Err(e) => {
+                    state.errors.record(
+                        vm,
+                        format!("reattach of {} failed: {}", syspath.display(), e),
+                    );
+                    format!("ERROR: {}", e)
+                }
+            }
+        };
+        lines.push(format!("{}: {}", syspath.display(), status));
+    }
+    lines
+}
+
+/// Reattach, when `--reconcile-interval` says it is due, whatever every VM
+/// holding devices has lost.  Unlike a manual `Reattach` it leaves breakers
+/// alone, and it logs only what it had to correct.
+fn reconcile(conn: &dyn Hypervisor, state: &mut State) {
+    let interval = match (state.next_reconcile, state.args.reconcile_interval) {
+        (Some(at), Some(interval)) if Instant::now() >= at => interval,
+        _ => return,
+    };
+    state.next_reconcile = Some(Instant::now() + interval);
+    if state.dry_run {
+        return;
+    }
+    let vms: Vec<String> = state.xmls.keys().cloned().collect();
+    let reattached = vms
+        .iter()
+        .flat_map(|vm| reattach(conn, state, vm))
+        .filter(|line| line.ends_with(": reattached"))
+        .count();
+    if reattached > 0 {
+        info!("Reconciliation reattached {} device(s)", reattached);
+    }
+}
+
+/// How often the hypervisor connection is checked, and the first and
+/// longest waits between attempts to reopen it once it is lost.
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Check the hypervisor connection when it is due, and reopen it if
+/// libvirt stopped answering, as when libvirtd restarts.  Failed attempts
+/// back off, doubling the wait each time.  Once reconnected, domain
+/// handles from the old connection are dropped and devices the VMs lost
+/// are attached again, as for `Reattach`.  Returns how many were.
+fn check_connection(conn: &dyn Hypervisor, state: &mut State) -> usize {
+    let now = Instant::now();
+    if state.next_connection_check.is_none_or(|at| now < at) {
+        return 0;
+    }
+    if state.reconnect_delay.is_zero() {
+        if conn.is_alive() {
+            state.next_connection_check = Some(now + CONNECTION_CHECK_INTERVAL);
+            return 0;
+        }
+        warn!("Lost the hypervisor connection, reconnecting");
+        state.reconnect_delay = RECONNECT_DELAY;
+    }
+    if let Err(e) = conn.reconnect() {
+        warn!(
+            "can't reconnect to the hypervisor, trying again in {:?}: {}",
+            state.reconnect_delay, e
+        );
+        state.next_connection_check = Some(now + state.reconnect_delay);
+        state.reconnect_delay = (state.reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        return 0;
+    }
+    info!("Reconnected to the hypervisor");
+    state.metrics.reconnects += 1;
+    state.reconnect_delay = Duration::ZERO;
+    state.next_connection_check = Some(now + CONNECTION_CHECK_INTERVAL);
+    state.domains.clear();
+    let vms: Vec<String> = state.xmls.keys().cloned().collect();
+    let reattached = vms
+        .iter()
+        .flat_map(|vm| reattach(conn, state, vm))
+        .filter(|line| line.ends_with(": reattached"))
+        .count();
+    info!("Reconnect restored {} attachment(s)", reattached);
+    reattached
+}
+
+/// Whether `domain_xml` has a hostdev for `sysdev`: one whose source is at
+/// the same USB bus and device number, or the same PCI address.  Numbers
+/// are compared by value, as libvirt writes some in hex.
+fn has_hostdev(domain_xml: &str, sysdev: &SysDev) -> bool {
+    let number = |value: Option<&str>| {
+        let value = value?;
+        match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => value.parse::<u32>().ok(),
+        }
+    };
+    domain_xml
+        .match_indices("<hostdev ")
+        .filter_map(|(i, _)| {
+            let hostdev = &domain_xml[i..i + domain_xml[i..].find("</hostdev>")?];
+            let source = &hostdev[hostdev.find("<source")?..];
+            let source = &source[..source.find("</source>")?];
+            let start = source.find("<address ")?;
+            Some(&source[start..start + source[start..].find('>')?])
+        })
+        .any(|address| match sysdev.device.pci_address.as_deref() {
+            Some(pci_address) => {
+                let parts = ["domain", "bus", "slot", "function"]
+                    .map(|attr| number(element_attr(address, attr)));
+                match parts {
+                    [Some(domain), Some(bus), Some(slot), Some(function)] => {
+                        format!("{:04x}:{:02x}:{:02x}.{:x}", domain, bus, slot, function)
+                            == pci_address
+                    }
+                    _ => false,
+                }
+            }
+            None => {
+                let want = [&sysdev.address.0, &sysdev.address.1].map(|n| number(Some(n)));
+                want[0].is_some()
+                    && ["bus", "device"].map(|attr| number(element_attr(address, attr))) == want
+            }
+        })
+}
+
+/// Syspaths of every `usb_device` currently present, and of every PCI
+/// device too if `pci`.
+fn scan_devices(pci: bool) -> io::Result<HashSet<PathBuf>> {
+    let mut enumerator = udev::Enumerator::new()?;
+    enumerator.match_subsystem("usb")?;
+    enumerator.match_property("DEVTYPE", "usb_device")?;
+    let mut present: HashSet<PathBuf> = enumerator
+        .scan_devices()?
+        .map(|d| d.syspath().to_owned())
+        .collect();
+    if pci {
+        let mut enumerator = udev::Enumerator::new()?;
+        enumerator.match_subsystem("pci")?;
+        present.extend(enumerator.scan_devices()?.map(|d| d.syspath().to_owned()));
+    }
+    Ok(present)
+}
+
+/// Whether any VM has a PCI filter, so PCI devices need watching at all.
+fn has_pci_filters(state: &State) -> bool {
+    state.filters.values().flatten().any(|f| f.pci)
+}
+
+/// Where `poll` learns about USB devices coming and going.
+pub enum DeviceSource {
+    /// The live udev netlink monitor.
+    Monitor(udev::MonitorSocket),
+    /// For environments without a udev monitor: enumerate present devices
+    /// at this interval and diff against the previous scan.
+    Scan(Duration),
+    /// Events from a `--record` recording, in dry-run mode.  Replayed with
+    /// their recorded timing, or as fast as possible if `fast`.
+    Replay { records: Vec<Record>, fast: bool },
+    /// Only the devices present at startup, for `--once`.
+    Once,
+}
+
+/// Run the daemon until it is asked to stop.
+///
+/// Returns whether every attach of a `--once` run succeeded; the daemon
+/// itself always returns `true`.
+pub fn poll(
+    hypervisor: Box<dyn Hypervisor>,
+    source: DeviceSource,
+    mut receiver: wake::Receiver<Command>,
+    shutting_down: Arc<AtomicBool>,
+    snapshot: Arc<RwLock<Snapshot>>,
+    dbus_signals: Option<Sender<DeviceSignal>>,
+    args: Args,
+) -> io::Result<bool> {
+    let mut poll = Poll::new()?;
+    let mut events = Events::with_capacity(1024);
+
+    let mut state = State {
+        nodedev: args.nodedev,
+        serial_norm: args.serial_norm,
+        dry_run: args.dry_run || matches!(source, DeviceSource::Replay { .. }),
+        resume_on_attach: args.resume_on_attach,
+        verbose_xml: args.verbose_xml,
+        confirm_hook: args.confirm_hook.clone(),
+        suppress_windows: args.suppress_windows.clone(),
+        legacy_match: args.legacy_match,
+        source: match source {
+            DeviceSource::Monitor(_) => "monitor",
+            DeviceSource::Scan(_) => "scan",
+            DeviceSource::Replay { .. } => "replay",
+            DeviceSource::Once => "once",
+        },
+        confirm_timeout: args.confirm_timeout.unwrap_or(DEFAULT_CONFIRM_TIMEOUT),
+        debounce: args.debounce.unwrap_or(DEFAULT_DEBOUNCE),
+        unplug_grace: args.unplug_grace.unwrap_or_default(),
+        startup_policy: args.startup_policy.clone(),
+        attach_retries: args.attach_retries.unwrap_or(DEFAULT_ATTACH_RETRIES),
+        dbus_signals,
+        ..Default::default()
+    };
+    // Neither a replay nor a one-shot run may touch the daemon's filters.
+    if state.source != "replay" && state.source != "once" {
+        state.state_file = args.state_file.clone();
+    }
+    if args.dry_run {
+        info!("Dry run: attaches and detaches are logged, not performed");
+    }
+
+    if let Some(path) = args.record.as_ref() {
+        state.recorder = Some(Recorder::create(path)?);
+        info!("Recording events to {}", path.display());
+    }
+    if let Some(path) = args.audit_log.as_ref() {
+        state.audit = Some(AuditLog::open(path)?);
+        info!("Auditing attaches and detaches to {}", path.display());
+    }
+
+    let mut signals = Signals::install()?;
+    signals.register(poll.registry(), Token(2))?;
+    let mut notifier = Notifier::from_env();
+
+    if let Some(path) = args.event_socket.as_ref() {
+        let events = EventSocket::bind(path)?;
+        poll.registry()
+            .register(&mut SourceFd(&events.fd()), Token(1), Interest::READABLE)?;
+        info!("Serving events on {}", path.display());
+        state.events = Some(events);
+    }
+
+    let conn = &*hypervisor;
+    receiver.register(poll.registry(), Token(4))?;
+    let (lifecycle_tx, mut lifecycle_rx) = wake::channel::<Lifecycle>()?;
+    lifecycle_rx.register(poll.registry(), Token(5))?;
+    if let Err(e) = conn.watch_lifecycle(lifecycle_tx) {
+        warn!("can't watch VMs starting and stopping: {}", e);
+    }
+
+    let (socket, scan_interval, mut replay) = match source {
+        DeviceSource::Monitor(socket) => (Some(socket), None, None),
+        DeviceSource::Scan(interval) => (None, Some(interval), None),
+        DeviceSource::Replay { records, fast } => {
+            (None, None, Some((VecDeque::from(records), fast)))
+        }
+        DeviceSource::Once => (None, None, None),
+    };
+    state.args = Args {
+        scan_interval,
+        ..args.clone()
+    };
+    state.next_reconcile = args
+        .reconcile_interval
+        .map(|interval| Instant::now() + interval);
+    state.next_connection_check = Some(Instant::now() + CONNECTION_CHECK_INTERVAL);
+    let started = Instant::now();
+    let mut scanned = HashSet::<PathBuf>::new();
+    let mut next_scan = Instant::now();
+    // PCI devices rarely come and go, so their monitor is only opened
+    // once a PCI filter exists.
+    let mut pci_socket: Option<Monitor> = None;
+    let mut pci_monitor_failed = false;
+
+    load_filters(conn, &mut state);
+    add_static_filters(conn, &mut state);
+
+    if state.source == "once" {
+        let succeeded = attach_once(conn, &mut state);
+        if let Err(e) = hypervisor.close() {
+            warn!("failed to disconnect from hypervisor: {}", e);
+        }
+        return Ok(succeeded);
+    }
+
+    let mut socket = socket
+        .map(|socket| Monitor::new("USB", udev_monitor, socket, poll.registry(), Token(0)))
+        .transpose()?;
+    if socket.is_some() {
+        // The monitor only reports changes, so devices that matched a
+        // saved filter before the restart are picked up here.  The first
+        // scan finds them without the monitor.
+        if !state.filters.is_empty() {
+            attach_present(conn, &mut state, None);
+        }
+        info!("Polling udev monitor...");
+    }
+    if let Some(interval) = scan_interval {
+        info!("Scanning for USB devices every {:?}...", interval);
+    }
+    if let Some((records, _)) = replay.as_ref() {
+        info!("Replaying {} recorded event(s), dry run...", records.len());
+    }
+    publish(conn, &state, &snapshot);
+    // `main` only gets here once the D-Bus name, if any, is ours.
+    notifier.ready();
+
+    'event: loop {
+        // Sleep until the next event, or the next timer if that is sooner.
+        let replay_due = replay.as_ref().and_then(|(records, fast)| {
+            records.front().map(|r| {
+                if *fast {
+                    Instant::now()
+                } else {
+                    started + r.at
+                }
+            })
+        });
+        let due = [
+            next_timer(&state),
+            notifier.next_ping(),
+            scan_interval.map(|_| next_scan),
+            replay_due,
+            socket.as_ref().and_then(Monitor::next_retry),
+            pci_socket.as_ref().and_then(Monitor::next_retry),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let timeout = [
+            due.map(|due| due.saturating_duration_since(Instant::now())),
+            args.poll_timeout,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        poll.poll(&mut events, timeout)?;
+        notifier.watchdog();
+        if events.iter().any(|e| e.token() == Token(4)) {
+            receiver.clear();
+            if !drain_commands(&receiver, conn, &mut state) {
+                break 'event;
+            }
+        }
+        if events.iter().any(|e| e.token() == Token(2)) {
+            let received = signals.pending();
+            if received.reload {
+                info!("Caught SIGHUP, reloading the config file.");
+                if let Err(e) = reload_filters(conn, &mut state) {
+                    warn!("{}", e);
+                }
+            }
+            if received.quit {
+                info!("Caught SIGTERM or SIGINT.");
+                break 'event;
+            }
+        }
+
+        if let Some(subscribers) = state.events.as_mut() {
+            if events.iter().any(|e| e.token() == Token(1)) {
+                subscribers.accept();
+            }
+            subscribers.flush();
+        }
+        if let Some(usb) = socket.as_mut() {
+            let status = usb.check(&events, poll.registry());
+            if let Some(socket) = usb.socket().filter(|_| status != monitor::Status::Idle) {
+                for x in socket.iter() {
+                    let device = match owning_usb_device(x.device()) {
+                        Some(d) => d,
+                        None => {
+                            debug!(
+                                "Skipping non-USB device: {}",
+                                x.device().syspath().display()
+                            );
+                            continue;
+                        }
+                    };
+                    match x.event_type() {
+                        // Interface drivers are usually bound by the time the
+                        // device itself binds, so match again for driver filters.
+                        udev::EventType::Add | udev::EventType::Bind => {
+                            device_event(conn, &mut state, true, device.syspath())
+                        }
+                        udev::EventType::Remove => {
+                            device_event(conn, &mut state, false, device.syspath())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if usb.recover(poll.registry()) || status == monitor::Status::Lost {
+                resync(conn, &mut state);
+            }
+            if pci_socket.is_none() && !pci_monitor_failed && has_pci_filters(&state) {
+                match pci_monitor() {
+                    Ok(pci) => {
+                        pci_socket = Some(Monitor::new(
+                            "PCI",
+                            pci_monitor,
+                            pci,
+                            poll.registry(),
+                            Token(3),
+                        )?);
+                        debug!("Polling udev monitor for PCI devices");
+                    }
+                    Err(e) => {
+                        warn!(
+                            "can't monitor PCI devices, only present ones will be attached: {}",
+                            e
+                        );
+                        pci_monitor_failed = true;
+                    }
+                }
+            }
+        }
+        if let Some(pci) = pci_socket.as_mut() {
+            let status = pci.check(&events, poll.registry());
+            if let Some(socket) = pci.socket().filter(|_| status != monitor::Status::Idle) {
+                for x in socket.iter() {
+                    match x.event_type() {
+                        udev::EventType::Add => device_event(conn, &mut state, true, x.syspath()),
+                        udev::EventType::Remove => {
+                            device_event(conn, &mut state, false, x.syspath())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            if pci.recover(poll.registry()) || status == monitor::Status::Lost {
+                resync(conn, &mut state);
+            }
+        }
+
+        if let Some(interval) = scan_interval {
+            if Instant::now() >= next_scan {
+                next_scan = Instant::now() + interval;
+                match scan_devices(has_pci_filters(&state)) {
+                    Ok(present) => {
+                        for syspath in present.difference(&scanned) {
+                            device_event(conn, &mut state, true, syspath);
+                        }
+                        for syspath in scanned.difference(&present) {
+                            device_event(conn, &mut state, false, syspath);
+                        }
+                        scanned = present;
+                    }
+                    Err(e) => warn!("failed to scan devices: {}", e),
+                }
+            }
+        }
+
+        if let Some((records, fast)) = replay.as_mut() {
+            while records
+                .front()
+                .is_some_and(|r| *fast || started.elapsed() >= r.at)
+            {
+                if let Some(record) = records.pop_front() {
+                    replay_record(conn, &mut state, record);
+                }
+            }
+            if records.is_empty() {
+                info!("Replay finished.");
+                break 'event;
+            }
+        }
+
+        flush_debounced(conn, &mut state);
+        flush_unplugged(conn, &mut state);
+
+        if events.iter().any(|e| e.token() == Token(5)) {
+            lifecycle_rx.clear();
+            while let Ok(event) = lifecycle_rx.try_recv() {
+                vm_lifecycle(conn, &mut state, event);
+            }
+        }
+
+        check_connection(conn, &mut state);
+        check_confirmations(conn, &mut state);
+        reconcile(conn, &mut state);
+
+        if let (Some(since), Some(timeout)) = (state.draining, args.drain_timeout) {
+            if since.elapsed() >= timeout {
+                info!("Drain timeout of {:?} reached.", timeout);
+                break 'event;
+            }
+        }
+
+        // Pick up commands that arrived while udev events were being
+        // handled, rather than leaving them for the next poll cycle.
+        if !drain_commands(&receiver, conn, &mut state) {
+            break 'event;
+        }
+        publish(conn, &state, &snapshot);
+    }
+
+    // Teardown order: stop accepting commands, apply whatever was already
+    // queued, detach everything (unless --keep-attached), then drop the
+    // hypervisor connection.
+    info!("Shutting down by request.");
+    notifier.stopping();
+    shutting_down.store(true, Ordering::SeqCst);
+    shut_down(&receiver, conn, &mut state, args.keep_attached);
+    if let Err(e) = hypervisor.close() {
+        panic!("Failed to disconnect from hypervisor: {}", e);
+    }
+    Ok(true)
+}
+
+/// Apply the commands queued before shutdown, stop any confirm hooks and,
+/// unless `keep_attached`, detach every device.
+fn shut_down(
+    receiver: &wake::Receiver<Command>,
+    conn: &dyn Hypervisor,
+    state: &mut State,
+    keep_attached: bool,
+) {
+    drain_commands(receiver, conn, state);
+    for mut pending in state.confirming.drain(..) {
+        let _ = pending.hook.kill();
+    }
+    match keep_attached {
+        true => info!("Leaving {} device(s) attached.", state.sysdevs.len()),
+        _ => detach_all(conn, state),
+    }
+}
+
+/// Attach every present device the config rules match, print what
+/// happened to each on stdout, and return whether every attach succeeded.
+fn attach_once(conn: &dyn Hypervisor, state: &mut State) -> bool {
+    if state.filters.is_empty() {
+        warn!("--once has no rules to apply, see --config");
+        return false;
+    }
+    if !attach_present(conn, state, None) {
+        return false;
+    }
+    for (syspath, sysdev) in &state.sysdevs {
+        println!(
+            "attached {} [{}:{}] to vm {}",
+            syspath.display(),
+            sysdev.device.vid.as_deref().unwrap_or("none"),
+            sysdev.device.pid.as_deref().unwrap_or("none"),
+            sysdev.vm
+        );
+    }
+    for (syspath, pending) in &state.pending_attaches {
+        println!("failed {} for vm {}", syspath.display(), pending.vm);
+    }
+    println!(
+        "{} attached, {} failed",
+        state.sysdevs.len(),
+        state.pending_attaches.len()
+    );
+    state.pending_attaches.is_empty()
+}
+
+/// Hypervisor connection used unless `--connect` names another.
+const DEFAULT_URI: &str = "qemu:///system";
+/// Well-known D-Bus name, unless `--dbus-name` gives another.
+const DEFAULT_DBUS_NAME: &str = "com.stormcrow.device";
+
+/// How long `--confirm-hook` has to confirm an attach, by default.
+const DEFAULT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a plugged device must stay before it is matched, by default.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// How often the poll loop wakes while waiting on something it can't poll
+/// for: a confirm hook exiting, or an event client reading its backlog.
+const BUSY_INTERVAL: Duration = Duration::from_millis(200);
+
+/// When the poll loop next has something to do without an event: the
+/// earliest debounce, unplug, confirmation or drain timer in `state`, or
+/// `None` if nothing is waiting.
+fn next_timer(state: &State) -> Option<Instant> {
+    let debounces = state
+        .debouncing
+        .values()
+        .map(|since| *since + state.debounce);
+    let unplugs = state
+        .unplugging
+        .values()
+        .map(|since| *since + state.unplug_grace);
+    let confirms = state.confirming.iter().map(|pending| pending.deadline);
+    let busy =
+        !state.confirming.is_empty() || state.events.as_ref().is_some_and(|e| e.has_pending());
+    let busy = busy.then(|| Instant::now() + BUSY_INTERVAL);
+    let drain = state
+        .draining
+        .zip(state.args.drain_timeout)
+        .map(|(since, timeout)| since + timeout);
+    debounces
+        .chain(unplugs)
+        .chain(confirms)
+        .chain(busy)
+        .chain(drain)
+        .chain(state.next_reconcile)
+        .chain(state.next_connection_check)
+        .min()
+}
+
+/// How often to scan when falling back from the udev monitor.
+const DEFAULT_SCAN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Command-line options.
+#[derive(Clone, Default)]
+pub struct Args {
+    /// Scan for devices at this interval instead of using the udev monitor.
+    scan_interval: Option<Duration>,
+    /// Attach using libvirt node devices rather than sysfs-built XML.
+    nodedev: bool,
+    /// Serve attach/detach events on this Unix socket.
+    event_socket: Option<PathBuf>,
+    /// Serve Prometheus metrics over HTTP on this address.
+    metrics_addr: Option<SocketAddr>,
+    /// Shut down cleanly after running this long.
+    max_runtime: Option<Duration>,
+    /// Daily UTC windows during which new devices aren't attached.
+    suppress_windows: Vec<SuppressWindow>,
+    /// Shut down this long after a `Drain`, rather than waiting for `Quit`.
+    drain_timeout: Option<Duration>,
+    /// Reattach devices missing from their VMs at this interval.
+    reconcile_interval: Option<Duration>,
+    /// Hypervisor URI; VMs named without a scope are looked up on this
+    /// connection only.
+    connect: Option<String>,
+    /// Further hypervisor URIs, from `--connect` given again, for VMs named
+    /// as `vm@uri` or by UUID.
+    also_connect: Vec<String>,
+    /// Record udev events and filter changes to this file.
+    record: Option<PathBuf>,
+    /// Append a line for every attach and detach to this file.
+    audit_log: Option<PathBuf>,
+    /// Replay a recording instead of watching udev, in dry-run mode.
+    replay: Option<PathBuf>,
+    /// Replay as fast as possible rather than with the recorded timing.
+    replay_fast: bool,
+    /// Serve the binary command protocol on this Unix socket.
+    control_socket: Option<PathBuf>,
+    /// Don't serve D-Bus, e.g. where there is no session bus.
+    no_dbus: bool,
+    /// Serve D-Bus on the system bus rather than the session bus.
+    system_bus: bool,
+    /// Well-known name to own, instead of `DEFAULT_DBUS_NAME`.
+    dbus_name: Option<String>,
+    /// Take the name over from an instance that already owns it.
+    replace: bool,
+    /// Program run after attaching for an `AddConfirmed` filter.
+    confirm_hook: Option<PathBuf>,
+    confirm_timeout: Option<Duration>,
+    /// How long a plugged device must stay before it is matched.
+    debounce: Option<Duration>,
+    /// The longest the poll loop sleeps with no event or timer due.
+    poll_timeout: Option<Duration>,
+    /// How long an attached device may be gone before it is detached.
+    unplug_grace: Option<Duration>,
+    /// How many times a failed attach is retried straight away.
+    attach_retries: Option<u32>,
+    /// `startupPolicy` for generated hostdevs, until changed over D-Bus.
+    startup_policy: Option<String>,
+    serial_norm: SerialNormalization,
+    /// Match VIDs and PIDs exactly as given, without canonicalising them.
+    legacy_match: bool,
+    /// Save filters here and load them on startup.
+    state_file: Option<PathBuf>,
+    /// Leave devices attached on shutdown rather than detaching them.
+    keep_attached: bool,
+    /// Attach the present devices the rules match, then exit.
+    once: bool,
+    /// Resume paused VMs so that devices can be attached to them.
+    resume_on_attach: bool,
+    /// Log the XML sent with every attach and detach.
+    verbose_xml: bool,
+    /// Log attaches and detaches with their XML instead of performing them.
+    dry_run: bool,
+    /// Static rules were read from this file.
+    config: Option<PathBuf>,
+    /// The rules from `config`, as each rule's VM and filter.
+    rules: Vec<(String, UsbFilter)>,
+}
+
+const USAGE: &str = "\
+usage: qemu-stormcrow [--scan-interval <secs>] [--nodedev] [--event-socket <path>]
+                     [--metrics-addr <ip:port>]
+                     [--max-runtime <secs>] [--drain-timeout <secs>] [-c|--connect <uri>]...
+                     [--record <file>] [--replay <file> [--replay-fast]] [--audit-log <file>]
+                     [--confirm-hook <program>] [--confirm-timeout <secs>]
+                     [--control-socket <path>] [--no-dbus] [--state-file <path>]
+                     [--bus <session|system>] [--dbus-name <name>] [--replace]
+                     [--keep-attached] [--resume-on-attach] [--verbose-xml] [--once] [--config <file>] [--dry-run]
+                     [--debounce <ms>] [--unplug-grace <ms>] [--poll-timeout <ms>]
+                     [--attach-retries <n>] [--startup-policy <mandatory|requisite|optional>]
+                     [--suppress-window <HH:MM-HH:MM>]... [--reconcile-interval <secs>]
+                     [--serial-normalize <trim,upper,strip-zeros|none> | --legacy-match]
+       qemu-stormcrow --help | --version
+
+The hypervisor URI is taken from --connect, else $STORMCROW_URI, else
+$LIBVIRT_DEFAULT_URI, else qemu:///system.";
+
+fn usage(error: &str) -> ! {
+    eprintln!("qemu-stormcrow: {}", error);
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn parse_args() -> Args {
+    let mut args = Args::default();
+    let mut serial_norm_set = false;
+    // `--flag=value` is the same as `--flag value`.
+    let mut argv = std::env::args()
+        .skip(1)
+        .flat_map(|arg| match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => {
+                vec![flag.to_owned(), value.to_owned()]
+            }
+            _ => vec![arg],
+        });
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--scan-interval" => {
+                let secs = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .filter(|s| *s > 0)
+                    .unwrap_or_else(|| usage("--scan-interval needs a number of seconds"));
+                args.scan_interval = Some(Duration::from_secs(secs));
+            }
+            "--nodedev" => args.nodedev = true,
+            "--legacy-match" => args.legacy_match = true,
+            "--serial-normalize" => {
+                serial_norm_set = true;
+                args.serial_norm = argv
+                    .next()
+                    .and_then(|s| SerialNormalization::parse(&s))
+                    .unwrap_or_else(|| {
+                        usage("--serial-normalize needs a list of trim, upper, strip-zeros or none")
+                    });
+            }
+            "--max-runtime" => {
+                let secs = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage("--max-runtime needs a number of seconds"));
+                // Zero means run forever, same as not passing the flag.
+                args.max_runtime = Some(Duration::from_secs(secs)).filter(|d| !d.is_zero());
+            }
+            "--suppress-window" => {
+                let window = argv
+                    .next()
+                    .and_then(|s| SuppressWindow::parse(&s))
+                    .unwrap_or_else(|| {
+                        usage("--suppress-window needs a UTC time range like 23:30-05:00")
+                    });
+                args.suppress_windows.push(window);
+            }
+            "--drain-timeout" => {
+                let secs = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage("--drain-timeout needs a number of seconds"));
+                args.drain_timeout = Some(Duration::from_secs(secs));
+            }
+            "--reconcile-interval" => {
+                let secs = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage("--reconcile-interval needs a number of seconds"));
+                args.reconcile_interval = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "-c" | "--connect" => {
+                let uri = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--connect needs a hypervisor URI"));
+                match args.connect {
+                    None => args.connect = Some(uri),
+                    Some(_) => args.also_connect.push(uri),
+                }
+            }
+            "--record" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--record needs a file"));
+                args.record = Some(PathBuf::from(path));
+            }
+            "--replay" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--replay needs a file"));
+                args.replay = Some(PathBuf::from(path));
+            }
+            "--replay-fast" => args.replay_fast = true,
+            "--confirm-hook" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--confirm-hook needs a program"));
+                args.confirm_hook = Some(PathBuf::from(path));
+            }
+            "--confirm-timeout" => {
+                let secs = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .filter(|s| *s > 0)
+                    .unwrap_or_else(|| usage("--confirm-timeout needs a number of seconds"));
+                args.confirm_timeout = Some(Duration::from_secs(secs));
+            }
+            "--debounce" => {
+                let ms = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage("--debounce needs a number of milliseconds"));
+                args.debounce = Some(Duration::from_millis(ms));
+            }
+            "--unplug-grace" => {
+                let ms = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .unwrap_or_else(|| usage("--unplug-grace needs a number of milliseconds"));
+                args.unplug_grace = Some(Duration::from_millis(ms));
+            }
+            "--poll-timeout" => {
+                let ms = argv
+                    .next()
+                    .and_then(|s| s.parse::<u64>().ok())
+                    .filter(|ms| *ms > 0)
+                    .unwrap_or_else(|| {
+                        usage("--poll-timeout needs a positive number of milliseconds")
+                    });
+                args.poll_timeout = Some(Duration::from_millis(ms));
+            }
+            "--startup-policy" => {
+                let policy = argv
+                    .next()
+                    .filter(|p| STARTUP_POLICIES.contains(&p.as_str()))
+                    .unwrap_or_else(|| {
+                        usage("--startup-policy needs mandatory, requisite or optional")
+                    });
+                args.startup_policy = Some(policy);
+            }
+            "--attach-retries" => {
+                let retries = argv
+                    .next()
+                    .and_then(|s| s.parse::<u32>().ok())
+                    .unwrap_or_else(|| usage("--attach-retries needs a number"));
+                args.attach_retries = Some(retries);
+            }
+            "--control-socket" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--control-socket needs a path"));
+                args.control_socket = Some(PathBuf::from(path));
+            }
+            "--no-dbus" => args.no_dbus = true,
+            "--replace" => args.replace = true,
+            "--bus" => {
+                args.system_bus = match argv.next().as_deref() {
+                    Some("session") => false,
+                    Some("system") => true,
+                    _ => usage("--bus needs session or system"),
+                };
+            }
+            "--dbus-name" => {
+                let name = argv
+                    .next()
+                    .filter(|name| {
+                        dbus::strings::BusName::new(name.as_str()).is_ok() && !name.starts_with(':')
+                    })
+                    .unwrap_or_else(|| {
+                        usage("--dbus-name needs a well-known bus name like com.stormcrow.device")
+                    });
+                args.dbus_name = Some(name);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("qemu-stormcrow {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            }
+            "--keep-attached" => args.keep_attached = true,
+            "--once" => args.once = true,
+            "--resume-on-attach" => args.resume_on_attach = true,
+            "--verbose-xml" => args.verbose_xml = true,
+            "--dry-run" => args.dry_run = true,
+            "--config" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--config needs a file"));
+                args.config = Some(PathBuf::from(path));
+            }
+            "--state-file" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--state-file needs a path"));
+                args.state_file = Some(PathBuf::from(path));
+            }
+            "--event-socket" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--event-socket needs a path"));
+                args.event_socket = Some(PathBuf::from(path));
+            }
+            "--audit-log" => {
+                let path = argv
+                    .next()
+                    .unwrap_or_else(|| usage("--audit-log needs a path"));
+                args.audit_log = Some(PathBuf::from(path));
+            }
+            "--metrics-addr" => {
+                let addr = argv.next().and_then(|a| a.parse().ok()).unwrap_or_else(|| {
+                    usage("--metrics-addr needs an address like 127.0.0.1:9477")
+                });
+                args.metrics_addr = Some(addr);
+            }
+            _ => usage(&format!("unknown argument '{}'", arg)),
+        }
+    }
+    if args.legacy_match && serial_norm_set {
+        usage("--legacy-match and --serial-normalize can't be combined");
+    }
+    if args.once && args.replay.is_some() {
+        usage("--once and --replay can't be combined");
+    }
+    if args.connect.is_none() {
+        args.connect = ["STORMCROW_URI", "LIBVIRT_DEFAULT_URI"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|uri| !uri.is_empty());
+    }
+    if args.state_file.is_none() {
+        args.state_file = Some(store::default_path());
+    }
+    let mut credentials = auth::Credentials::default();
+    if let Some(path) = args.config.as_ref() {
+        let config = config::load(path)
+            .unwrap_or_else(|e| usage(&format!("can't load config {}: {}", path.display(), e)));
+        args.rules = config.rules;
+        credentials.username = config.username;
+        credentials.password = config.password;
+    }
+    // The config file wins over the environment.
+    let env = |var: &str| std::env::var(var).ok().filter(|v| !v.is_empty());
+    credentials.username = credentials
+        .username
+        .or_else(|| env("STORMCROW_AUTH_USERNAME"));
+    credentials.password = credentials
+        .password
+        .or_else(|| env("STORMCROW_AUTH_PASSWORD"));
+    auth::configure(credentials);
+    args
+}
+
+fn udev_monitor() -> io::Result<udev::MonitorSocket> {
+    MonitorBuilder::new()?
+        .match_subsystem_devtype("usb", "usb_device")?
+        .listen()
+}
+
+fn pci_monitor() -> io::Result<udev::MonitorSocket> {
+    MonitorBuilder::new()?.match_subsystem("pci")?.listen()
+}
+
+/// Connect to the hypervisors the arguments name, the primary first.
+/// Lifecycle events are only wanted when watching for devices: a replay
+/// has no VMs coming and going, and a one-shot run doesn't stay to see
+/// them.
+fn connect(args: &Args) -> Box<dyn Hypervisor> {
+    let uri = args.connect.as_deref().unwrap_or(DEFAULT_URI);
+    info!("Attempting to connect to hypervisor: '{}'...", uri);
+    let primary = match Libvirt::open(uri, args.replay.is_none() && !args.once) {
+        Ok(conn) => conn,
+        Err(e) => panic!("No connection to hypervisor: {}", e),
+    };
+    if args.also_connect.is_empty() {
+        return Box::new(primary);
+    }
+    let mut all = Vec::new();
+    for other in &args.also_connect {
+        info!("Attempting to connect to hypervisor: '{}'...", other);
+        match primary.open_another(other) {
+            Ok(conn) => all.push((other.clone(), Box::new(conn) as Box<dyn Hypervisor>)),
+            Err(e) => panic!("No connection to hypervisor {}: {}", other, e),
+        }
+    }
+    all.insert(0, (uri.to_owned(), Box::new(primary)));
+    Box::new(Connections::new(all))
+}
+
+fn main() {
+    // Quiet by default under journald; RUST_LOG=debug for per-event detail.
+    // env_logger lets every level through, so that the max level, which
+    // starts as RUST_LOG's and which `SetLogLevel` changes, decides.
+    let env = || env_logger::Env::default().default_filter_or("info");
+    let level = env_logger::Builder::from_env(env()).build().filter();
+    env_logger::Builder::from_env(env())
+        .filter_level(LevelFilter::Trace)
+        .init();
+    log::set_max_level(level);
+    let args = parse_args();
+    info!("Starting qemu-stormcrow.");
+
+    let (sender, receiver) = wake::channel::<Command>().expect("failed to make command channel");
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+    // A one-shot run serves nothing and watches nothing.
+    if args.once {
+        match poll(
+            connect(&args),
+            DeviceSource::Once,
+            receiver,
+            shutting_down,
+            snapshot,
+            None,
+            args,
+        ) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => panic!("failed to attach present devices: {}", e),
+        }
+    }
+    let dev = DbusDevice {
+        sender: sender.clone(),
+        shutting_down: shutting_down.clone(),
+        legacy_match: args.legacy_match,
+        snapshot: snapshot.clone(),
+    };
+    if let Some(runtime) = args.max_runtime {
+        let sender = sender.clone();
+        thread::spawn(move || {
+            thread::sleep(runtime);
+            info!("Max runtime of {:?} reached.", runtime);
+            let _ = sender.send(Command::Shutdown);
+        });
+    }
+    if let Some(addr) = args.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .unwrap_or_else(|e| usage(&format!("can't serve metrics on {}: {}", addr, e)));
+        info!("Serving metrics on http://{}/metrics", addr);
+        let sender = sender.clone();
+        thread::spawn(move || metrics::serve(listener, sender));
+    }
+    if let Some(path) = args.control_socket.as_deref() {
+        let listener = control::bind(path).unwrap_or_else(|e| {
+            usage(&format!(
+                "can't serve commands on {}: {}",
+                path.display(),
+                e
+            ))
+        });
+        info!("Serving commands on {}", path.display());
+        let dev = dev.clone();
+        thread::spawn(move || control::serve(listener, dev));
+    }
+    let mut dbus_signals = None;
+    if !args.no_dbus {
+        info!("Starting dbus monitor...");
+        let (signal_tx, signal_rx) = channel::<DeviceSignal>();
+        dbus_signals = Some(signal_tx);
+        let (ready_tx, ready_rx) = channel::<()>();
+        let system_bus = args.system_bus;
+        let name = args
+            .dbus_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_DBUS_NAME.to_owned());
+        let replace = args.replace;
+        thread::spawn(move || {
+            if let Err(e) = dbus_server(dev, signal_rx, ready_tx, system_bus, name, replace) {
+                error!("D-Bus server failed: {}", e);
+            }
+        });
+        // Rather than appear to run while ignoring every call, nothing is
+        // attached until the name is ours.
+        if ready_rx.recv().is_err() {
+            std::process::exit(1);
+        }
+    }
+
+    let source = match (args.replay.as_ref(), args.scan_interval) {
+        (Some(path), _) => match record::load(path) {
+            Ok(records) => DeviceSource::Replay {
+                records,
+                fast: args.replay_fast,
+            },
+            Err(e) => usage(&format!("can't read replay file {}: {}", path.display(), e)),
+        },
+        (None, Some(interval)) => DeviceSource::Scan(interval),
+        (None, None) => {
+            debug!("Making udev monitor...");
+            match udev_monitor() {
+                Ok(socket) => DeviceSource::Monitor(socket),
+                Err(e) => {
+                    warn!("udev monitor unavailable ({}), falling back to scanning", e);
+                    DeviceSource::Scan(DEFAULT_SCAN_INTERVAL)
+                }
+            }
+        }
+    };
+
+    poll(
+        connect(&args),
+        source,
+        receiver,
+        shutting_down,
+        snapshot,
+        dbus_signals,
+        args,
+    )
+    .expect("failed to poll udev monitor");
+    info!("Done!");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hypervisor::mock::{Call, MockHypervisor};
+
+    const VM: &str = "builder";
+    const UUID: &str = "5a1c2f9e-0b47-4d3a-9c1e-7f2b8d6e4a10";
+
+    /// A path in the temp directory unique to this process and `name`.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("stormcrow-{}-{}", std::process::id(), name))
+    }
+
+    /// The hostdev XML the built-in template gives a serial-less device.
+    fn expected_xml(vid: &str, pid: &str, bus: &str, dev: &str) -> String {
+        format!(
+            "\n<hostdev mode='subsystem' type='usb'>\n  <source>\n    \
+             <vendor id='0x{}'/>\n    <product id='0x{}'/>\n    \
+             <address bus='{}' device='{}'/>\n  </source>\n</hostdev>\n",
+            vid, pid, bus, dev
+        )
+    }
+
+    /// Sysfs attributes of a USB device.
+    fn usb_attrs(vid: &str, pid: &str, bus: &str, dev: &str) -> SysfsAttrs {
+        [
+            ("idVendor", vid),
+            ("idProduct", pid),
+            ("busnum", bus),
+            ("devnum", dev),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+    }
+
+    fn id_filter(vid: &str, pid: &str) -> UsbFilter {
+        UsbFilter {
+            vid: Some(vid.to_owned()),
+            pid: Some(pid.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// A front end whose commands go to the returned receiver.
+    fn front_end() -> (DbusDevice, wake::Receiver<Command>) {
+        let (sender, receiver) = wake::channel().unwrap();
+        let dev = DbusDevice {
+            sender,
+            shutting_down: Arc::new(AtomicBool::new(false)),
+            legacy_match: false,
+            snapshot: Arc::new(RwLock::new(Snapshot::default())),
+        };
+        (dev, receiver)
+    }
+
+    /// A hypervisor with one running VM, and a state that doesn't scan the
+    /// host's devices.
+    fn setup() -> (MockHypervisor, State) {
+        let mut conn = MockHypervisor::default();
+        conn.add_vm(VM, UUID);
+        let state = State {
+            source: "replay",
+            ..Default::default()
+        };
+        (conn, state)
+    }
+
+    #[test]
+    fn canon_id_accepts_sysfs_and_user_forms() {
+        for id in ["46d", "046D", "0x046d"] {
+            assert_eq!(canon_id(id).as_deref(), Some("046d"), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn canon_id_pads_and_strips_the_prefix() {
+        assert_eq!(canon_id("1").as_deref(), Some("0001"));
+        assert_eq!(canon_id("0x1").as_deref(), Some("0001"));
+        assert_eq!(canon_id("0X1D6B").as_deref(), Some("1d6b"));
+        assert_eq!(canon_id(" 0x0bda\n").as_deref(), Some("0bda"));
+        // Four digits after the prefix at most, and at least one.
+        assert_eq!(canon_id("0x00046d"), None);
+        assert_eq!(canon_id("0x"), None);
+        assert_eq!(canon_id("x46d"), None);
+    }
+
+    #[test]
+    fn serial_normalization_steps() {
+        let norm = |spec| SerialNormalization::parse(spec).unwrap();
+        assert_eq!(norm("trim").apply(" ab01 \t"), "ab01");
+        assert_eq!(norm("upper").apply(" ab01"), " AB01");
+        assert_eq!(norm("strip-zeros").apply("000ab"), "ab");
+        assert_eq!(norm("strip-zeros").apply("0000"), "0");
+        assert_eq!(norm("none").apply(" 00ab "), " 00ab ");
+        assert_eq!(norm("trim, upper,strip-zeros").apply(" 00ab\n"), "AB");
+        // Zeros are stripped after trimming, so padding doesn't hide them.
+        assert_eq!(norm("strip-zeros").apply(" 00ab"), " 00ab");
+        assert_eq!(norm("trim,strip-zeros").apply(" 00ab"), "ab");
+        assert_eq!(SerialNormalization::default().apply(" 00ab "), "00ab");
+    }
+
+    #[test]
+    fn serial_normalization_parse() {
+        for spec in ["none", "trim", "upper,trim", "trim,upper,strip-zeros"] {
+            let norm = SerialNormalization::parse(spec).unwrap();
+            // Displayed in a fixed order, as `parse` takes it.
+            let shown = norm.to_string();
+            assert_eq!(
+                SerialNormalization::parse(&shown).unwrap().to_string(),
+                shown
+            );
+        }
+        assert_eq!(
+            SerialNormalization::parse("upper,trim")
+                .unwrap()
+                .to_string(),
+            "trim,upper"
+        );
+        assert_eq!(SerialNormalization::default().to_string(), "trim");
+        for spec in ["", "lower", "trim,", "trim;upper"] {
+            assert!(SerialNormalization::parse(spec).is_none(), "{:?}", spec);
+        }
+    }
+
+    /// A device with the given serial, `None` for no `serial` attribute.
+    fn device_with_serial(serial: Option<&str>) -> UsbFilter {
+        UsbFilter {
+            serial: serial.map(str::to_owned),
+            ..id_filter("1050", "0407")
+        }
+    }
+
+    #[test]
+    fn unspecified_serial_matches_any() {
+        let filter = id_filter("1050", "0407");
+        for serial in [None, Some(""), Some("12345678")] {
+            assert!(filter.matches(&device_with_serial(serial)), "{:?}", serial);
+        }
+    }
+
+    #[test]
+    fn empty_serial_matches_only_no_serial() {
+        let filter = device_with_serial(Some(""));
+        assert!(filter.matches(&device_with_serial(None)));
+        assert!(filter.matches(&device_with_serial(Some(""))));
+        assert!(!filter.matches(&device_with_serial(Some("12345678"))));
+    }
+
+    #[test]
+    fn set_serial_matches_exactly() {
+        let filter = device_with_serial(Some("12345678"));
+        assert!(filter.matches(&device_with_serial(Some("12345678"))));
+        assert!(!filter.matches(&device_with_serial(Some("1234567"))));
+        assert!(!filter.matches(&device_with_serial(Some(""))));
+        assert!(!filter.matches(&device_with_serial(None)));
+    }
+
+    #[test]
+    fn empty_serial_is_its_own_filter() {
+        let any = id_filter("1050", "0407");
+        let none = device_with_serial(Some(""));
+        assert_ne!(any, none);
+        let fields = none.to_state_fields();
+        assert_eq!(UsbFilter::from_fields(&fields).serial.as_deref(), Some(""));
+    }
+
+    #[test]
+    fn usb_xml_fills_the_builtin_template() {
+        assert_eq!(
+            usb_xml(None, "046d", "c52b", "1", "12", ""),
+            expected_xml("046d", "c52b", "1", "12")
+        );
+    }
+
+    #[test]
+    fn usb_xml_escapes_a_custom_template() {
+        let template =
+            "<hostdev><serial>{serial}</serial><address bus='{bus}' device='{dev}'/></hostdev>";
+        assert_eq!(
+            usb_xml(Some(template), "046d", "c52b", "3", "7", "A<B>&'C\""),
+            "<hostdev><serial>A&lt;B&gt;&amp;&apos;C&quot;</serial>\
+             <address bus='3' device='7'/></hostdev>"
+        );
+    }
+
+    #[test]
+    fn xml_escape_leaves_plain_text() {
+        assert_eq!(xml_escape("SN-0042 x"), "SN-0042 x");
+        assert_eq!(xml_escape(""), "");
+        assert_eq!(xml_escape("a&&b"), "a&amp;&amp;b");
+    }
+
+    #[test]
+    fn bus_address_reads_decimal_numbers() {
+        let attrs = usb_attrs("046d", "c52b", "3", "12");
+        assert_eq!(bus_address(&attrs).unwrap(), ("3", "12"));
+        let attrs = usb_attrs("046d", "c52b", " 003\n", "007");
+        assert_eq!(bus_address(&attrs).unwrap(), ("003", "007"));
+    }
+
+    #[test]
+    fn bus_address_rejects_non_decimal_numbers() {
+        for (bus, dev) in [
+            ("0x3", "7"),
+            ("3", "7a"),
+            ("-1", "7"),
+            ("3", "7'/>"),
+            ("", "7"),
+        ] {
+            let attrs = usb_attrs("046d", "c52b", bus, dev);
+            assert!(bus_address(&attrs).is_err(), "{:?}:{:?}", bus, dev);
+        }
+        let mut attrs = usb_attrs("046d", "c52b", "3", "7");
+        attrs.remove("devnum");
+        let e = bus_address(&attrs).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn hostdev_ids_must_be_hex() {
+        assert_eq!(canon_id("c52b").as_deref(), Some("c52b"));
+        for id in ["g00d", "04 6d", "12345", "", "0x", "'/><x"] {
+            assert_eq!(canon_id(id), None, "{:?}", id);
+        }
+        // A device whose IDs don't parse is attached by address alone.
+        let (conn, state) = setup();
+        let device = UsbFilter {
+            vid: Some("zzzz".to_owned()),
+            pid: Some("c52b".to_owned()),
+            ..Default::default()
+        };
+        let xml = hostdev_xml(
+            &conn,
+            &state,
+            UUID,
+            Path::new("/sys/bus/usb/devices/3-7"),
+            &device,
+            ("3", "7"),
+            Placement::default(),
+        );
+        assert_eq!(
+            xml,
+            usb_xml(Some(USB_ADDRESS_XML_TEMPLATE), "", "", "3", "7", "")
+        );
+        assert!(!xml.contains("zzzz"));
+    }
+
+    #[test]
+    fn guest_address_goes_before_the_closing_tag() {
+        let xml = usb_xml(None, "046d", "c52b", "3", "7", "");
+        let addressed = with_guest_address(&xml, 1, Some(2)).unwrap();
+        assert!(addressed
+            .ends_with("</source>\n  <address type='usb' bus='1' port='2'/>\n</hostdev>\n"));
+        let addressed = with_guest_address(&xml, 0, None).unwrap();
+        assert!(addressed.ends_with("  <address type='usb' bus='0'/>\n</hostdev>\n"));
+        // The host address in the source is left alone.
+        assert!(addressed.contains("<address bus='3' device='7'/>"));
+    }
+
+    #[test]
+    fn guest_address_already_set_is_kept() {
+        for set in [
+            "<hostdev><address type='usb' bus='0' port='4'/></hostdev>",
+            "<hostdev><address type=\"usb\" bus=\"0\"/></hostdev>",
+        ] {
+            assert_eq!(with_guest_address(set, 1, Some(2)), None);
+        }
+        assert_eq!(with_guest_address("<hostdev/>", 1, None), None);
+    }
+
+    #[test]
+    fn guest_address_from_a_filter_reaches_the_xml() {
+        let (conn, state) = setup();
+        let filter = UsbFilter {
+            guest_bus: Some(1),
+            guest_port: Some(3),
+            ..id_filter("046d", "c52b")
+        };
+        let xml = hostdev_xml(
+            &conn,
+            &state,
+            UUID,
+            Path::new("/sys/bus/usb/devices/3-7"),
+            &id_filter("046d", "c52b"),
+            ("3", "7"),
+            filter.placement(),
+        );
+        assert!(xml.contains("<address type='usb' bus='1' port='3'/>"));
+    }
+
+    #[test]
+    fn filter_template_overrides_the_global_one() {
+        let (conn, mut state) = setup();
+        state.xml_template = Some("<hostdev global='1'><source/></hostdev>".to_owned());
+        let filter = UsbFilter {
+            xml_template: Some("<hostdev own='{vid}:{pid}'><source></source></hostdev>".to_owned()),
+            ..id_filter("046d", "c52b")
+        };
+        let xml = |placement| {
+            hostdev_xml(
+                &conn,
+                &state,
+                UUID,
+                Path::new("/sys/bus/usb/devices/3-7"),
+                &id_filter("046d", "c52b"),
+                ("3", "7"),
+                placement,
+            )
+        };
+        assert_eq!(
+            xml(filter.placement()),
+            "<hostdev own='046d:c52b'><source><address bus='3' device='7'/></source></hostdev>"
+        );
+        assert!(xml(Placement::default()).starts_with("<hostdev global='1'>"));
+        // Kept with the filter's other fields across a restart.
+        let fields = filter.to_fields();
+        assert_eq!(
+            UsbFilter::from_fields(&fields).xml_template,
+            filter.xml_template
+        );
+    }
+
+    #[test]
+    fn startup_policy_is_added_to_the_source() {
+        let xml = usb_xml(None, "046d", "c52b", "3", "7", "");
+        let with = with_startup_policy(&xml, "optional").unwrap();
+        assert!(with.contains("<source startupPolicy='optional'>"));
+        assert_eq!(with.replace(" startupPolicy='optional'", ""), xml);
+    }
+
+    #[test]
+    fn startup_policy_already_set_is_kept() {
+        let xml = "<hostdev><source startupPolicy='mandatory'/></hostdev>";
+        assert_eq!(with_startup_policy(xml, "optional"), None);
+        assert_eq!(with_startup_policy("<hostdev/>", "optional"), None);
+        // A template's own policy reaches the XML unchanged.
+        let (conn, mut state) = setup();
+        state.xml_template = Some(xml.to_owned());
+        state.startup_policy = Some("optional".to_owned());
+        let xml = hostdev_xml(
+            &conn,
+            &state,
+            UUID,
+            Path::new("/sys/bus/usb/devices/3-7"),
+            &id_filter("046d", "c52b"),
+            ("3", "7"),
+            Placement::default(),
+        );
+        assert!(xml.contains("startupPolicy='mandatory'"));
+        assert!(!xml.contains("optional"));
+    }
+
+    #[test]
+    fn log_level_is_set_and_read_back() {
+        let (dev, _receiver) = front_end();
+        let level = |dev| call(dev, "GetLogLevel", &[]).unwrap();
+        let set = |dev, level: &str| call(dev, "SetLogLevel", &[level.to_owned()]).unwrap();
+        let before = level(&dev);
+        assert_eq!(set(&dev, "DEBUG"), "OK");
+        assert_eq!(level(&dev), "debug");
+        for bad in ["verbose", "", "5", "info,dbus=warn"] {
+            assert!(
+                set(&dev, bad).starts_with("ERROR: invalid log level"),
+                "{:?}",
+                bad
+            );
+        }
+        assert_eq!(level(&dev), "debug");
+        assert_eq!(set(&dev, &before), "OK");
+    }
+
+    #[test]
+    fn read_only_access_is_refused() {
+        let (dev, receiver) = front_end();
+        let set = |mode: &str| {
+            let args = [VM, "f00d", "0001", "", mode].map(str::to_owned);
+            call(&dev, "SetAccessMode", &args).unwrap()
+        };
+        assert_eq!(set("read-write"), "OK");
+        assert_eq!(
+            set("read-only"),
+            "ERROR: read-only access isn't supported for USB or PCI hostdevs"
+        );
+        assert!(set("write-once").starts_with("ERROR: invalid mode"));
+        // Nothing to change in any case.
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn attach_then_detach_sends_the_same_xml() {
+        let (conn, mut state) = setup();
+        let (sender, receiver) = wake::channel().unwrap();
+        sender
+            .send(Command::add_or_remove(true, VM, id_filter("f00d", "0001")))
+            .unwrap();
+        assert!(drain_commands(&receiver, &conn, &mut state));
+        // Registered under the UUID the name resolves to.
+        assert!(state.filters.contains_key(UUID));
+
+        let syspath = Path::new("/sys/devices/pci0000:00/0000:00:14.0/usb3/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        assert!(device_added(&conn, &mut state, syspath, &attrs, None));
+        assert_eq!(device_removed(&conn, &mut state, syspath), vec![UUID]);
+
+        let xml = expected_xml("f00d", "0001", "3", "7");
+        assert_eq!(
+            conn.calls(),
+            vec![
+                Call::Attach(VM.to_owned(), xml.clone()),
+                Call::Detach(VM.to_owned(), xml),
+            ]
+        );
+        assert!(state.sysdevs.is_empty());
+        assert_eq!(state.xmls.get(UUID).map(Vec::len), Some(0));
+    }
+
+    #[test]
+    fn unmatched_device_is_not_attached() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        let syspath = Path::new("/sys/bus/usb/devices/3-8");
+        let attrs = usb_attrs("f00d", "0002", "3", "8");
+        assert!(!device_added(&conn, &mut state, syspath, &attrs, None));
+        assert!(device_removed(&conn, &mut state, syspath).is_empty());
+        assert!(conn.calls().is_empty());
+    }
+
+    #[test]
+    fn scoped_vm_gets_devices_on_its_connection() {
+        let (conn, mut state) = setup();
+        let mut session = MockHypervisor::default();
+        let guest = session.add_vm(VM, "0f9e8d7c-6b5a-4392-8170-6e5d4c3b2a19");
+        let conns = Connections::new(vec![
+            ("qemu:///system".to_owned(), Box::new(conn)),
+            ("qemu:///session".to_owned(), Box::new(session)),
+        ]);
+        let (sender, receiver) = wake::channel().unwrap();
+        let scoped = format!("{}@qemu:///session", VM);
+        sender
+            .send(Command::add_or_remove(
+                true,
+                &scoped,
+                id_filter("f00d", "0001"),
+            ))
+            .unwrap();
+        assert!(drain_commands(&receiver, &conns, &mut state));
+        // Kept under the session VM's UUID, not the system one's of the
+        // same name.
+        assert_eq!(
+            state.filters.keys().collect::<Vec<_>>(),
+            ["0f9e8d7c-6b5a-4392-8170-6e5d4c3b2a19"]
+        );
+
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        assert!(device_added(&conns, &mut state, syspath, &attrs, None));
+        assert_eq!(
+            *guest.hostdevs.borrow(),
+            [expected_xml("f00d", "0001", "3", "7")]
+        );
+    }
+
+    #[test]
+    fn repeated_add_attaches_once() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        device_added(&conn, &mut state, syspath, &attrs, None);
+        device_added(&conn, &mut state, syspath, &attrs, None);
+        let xml = expected_xml("f00d", "0001", "3", "7");
+        assert_eq!(conn.calls(), vec![Call::Attach(VM.to_owned(), xml)]);
+    }
+
+    #[test]
+    fn replug_without_removal_detaches_the_stale_address() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        device_added(
+            &conn,
+            &mut state,
+            syspath,
+            &usb_attrs("f00d", "0001", "3", "7"),
+            None,
+        );
+        device_added(
+            &conn,
+            &mut state,
+            syspath,
+            &usb_attrs("f00d", "0001", "3", "9"),
+            None,
+        );
+        let old = expected_xml("f00d", "0001", "3", "7");
+        let new = expected_xml("f00d", "0001", "3", "9");
+        assert_eq!(
+            conn.calls(),
+            vec![
+                Call::Attach(VM.to_owned(), old.clone()),
+                Call::Detach(VM.to_owned(), old),
+                Call::Attach(VM.to_owned(), new),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_devices_attach_and_detach_one_by_one() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("0403", "6001"));
+        let devices = [("1-1", "5"), ("1-2", "6"), ("2-3.1", "9")];
+        let syspath = |port| PathBuf::from(format!("/sys/bus/usb/devices/{}", port));
+        let bus = |port: &str| port[..1].to_owned();
+        for (port, dev) in devices {
+            let attrs = usb_attrs("0403", "6001", &bus(port), dev);
+            assert!(device_added(
+                &conn,
+                &mut state,
+                &syspath(port),
+                &attrs,
+                None
+            ));
+        }
+        let xmls: Vec<String> = devices
+            .iter()
+            .map(|(port, dev)| expected_xml("0403", "6001", &bus(port), dev))
+            .collect();
+        let attaches: Vec<Call> = xmls
+            .iter()
+            .map(|xml| Call::Attach(VM.to_owned(), xml.clone()))
+            .collect();
+        assert_eq!(conn.calls(), attaches);
+        // Each under its own syspath, told apart by address.
+        let held: Vec<&(PathBuf, String)> = state.xmls[UUID].iter().collect();
+        assert_eq!(held.len(), 3);
+        for ((port, _), (path, xml)) in devices.iter().zip(held.iter().copied()) {
+            assert_eq!(*path, syspath(port));
+            assert!(xmls.contains(xml));
+        }
+        assert!(xmls[1].contains("<address bus='1' device='6'/>"));
+
+        // Unplugging one detaches only that one.
+        assert_eq!(
+            device_removed(&conn, &mut state, &syspath("1-2")),
+            vec![UUID]
+        );
+        let mut expected = attaches.clone();
+        expected.push(Call::Detach(VM.to_owned(), xmls[1].clone()));
+        assert_eq!(conn.calls(), expected);
+        assert_eq!(state.xmls[UUID].len(), 2);
+        assert_eq!(state.sysdevs.len(), 2);
+
+        device_removed(&conn, &mut state, &syspath("2-3.1"));
+        device_removed(&conn, &mut state, &syspath("1-1"));
+        expected.push(Call::Detach(VM.to_owned(), xmls[2].clone()));
+        expected.push(Call::Detach(VM.to_owned(), xmls[0].clone()));
+        assert_eq!(conn.calls(), expected);
+        assert!(state.xmls[UUID].is_empty());
+    }
+
+    #[test]
+    fn commands_queued_behind_shutdown_apply_before_detaching() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        let first = Path::new("/sys/bus/usb/devices/3-7");
+        let second = Path::new("/sys/bus/usb/devices/3-8");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        device_added(&conn, &mut state, first, &attrs, None);
+        let attrs = usb_attrs("f00d", "0001", "3", "8");
+        device_added(&conn, &mut state, second, &attrs, None);
+
+        let (sender, receiver) = wake::channel().unwrap();
+        sender.send(Command::Shutdown).unwrap();
+        assert!(!drain_commands(&receiver, &conn, &mut state));
+        // Sent after the loop saw the shutdown, before it tore down.
+        let (reply, replied) = channel();
+        sender
+            .send(Command::DetachPath {
+                syspath: second.to_owned(),
+                reply,
+            })
+            .unwrap();
+        shut_down(&receiver, &conn, &mut state, false);
+        assert_eq!(replied.try_recv().unwrap(), UUID);
+
+        // `detach_all` alone would go in syspath order.
+        let first = expected_xml("f00d", "0001", "3", "7");
+        let second = expected_xml("f00d", "0001", "3", "8");
+        assert_eq!(
+            conn.calls(),
+            vec![
+                Call::Attach(VM.to_owned(), first.clone()),
+                Call::Attach(VM.to_owned(), second.clone()),
+                Call::Detach(VM.to_owned(), second),
+                Call::Detach(VM.to_owned(), first),
+            ]
+        );
+        assert!(state.sysdevs.is_empty());
+    }
+
+    #[test]
+    fn keep_attached_shutdown_detaches_nothing() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        device_added(&conn, &mut state, syspath, &attrs, None);
+        let (_sender, receiver) = wake::channel().unwrap();
+        shut_down(&receiver, &conn, &mut state, true);
+        assert_eq!(conn.calls().len(), 1);
+        assert_eq!(state.sysdevs.len(), 1);
+    }
+
+    #[test]
+    fn exhausted_retries_mark_the_device_failed_until_reattach() {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm("flaky", "3e8a1f5c-7b2d-4c90-8e6f-a4d1b0c92f37");
+        let uuid = vm.uuid().unwrap();
+        let filter = UsbFilter {
+            max_attach_retries: Some(1),
+            ..id_filter("f00d", "0001")
+        };
+        add_filter(&mut state, uuid.clone(), filter);
+        // Reattach reads the device's attributes back from its syspath.
+        let syspath = temp_path("failed");
+        std::fs::create_dir_all(&syspath).unwrap();
+        let attrs = usb_attrs("f00d", "0001", "3", "9");
+        for (attr, value) in &attrs {
+            std::fs::write(syspath.join(attr), value).unwrap();
+        }
+
+        vm.fail_attach.set(true);
+        device_added(&conn, &mut state, &syspath, &attrs, None);
+        // Tried once and retried once, then given up on rather than pending.
+        assert_eq!(conn.calls().len(), 2);
+        assert!(state.pending_attaches.is_empty());
+        assert_eq!(
+            state.failed_attaches.get(&syspath),
+            Some(&PendingAttach {
+                filter: uuid.clone(),
+                vm: uuid.clone(),
+                attempts: 2
+            })
+        );
+        let snapshot = RwLock::new(Snapshot::default());
+        publish(&conn, &state, &snapshot);
+        let report = status_report(&snapshot.read().unwrap());
+        assert!(report.contains(&format!(
+            "\"failed\":[{{\"vm\":\"{}\",\"syspath\":{},\"attempts\":2}}]",
+            uuid,
+            json_str(&syspath.to_string_lossy())
+        )));
+
+        // Skipped on later events, even once the VM would take it.
+        vm.fail_attach.set(false);
+        device_added(&conn, &mut state, &syspath, &attrs, Some(&uuid));
+        assert_eq!(conn.calls().len(), 2);
+
+        let (sender, receiver) = wake::channel().unwrap();
+        let (reply, replied) = channel();
+        sender.send(Command::Reattach { vm: None, reply }).unwrap();
+        drain_commands(&receiver, &conn, &mut state);
+        assert_eq!(
+            replied.try_recv().unwrap(),
+            format!("{}: reattached", syspath.display())
+        );
+        assert!(state.failed_attaches.is_empty());
+        assert_eq!(
+            state.sysdevs.get(&syspath).map(|d| d.vm.as_str()),
+            Some(uuid.as_str())
+        );
+        std::fs::remove_dir_all(&syspath).unwrap();
+    }
+
+    #[test]
+    fn unplugging_a_failed_device_forgets_it() {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm("flaky", "3e8a1f5c-7b2d-4c90-8e6f-a4d1b0c92f37");
+        let uuid = vm.uuid().unwrap();
+        let filter = UsbFilter {
+            max_attach_retries: Some(0),
+            ..id_filter("f00d", "0001")
+        };
+        add_filter(&mut state, uuid, filter);
+        vm.fail_attach.set(true);
+        let syspath = Path::new("/sys/bus/usb/devices/3-9");
+        let attrs = usb_attrs("f00d", "0001", "3", "9");
+        device_added(&conn, &mut state, syspath, &attrs, None);
+        assert_eq!(conn.calls().len(), 1);
+        assert!(state.failed_attaches.contains_key(syspath));
+
+        // Replugged: a fresh start.
+        device_removed(&conn, &mut state, syspath);
+        assert!(state.failed_attaches.is_empty());
+        vm.fail_attach.set(false);
+        assert!(device_added(&conn, &mut state, syspath, &attrs, None));
+        assert_eq!(conn.calls().len(), 2);
+        assert!(state.sysdevs.contains_key(syspath));
+    }
+
+    /// Fail an attach through the filter under `key`, then check that
+    /// `retry_pending` tries it again through the same filter.
+    fn retry_through(key: &str, filter: UsbFilter, vm_name: &str, name: &str) {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm(vm_name, "6f2b9d14-8c3e-4a57-b0e1-d97a2c5f8e03");
+        let uuid = vm.uuid().unwrap();
+        add_filter(&mut state, key.to_owned(), filter);
+        // Retries read the device's attributes back from its syspath.
+        let syspath = temp_path(name);
+        std::fs::create_dir_all(&syspath).unwrap();
+        let attrs = usb_attrs("f00d", "0001", "3", "9");
+        for (attr, value) in &attrs {
+            std::fs::write(syspath.join(attr), value).unwrap();
+        }
+
+        vm.fail_attach.set(true);
+        device_added(&conn, &mut state, &syspath, &attrs, None);
+        // Still failing when retried, so its attempts add up.
+        retry_pending(&conn, &mut state);
+        assert_eq!(
+            state.pending_attaches.get(&syspath),
+            Some(&PendingAttach {
+                filter: key.to_owned(),
+                vm: uuid.clone(),
+                attempts: 2
+            })
+        );
+        let snapshot = RwLock::new(Snapshot::default());
+        publish(&conn, &state, &snapshot);
+        let report = status_report(&snapshot.read().unwrap());
+        assert!(report.contains(&format!(
+            "\"pending\":[{{\"vm\":\"{}\",\"syspath\":{},\"attempts\":2}}]",
+            uuid,
+            json_str(&syspath.to_string_lossy())
+        )));
+
+        vm.fail_attach.set(false);
+        retry_pending(&conn, &mut state);
+        assert!(state.pending_attaches.is_empty());
+        assert_eq!(
+            state.sysdevs.get(&syspath).map(|d| d.vm.as_str()),
+            Some(uuid.as_str())
+        );
+        std::fs::remove_dir_all(&syspath).unwrap();
+    }
+
+    #[test]
+    fn pending_attach_is_retried_through_its_templated_filter() {
+        let templated = UsbFilter {
+            templated_vm: true,
+            ..id_filter("f00d", "0001")
+        };
+        retry_through("vm-{vid}", templated, "vm-f00d", "templated");
+    }
+
+    #[test]
+    fn pending_attach_is_retried_through_its_wildcard_filter() {
+        // Sorted before "builder", so the wildcard picks it.
+        retry_through(WILDCARD_VM, id_filter("f00d", "0001"), "alpha", "wildcard");
+    }
+
+    #[test]
+    fn reload_applies_the_rules_that_changed() {
+        let (conn, mut state) = setup();
+        let path = temp_path("reload.toml");
+        let rule = |pid: &str| {
+            format!(
+                "[[rule]]\nvm = \"{}\"\nvid = \"f00d\"\npid = \"{}\"\n",
+                VM, pid
+            )
+        };
+        std::fs::write(&path, rule("0001") + &rule("0002")).unwrap();
+        state.args.config = Some(path.clone());
+        state.args.rules = config::load(&path).unwrap().rules;
+        add_static_filters(&conn, &mut state);
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        assert!(device_added(&conn, &mut state, syspath, &attrs, None));
+
+        std::fs::write(&path, rule("0002") + &rule("0003")).unwrap();
+        let result = reload_filters(&conn, &mut state).unwrap();
+        assert_eq!(
+            result.to_json(),
+            format!(
+                "{{\"added\":[{{\"vm\":\"{uuid}\",\"pid\":\"0003\",\"vid\":\"f00d\"}}],\
+                 \"removed\":[{{\"vm\":\"{uuid}\",\"pid\":\"0001\",\"vid\":\"f00d\"}}],\
+                 \"attached\":[],\
+                 \"detached\":[{{\"vm\":\"{uuid}\",\"syspath\":\"/sys/bus/usb/devices/3-7\",\"result\":\"ok\"}}]}}",
+                uuid = UUID
+            )
+        );
+        let filters = &state.filters[UUID];
+        assert!(!filters.contains(&id_filter("f00d", "0001")));
+        assert!(filters.contains(&id_filter("f00d", "0002")));
+        assert!(filters.contains(&id_filter("f00d", "0003")));
+        let xml = expected_xml("f00d", "0001", "3", "7");
+        assert_eq!(conn.calls().last(), Some(&Call::Detach(VM.to_owned(), xml)));
+        assert!(state.sysdevs.is_empty());
+
+        // A broken file leaves the rules as they were.
+        std::fs::write(&path, "[[rule]]\nvm = \"work\"\nvid = \"nope\"\n").unwrap();
+        assert!(reload_filters(&conn, &mut state).is_err());
+        assert_eq!(state.args.rules.len(), 2);
+        assert_eq!(state.filters[UUID].len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reattach_closes_the_breaker() {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm("flaky", "0b2e4c61-9d8f-4a37-b5e0-3c7a1d2f6e84");
+        let uuid = vm.uuid().unwrap();
+        add_filter(&mut state, uuid.clone(), id_filter("f00d", "0001"));
+        vm.fail_attach.set(true);
+        for dev in 1..=BREAKER_FAILURES {
+            let syspath = PathBuf::from(format!("/sys/bus/usb/devices/3-{}", dev));
+            let attrs = usb_attrs("f00d", "0001", "3", &dev.to_string());
+            device_added(&conn, &mut state, &syspath, &attrs, None);
+        }
+        assert!(state.breakers.get_mut(&uuid).unwrap().is_open(&uuid));
+
+        let (sender, receiver) = wake::channel().unwrap();
+        let (reply, replied) = channel();
+        sender.send(Command::Reattach { vm: None, reply }).unwrap();
+        drain_commands(&receiver, &conn, &mut state);
+        // Nothing was held, so nothing to report, but attaches resume.
+        assert_eq!(replied.try_recv().unwrap(), "");
+        assert!(!state.breakers.contains_key(&uuid));
+    }
+
+    #[test]
+    fn reconcile_reattaches_lost_devices_when_due() {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm("spare", "9c3d7e20-4f1a-4b8e-a6d2-1e5f0b7c3a98");
+        let uuid = vm.uuid().unwrap();
+        state.args.reconcile_interval = Some(Duration::from_secs(60));
+        add_filter(&mut state, uuid.clone(), id_filter("f00d", "0001"));
+        // Reattaching skips devices whose syspath is gone.
+        let syspath = temp_path("reconcile");
+        std::fs::create_dir_all(&syspath).unwrap();
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        assert!(device_added(&conn, &mut state, &syspath, &attrs, None));
+        state
+            .breakers
+            .entry(uuid.clone())
+            .or_default()
+            .failed(&uuid);
+
+        // Not due yet, so nothing is compared.
+        let later = Instant::now() + Duration::from_secs(60);
+        state.next_reconcile = Some(later);
+        vm.hostdevs.borrow_mut().clear();
+        reconcile(&conn, &mut state);
+        assert_eq!(conn.calls().len(), 1);
+        assert_eq!(next_timer(&state), Some(later));
+
+        state.next_reconcile = Some(Instant::now());
+        reconcile(&conn, &mut state);
+        let xml = expected_xml("f00d", "0001", "3", "7");
+        assert_eq!(conn.calls()[1..], [Call::Attach("spare".to_owned(), xml)]);
+        assert!(state.next_reconcile.unwrap() > Instant::now());
+        // Only a manual Reattach closes a breaker.
+        assert!(state.breakers.contains_key(&uuid));
+
+        // Present now, so the next pass leaves it alone.
+        state.next_reconcile = Some(Instant::now());
+        reconcile(&conn, &mut state);
+        assert_eq!(conn.calls().len(), 2);
+        std::fs::remove_dir(&syspath).unwrap();
+    }
+
+    #[test]
+    fn lost_connection_is_reopened_with_backoff() {
+        let (mut conn, mut state) = setup();
+        let vm = conn.add_vm("spare", "9c3d7e20-4f1a-4b8e-a6d2-1e5f0b7c3a98");
+        add_filter(&mut state, vm.uuid().unwrap(), id_filter("f00d", "0001"));
+        let syspath = temp_path("reconnect");
+        std::fs::create_dir_all(&syspath).unwrap();
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        assert!(device_added(&conn, &mut state, &syspath, &attrs, None));
+
+        // Checked while alive: nothing to do until the next check.
+        state.next_connection_check = Some(Instant::now());
+        assert_eq!(check_connection(&conn, &mut state), 0);
+        assert!(state.reconnect_delay.is_zero());
+        assert!(next_timer(&state).is_some_and(|at| at > Instant::now()));
+
+        // libvirtd restarted, and took the guest's devices with it.
+        conn.dead.set(true);
+        conn.fail_reconnect.set(true);
+        vm.hostdevs.borrow_mut().clear();
+        state.next_connection_check = Some(Instant::now());
+        check_connection(&conn, &mut state);
+        assert_eq!(state.metrics.reconnects, 0);
+        assert_eq!(state.reconnect_delay, RECONNECT_DELAY * 2);
+        check_connection(&conn, &mut state);
+        // Not due again yet.
+        assert_eq!(state.reconnect_delay, RECONNECT_DELAY * 2);
+
+        conn.fail_reconnect.set(false);
+        state.next_connection_check = Some(Instant::now());
+        assert_eq!(check_connection(&conn, &mut state), 1);
+        assert!(conn.is_alive());
+        assert_eq!(state.metrics.reconnects, 1);
+        assert!(state.reconnect_delay.is_zero());
+        assert_eq!(vm.hostdevs.borrow().len(), 1);
+        assert!(state
+            .metrics
+            .render(true, &BTreeMap::new())
+            .contains("\nstormcrow_reconnects_total 1\n"));
+        std::fs::remove_dir(&syspath).unwrap();
+    }
+
+    #[test]
+    fn status_reports_breakers() {
+        let (conn, mut state) = setup();
+        let snapshot = RwLock::new(Snapshot::default());
+        publish(&conn, &state, &snapshot);
+        let report = status_report(&snapshot.read().unwrap());
+        assert!(report.ends_with(",\"breakers\":{}}"), "{}", report);
+
+        state
+            .breakers
+            .entry(UUID.to_owned())
+            .or_default()
+            .failed(UUID);
+        let paused = Breaker {
+            open_until: Some(Instant::now() + Duration::from_secs(90)),
+            ..Default::default()
+        };
+        state.breakers.insert("paused".to_owned(), paused);
+        publish(&conn, &state, &snapshot);
+        let report = status_report(&snapshot.read().unwrap());
+        let breakers = &report[report.find("\"breakers\"").unwrap()..];
+        assert!(breakers.contains(&format!(
+            "\"{}\":{{\"failures\":1,\"open_for_secs\":null}}",
+            UUID
+        )));
+        // Rounded down, and a moment has passed.
+        assert!(breakers.contains("\"paused\":{\"failures\":0,\"open_for_secs\":89}"));
+    }
+
+    #[test]
+    fn filters_for_missing_vms_are_counted_but_kept() {
+        let (conn, mut state) = setup();
+        add_filter(&mut state, UUID.to_owned(), id_filter("f00d", "0001"));
+        add_filter(&mut state, VM.to_owned(), id_filter("f00d", "0002"));
+        add_filter(&mut state, "gone".to_owned(), id_filter("f00d", "0003"));
+        add_filter(&mut state, "gone".to_owned(), id_filter("f00d", "0004"));
+        add_filter(
+            &mut state,
+            WILDCARD_VM.to_owned(),
+            id_filter("f00d", "0005"),
+        );
+        let templated = UsbFilter {
+            templated_vm: true,
+            ..id_filter("f00d", "0006")
+        };
+        add_filter(&mut state, "vm-{serial}".to_owned(), templated);
+        assert_eq!(orphaned_filters(&conn, &state), 2);
+        assert_eq!(state.filters["gone"].len(), 2);
+    }
+
+    #[test]
+    fn remove_command_keeps_attached_devices() {
+        let (conn, mut state) = setup();
+        let (sender, receiver) = wake::channel().unwrap();
+        let filter = id_filter("f00d", "0001");
+        sender
+            .send(Command::add_or_remove(true, VM, filter.clone()))
+            .unwrap();
+        drain_commands(&receiver, &conn, &mut state);
+        let syspath = Path::new("/sys/bus/usb/devices/3-7");
+        let attrs = usb_attrs("f00d", "0001", "3", "7");
+        device_added(&conn, &mut state, syspath, &attrs, None);
+        sender
+            .send(Command::add_or_remove(false, VM, filter))
+            .unwrap();
+        drain_commands(&receiver, &conn, &mut state);
+        assert!(state.filters.get(UUID).is_none_or(HashSet::is_empty));
+        let xml = expected_xml("f00d", "0001", "3", "7");
+        assert_eq!(conn.calls(), vec![Call::Attach(VM.to_owned(), xml)]);
+    }
 }
@@ -0,0 +1,161 @@
+//! Prometheus metrics over HTTP (`--metrics-addr`).
+//!
+//! `GET /metrics` answers in the Prometheus text format with counters of
+//! attaches, detaches, failed attaches and reconnects since startup,
+//! whether the hypervisor connection is alive, and the devices attached to
+//! each VM:
+//!
+//! ```text
+//! stormcrow_attaches_total 12
+//! stormcrow_attached_devices{vm="work"} 2
+//! ```
+//!
+//! The counters live in the `poll` loop's state; each scrape asks the loop
+//! for a rendering over the command channel, like `GetConfig`.  Scrapers
+//! are served one at a time, which is plenty for a metrics endpoint.
+
+use crate::wake::Sender;
+use crate::Command;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::time::Duration;
+
+/// How long a scraper may take to send its request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters since startup, bumped by the `poll` loop.
+#[derive(Default)]
+pub struct Metrics {
+    pub attaches: u64,
+    pub detaches: u64,
+    pub attach_failures: u64,
+    pub reconnects: u64,
+}
+
+impl Metrics {
+    /// The counters, plus gauges of the connection and of the devices
+    /// attached to each VM, in the Prometheus text format.
+    pub fn render(&self, connected: bool, attached: &BTreeMap<String, usize>) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "stormcrow_attaches_total",
+                "Devices attached to VMs.",
+                self.attaches,
+            ),
+            (
+                "stormcrow_detaches_total",
+                "Devices detached from VMs.",
+                self.detaches,
+            ),
+            (
+                "stormcrow_attach_failures_total",
+                "Attaches libvirt refused, after retries.",
+                self.attach_failures,
+            ),
+            (
+                "stormcrow_reconnects_total",
+                "Times the hypervisor connection was lost and reopened.",
+                self.reconnects,
+            ),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                name, help, name, name, value
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP stormcrow_hypervisor_connected Whether libvirt answers on the connection.\n\
+             # TYPE stormcrow_hypervisor_connected gauge\n\
+             stormcrow_hypervisor_connected {}",
+            connected as u8
+        );
+        let _ = writeln!(
+            out,
+            "# HELP stormcrow_attached_devices Devices currently attached, by VM.\n\
+             # TYPE stormcrow_attached_devices gauge"
+        );
+        for (vm, count) in attached {
+            let _ = writeln!(
+                out,
+                "stormcrow_attached_devices{{vm=\"{}\"}} {}",
+                label(vm),
+                count
+            );
+        }
+        out
+    }
+}
+
+/// A label value, escaped as the text format requires.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve scrapers on `listener` forever.  It is bound by the caller, so
+/// that an address in use stops the daemon at startup.
+pub fn serve(listener: TcpListener, sender: Sender<Command>) {
+    for stream in listener.incoming() {
+        let result = stream.and_then(|mut stream| {
+            stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+            serve_client(&mut stream, &sender)
+        });
+        if let Err(e) = result {
+            warn!("dropping metrics client: {}", e);
+        }
+    }
+}
+
+/// Answer one HTTP request and close the connection.
+fn serve_client(stream: &mut TcpStream, sender: &Sender<Command>) -> io::Result<()> {
+    let mut reader = BufReader::new(&*stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // The headers don't matter, but are read so the client sees a clean
+    // close rather than a reset.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    debug!("Metrics request: {} {}", method, path);
+    let (status, body) = match (method, path.split('?').next()) {
+        ("GET", Some("/metrics")) => {
+            let (reply_tx, reply_rx) = channel::<String>();
+            match sender
+                .send(Command::Metrics(reply_tx))
+                .ok()
+                .and_then(|_| reply_rx.recv_timeout(Duration::from_secs(10)).ok())
+            {
+                Some(body) => ("200 OK", body),
+                None => (
+                    "503 Service Unavailable",
+                    "no reply from event loop\n".to_owned(),
+                ),
+            }
+        }
+        ("GET", _) => ("404 Not Found", "only /metrics is served\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "only GET is supported\n".to_owned(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
@@ -0,0 +1,137 @@
+//! udev monitor sockets that are rebuilt when they fail.
+//!
+//! A netlink socket that overflows reports an error and drops events, and
+//! one that is reset stops delivering them.  Either way the daemon would
+//! carry on running but deaf to devices.  An error is reported as events
+//! having been lost, for the loop to catch up by rescanning; a socket that
+//! is closed, or errors several cycles in a row, is opened again with the
+//! same matchers and registered under the same token.
+
+use log::{info, warn};
+use mio::event::Event;
+use mio::{Events, Interest, Registry, Token};
+use std::io;
+use std::time::{Duration, Instant};
+use udev::MonitorSocket;
+
+/// Consecutive cycles with a socket error after which it is rebuilt.
+const MAX_ERRORS: u32 = 3;
+
+/// How long to wait before trying again to open a monitor that failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a cycle's events say about a monitor.
+#[derive(PartialEq)]
+pub enum Status {
+    Idle,
+    /// It has events to read.
+    Readable,
+    /// It reported an error, so events may have been missed.
+    Lost,
+}
+
+pub struct Monitor {
+    /// What it monitors, for the log.
+    name: &'static str,
+    /// Opens it with its matchers.
+    open: fn() -> io::Result<MonitorSocket>,
+    token: Token,
+    /// `None` from a failure until it is rebuilt.
+    socket: Option<MonitorSocket>,
+    /// Cycles in a row with an error.
+    errors: u32,
+    /// When to next try to rebuild it.
+    retry: Option<Instant>,
+}
+
+impl Monitor {
+    /// Register `socket`, which `open` opened, under `token`.
+    pub fn new(
+        name: &'static str,
+        open: fn() -> io::Result<MonitorSocket>,
+        mut socket: MonitorSocket,
+        registry: &Registry,
+        token: Token,
+    ) -> io::Result<Monitor> {
+        registry.register(&mut socket, token, Interest::READABLE)?;
+        Ok(Monitor {
+            name,
+            open,
+            token,
+            socket: Some(socket),
+            errors: 0,
+            retry: None,
+        })
+    }
+
+    /// The socket, unless it failed and hasn't been rebuilt yet.
+    pub fn socket(&self) -> Option<&MonitorSocket> {
+        self.socket.as_ref()
+    }
+
+    /// Look at the cycle's `events` for this monitor.  A socket that is
+    /// closed, or has errored too often, is dropped to be rebuilt by
+    /// `recover`.
+    pub fn check(&mut self, events: &Events, registry: &Registry) -> Status {
+        let ours: Vec<&Event> = events.iter().filter(|e| e.token() == self.token).collect();
+        if ours.is_empty() {
+            return Status::Idle;
+        }
+        let closed = ours.iter().any(|e| e.is_read_closed());
+        if !closed && !ours.iter().any(|e| e.is_error()) {
+            self.errors = 0;
+            return Status::Readable;
+        }
+        self.errors += 1;
+        let what = match closed {
+            true => "closed",
+            _ => "errored, events may be lost",
+        };
+        warn!(
+            "udev {} monitor socket {} ({} in a row)",
+            self.name, what, self.errors
+        );
+        if closed || self.errors >= MAX_ERRORS {
+            if let Some(mut socket) = self.socket.take() {
+                let _ = registry.deregister(&mut socket);
+            }
+            self.retry = Some(Instant::now());
+        }
+        Status::Lost
+    }
+
+    /// When a failed monitor is next due a rebuild.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.retry
+    }
+
+    /// Rebuild a failed monitor if it is due.  Returns `true` if it was
+    /// rebuilt, after which anything that happened while it was down has
+    /// to be caught up on.
+    pub fn recover(&mut self, registry: &Registry) -> bool {
+        if self.retry.is_none_or(|at| Instant::now() < at) {
+            return false;
+        }
+        let rebuilt = (self.open)().and_then(|mut socket| {
+            registry.register(&mut socket, self.token, Interest::READABLE)?;
+            Ok(socket)
+        });
+        match rebuilt {
+            Ok(socket) => {
+                info!("Rebuilt udev {} monitor after a socket failure", self.name);
+                self.socket = Some(socket);
+                self.errors = 0;
+                self.retry = None;
+                true
+            }
+            Err(e) => {
+                warn!(
+                    "can't rebuild udev {} monitor, trying again in {:?}: {}",
+                    self.name, RETRY_INTERVAL, e
+                );
+                self.retry = Some(Instant::now() + RETRY_INTERVAL);
+                false
+            }
+        }
+    }
+}
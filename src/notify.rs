@@ -0,0 +1,89 @@
+//! systemd readiness and watchdog notifications, for `Type=notify` units
+//! with `WatchdogSec`.
+//!
+//! Messages are datagrams to the socket named by `$NOTIFY_SOCKET`, as
+//! `sd_notify(3)` sends them.  Without that variable every call is a
+//! no-op, so the daemon behaves the same when launched by hand.
+
+use log::{debug, warn};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    /// How often to ping the watchdog: half of `$WATCHDOG_USEC`, if it is
+    /// set for this process.
+    watchdog: Option<Duration>,
+    last_ping: Option<Instant>,
+}
+
+impl Notifier {
+    /// Connect to the socket systemd passed in the environment, if any.
+    pub fn from_env() -> Notifier {
+        let socket = std::env::var("NOTIFY_SOCKET").ok().and_then(|path| {
+            // A leading `@` names a socket in the abstract namespace.
+            let addr = match path.strip_prefix('@') {
+                Some(name) => SocketAddr::from_abstract_name(name),
+                None => SocketAddr::from_pathname(&path),
+            };
+            match addr.and_then(|addr| Ok((UnixDatagram::unbound()?, addr))) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    warn!("can't notify systemd at {}: {}", path, e);
+                    None
+                }
+            }
+        });
+        // The watchdog is meant for the main process only.
+        let for_us =
+            std::env::var("WATCHDOG_PID").map_or(true, |pid| pid.parse() == Ok(std::process::id()));
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|usec| usec.parse::<u64>().ok())
+            .filter(|usec| *usec > 0 && for_us && socket.is_some())
+            .map(|usec| Duration::from_micros(usec) / 2);
+        Notifier {
+            socket,
+            watchdog,
+            last_ping: None,
+        }
+    }
+
+    /// Tell systemd that startup has finished.
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    /// Tell systemd that shutdown has started.
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// When the watchdog is next due a ping, if it is enabled.
+    pub fn next_ping(&self) -> Option<Instant> {
+        let interval = self.watchdog?;
+        Some(self.last_ping.map_or_else(Instant::now, |t| t + interval))
+    }
+
+    /// Ping the watchdog if it is due.  Called every poll cycle.
+    pub fn watchdog(&mut self) {
+        let interval = match self.watchdog {
+            Some(i) => i,
+            None => return,
+        };
+        if self.last_ping.is_none_or(|t| t.elapsed() >= interval) {
+            self.send("WATCHDOG=1");
+            self.last_ping = Some(Instant::now());
+        }
+    }
+
+    fn send(&self, message: &str) {
+        if let Some((socket, addr)) = self.socket.as_ref() {
+            debug!("Notifying systemd: {}", message);
+            if let Err(e) = socket.send_to_addr(message.as_bytes(), addr) {
+                warn!("failed to notify systemd ({}): {}", message, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Recording of udev events and filter changes for `--record`, and reading
+//! them back for `--replay`.
+//!
+//! A recording has one event per line, with tabs between the fields:
+//!
+//! ```text
+//! <ms since start> <kind> <subject> <key>=<value> ...
+//! ```
+//!
+//! `kind` is `add` or `remove` for a udev event, with the device's syspath
+//! as the subject and, for `add`, its sysfs attributes as the keys.  It is
+//! `filter-add` or `filter-remove` for a filter registered or removed over
+//! D-Bus, with the VM as the subject and the filter's fields as the keys.
+//! Backslashes, tabs and newlines in subjects and values are escaped as
+//! `\\`, `\t` and `\n`.
+
+use log::warn;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// One recorded event.
+pub struct Record {
+    /// Time since the recording started.
+    pub at: Duration,
+    pub kind: String,
+    pub subject: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+pub struct Recorder {
+    file: File,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Start a new recording at `path`, replacing any existing file.
+    pub fn create(path: &Path) -> io::Result<Recorder> {
+        Ok(Recorder {
+            file: File::create(path)?,
+            start: Instant::now(),
+        })
+    }
+
+    /// Append an event.  A failed write is reported but doesn't stop the
+    /// daemon.
+    pub fn write(&mut self, kind: &str, subject: &str, fields: &BTreeMap<String, String>) {
+        let mut line = format!(
+            "{}\t{}\t{}",
+            self.start.elapsed().as_millis(),
+            kind,
+            escape(subject)
+        );
+        for (key, value) in fields {
+            line.push_str(&format!("\t{}={}", key, escape(value)));
+        }
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("failed to record event: {}", e);
+        }
+    }
+}
+
+/// Read every event from a recording made by `Recorder`.
+pub fn load(path: &Path) -> io::Result<Vec<Record>> {
+    let invalid = |n: usize, what: &str| {
+        io::Error::new(ErrorKind::InvalidData, format!("line {}: {}", n + 1, what))
+    };
+    let mut records = Vec::new();
+    for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let at = parts
+            .next()
+            .and_then(|ms| ms.parse().ok())
+            .map(Duration::from_millis)
+            .ok_or_else(|| invalid(n, "bad timestamp"))?;
+        let kind = parts
+            .next()
+            .ok_or_else(|| invalid(n, "missing kind"))?
+            .to_owned();
+        let subject = unescape(parts.next().ok_or_else(|| invalid(n, "missing subject"))?);
+        let mut fields = BTreeMap::new();
+        for field in parts {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| invalid(n, "field without '='"))?;
+            fields.insert(key.to_owned(), unescape(value));
+        }
+        records.push(Record {
+            at,
+            kind,
+            subject,
+            fields,
+        });
+    }
+    Ok(records)
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+pub fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
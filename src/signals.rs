@@ -0,0 +1,93 @@
+//! SIGTERM, SIGINT and SIGHUP delivered to the poll loop through a
+//! self-pipe.
+//!
+//! The handler only writes the signal's number to a non-blocking pipe,
+//! which is async-signal-safe.  The read end is registered with the poll
+//! loop, which then shuts down the same way as for `Quit`, or for SIGHUP
+//! reloads the `--config` file as for `ReloadFilters`.
+
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std::io::{self, ErrorKind, Read};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Write end of the pipe, for the handler.  `-1` until `install`.
+static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    let fd = WRITE_FD.load(Ordering::Relaxed);
+    if fd >= 0 {
+        // Nothing useful can be done if the pipe is full: a wakeup is
+        // already pending.
+        unsafe {
+            libc::write(fd, [signal as u8].as_ptr().cast(), 1);
+        }
+    }
+}
+
+/// The signals that arrived since `pending` last looked.
+#[derive(Default)]
+pub struct Received {
+    /// SIGTERM or SIGINT.
+    pub quit: bool,
+    /// SIGHUP.
+    pub reload: bool,
+}
+
+pub struct Signals {
+    receiver: pipe::Receiver,
+    /// Kept open for the handler, which only has its raw fd.
+    _sender: pipe::Sender,
+}
+
+impl Signals {
+    /// Route SIGTERM, SIGINT and SIGHUP to a new pipe.
+    pub fn install() -> io::Result<Signals> {
+        let (sender, receiver) = pipe::new()?;
+        WRITE_FD.store(sender.as_raw_fd(), Ordering::Relaxed);
+        for signal in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(Signals {
+            receiver,
+            _sender: sender,
+        })
+    }
+
+    /// Register the read end with the poll loop.
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        self.receiver.register(registry, token, Interest::READABLE)
+    }
+
+    /// Empty the pipe, returning which signals arrived since last time.
+    pub fn pending(&mut self) -> Received {
+        let mut received = Received::default();
+        let mut buf = [0u8; 16];
+        loop {
+            match self.receiver.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    for &signal in &buf[..n] {
+                        match signal as libc::c_int {
+                            libc::SIGHUP => received.reload = true,
+                            _ => received.quit = true,
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+        received
+    }
+}
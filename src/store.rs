@@ -0,0 +1,300 @@
+//! Registered filters saved across restarts (`--state-file`).
+//!
+//! The file is a JSON array with one object per filter, each on its own
+//! line, holding the VM and the filter's fields as strings, the same
+//! objects `List` replies with:
+//!
+//! ```json
+//! [
+//! {"vm":"work","pid":"0407","vid":"1050"},
+//! {"vm":"*","disabled":"1","driver":"cdc_acm"}
+//! ]
+//! ```
+//!
+//! It is rewritten whole after every change, through a temporary file so
+//! that a crash mid-write leaves the previous version in place.  A file
+//! in the tab-separated form of a `--record` recording, which earlier
+//! versions wrote, is still read, and replaced with JSON on the next
+//! change.
+
+use crate::events::json_str;
+use crate::record::unescape;
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Saved filters, as each one's VM and fields.
+type Saved = Vec<(String, BTreeMap<String, String>)>;
+
+/// Where filters are kept unless `--state-file` names another file:
+/// `$XDG_STATE_HOME/qemu-stormcrow/filters` if that is set, otherwise
+/// `/var/lib/qemu-stormcrow/filters`.
+pub fn default_path() -> PathBuf {
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(state) if !state.is_empty() => PathBuf::from(state).join("qemu-stormcrow"),
+        _ => PathBuf::from("/var/lib/qemu-stormcrow"),
+    };
+    dir.join("filters")
+}
+
+/// Replace the file at `path` with `filters`, given as each filter's VM and
+/// fields.  Creates the parent directory if needed.
+pub fn save<'a>(
+    path: &Path,
+    filters: impl Iterator<Item = (&'a str, BTreeMap<String, String>)>,
+) -> io::Result<()> {
+    let objects: Vec<String> = filters
+        .map(|(vm, fields)| {
+            let mut members = vec![format!("\"vm\":{}", json_str(vm))];
+            for (key, value) in &fields {
+                members.push(format!("{}:{}", json_str(key), json_str(value)));
+            }
+            format!("{{{}}}", members.join(","))
+        })
+        .collect();
+    let out = match objects.is_empty() {
+        true => "[]\n".to_owned(),
+        _ => format!("[\n{}\n]\n", objects.join(",\n")),
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, out)?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Read every filter saved by `save`, as its VM and fields.
+pub fn load(path: &Path) -> io::Result<Saved> {
+    let text = std::fs::read_to_string(path)?;
+    let filters = match text.trim_start().starts_with('[') {
+        true => Json::new(&text).filters(),
+        _ => load_legacy(&text),
+    };
+    filters.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Read the tab-separated form: a line per filter, with the VM and then
+/// `key=value` fields, escaped as in a recording.
+fn load_legacy(text: &str) -> Result<Saved, String> {
+    let mut filters = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split('\t');
+        let vm = unescape(parts.next().unwrap_or(""));
+        if vm.is_empty() {
+            return Err(format!("line {}: missing vm", n + 1));
+        }
+        let mut fields = BTreeMap::new();
+        for field in parts {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("line {}: field without '='", n + 1))?;
+            fields.insert(key.to_owned(), unescape(value));
+        }
+        filters.push((vm, fields));
+    }
+    Ok(filters)
+}
+
+/// Reads the JSON `save` writes: an array of objects whose values are all
+/// strings.  Anything else is an error naming the line.
+struct Json<'a> {
+    rest: &'a str,
+    line: usize,
+}
+
+impl<'a> Json<'a> {
+    fn new(text: &'a str) -> Json<'a> {
+        Json {
+            rest: text,
+            line: 1,
+        }
+    }
+
+    fn error(&self, what: &str) -> String {
+        format!("line {}: {}", self.line, what)
+    }
+
+    /// Skip whitespace, then take `c` if it comes next.
+    fn eat(&mut self, c: char) -> bool {
+        let trimmed = self.rest.trim_start();
+        let skipped = &self.rest[..self.rest.len() - trimmed.len()];
+        self.line += skipped.matches('\n').count();
+        self.rest = trimmed;
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            _ => Err(self.error(&format!("expected '{}'", c))),
+        }
+    }
+
+    fn filters(mut self) -> Result<Saved, String> {
+        let mut filters = Vec::new();
+        self.expect('[')?;
+        if !self.eat(']') {
+            loop {
+                filters.push(self.filter()?);
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        if !self.rest.trim().is_empty() {
+            return Err(self.error("trailing data after the array"));
+        }
+        Ok(filters)
+    }
+
+    /// One `{"vm":...,"key":"value",...}`.
+    fn filter(&mut self) -> Result<(String, BTreeMap<String, String>), String> {
+        let line = self.line;
+        let mut fields = BTreeMap::new();
+        self.expect('{')?;
+        if !self.eat('}') {
+            loop {
+                let key = self.string()?;
+                self.expect(':')?;
+                let value = self.string()?;
+                if fields.insert(key.clone(), value).is_some() {
+                    return Err(self.error(&format!("duplicate key '{}'", key)));
+                }
+                if self.eat('}') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        match fields.remove("vm").filter(|vm| !vm.is_empty()) {
+            Some(vm) => Ok((vm, fields)),
+            None => Err(format!("line {}: missing vm", line)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.rest.chars();
+        loop {
+            match chars.next() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .filter(|_| hex.len() == 4)
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| self.error(&format!("invalid escape \\u{}", hex)))?;
+                        out.push(c);
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+        self.rest = chars.as_str();
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    fn fields(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn saved_filters_load_back() {
+        let path = temp_path("filters");
+        let filters = vec![
+            (
+                "work".to_owned(),
+                fields(&[("vid", "1050"), ("pid", "0407")]),
+            ),
+            (
+                "odd \"vm\"\t\\".to_owned(),
+                fields(&[("serial", ""), ("product_match", "Key\n\u{1}é")]),
+            ),
+        ];
+        save(
+            &path,
+            filters.iter().map(|(vm, f)| (vm.as_str(), f.clone())),
+        )
+        .unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert!(text.starts_with("[\n{\"vm\":\"work\",\"pid\":\"0407\",\"vid\":\"1050\"},\n"));
+        assert_eq!(load(&path).unwrap(), filters);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn no_filters_is_an_empty_array() {
+        let path = temp_path("empty");
+        save(&path, std::iter::empty()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "[]\n");
+        assert!(load(&path).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn legacy_files_still_load() {
+        let text = "work\tpid=0407\tvid=1050\nkeys\\twith tab\tserial=a\\nb\n";
+        let expected = vec![
+            (
+                "work".to_owned(),
+                fields(&[("vid", "1050"), ("pid", "0407")]),
+            ),
+            ("keys\twith tab".to_owned(), fields(&[("serial", "a\nb")])),
+        ];
+        assert_eq!(load_legacy(text).unwrap(), expected);
+    }
+
+    #[test]
+    fn corrupt_json_names_the_line() {
+        for (text, error) in [
+            (
+                "[\n{\"vm\":\"work\"},\n{\"vm\":\"x\",\"vid\":1050}\n]",
+                "line 3",
+            ),
+            ("[\n{\"vid\":\"1050\"}\n]", "line 2: missing vm"),
+            ("[\n{\"vm\":\"work\"}\n", "line 3: expected ','"),
+            (
+                "[{\"vm\":\"a\",\"vm\":\"b\"}]",
+                "line 1: duplicate key 'vm'",
+            ),
+            ("[] []", "trailing data"),
+            ("[{\"vm\":\"a\\q\"}]", "invalid escape"),
+        ] {
+            let e = Json::new(text).filters().unwrap_err();
+            assert!(e.contains(error), "{:?}: {}", text, e);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Channels into the poll loop that wake it, so that it can sleep until
+//! something happens rather than checking them on a timer.
+//!
+//! Each send is followed by a byte on a non-blocking pipe, whose read end
+//! the loop registers like any other event source, as for signals.  On
+//! readiness the loop empties the pipe first and the channel second, so a
+//! message sent in between still leaves a byte behind to wake it again.
+
+use mio::event::Source;
+use mio::unix::pipe;
+use mio::{Interest, Registry, Token};
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, SendError, TryRecvError};
+use std::sync::Arc;
+
+/// A channel whose receiver is a mio event source.
+pub fn channel<T>() -> io::Result<(Sender<T>, Receiver<T>)> {
+    let (pipe_tx, pipe_rx) = pipe::new()?;
+    let (sender, receiver) = mpsc::channel();
+    Ok((
+        Sender {
+            sender,
+            pipe: Arc::new(pipe_tx),
+        },
+        Receiver {
+            receiver,
+            pipe: pipe_rx,
+        },
+    ))
+}
+
+pub struct Sender<T> {
+    sender: mpsc::Sender<T>,
+    pipe: Arc<pipe::Sender>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            sender: self.sender.clone(),
+            pipe: self.pipe.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Send `value` and wake the poll loop.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.sender.send(value)?;
+        // A full pipe already has a wakeup in it.
+        let _ = (&*self.pipe).write(&[1]);
+        Ok(())
+    }
+}
+
+pub struct Receiver<T> {
+    receiver: mpsc::Receiver<T>,
+    pipe: pipe::Receiver,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.receiver.try_recv()
+    }
+
+    /// Empty the pipe.  Called before draining the channel, since the
+    /// pipe is edge-triggered.
+    pub fn clear(&mut self) {
+        let mut buf = [0u8; 64];
+        loop {
+            match self.pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Register the pipe with the poll loop.
+    pub fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        self.pipe.register(registry, token, Interest::READABLE)
+    }
+}